@@ -0,0 +1,380 @@
+//! Advertising payload announcing the pet to nearby devices.
+//!
+//! The advertising packet carries the flags, the 16-bit service UUIDs and a
+//! manufacturer-specific beacon; the name goes in the scan response so both
+//! fit in the 31 bytes of a legacy advertisement:
+//!
+//! ```text
+//!  manufacturer data (AD type 0xFF)
+//!  offset  size  field
+//!  0       2     company identifier, 0xFFFF (test/internal use), little endian
+//!  2       2     magic "TG"
+//!  4       1     beacon version, currently 1
+//!  5       1     species
+//!  6       1     life stage
+//!  7       1     mood (pet status code)
+//!  8       1     happiness bucket, 0..=3
+//! ```
+
+use crate::pet::{LifeStage, Species, TamagotchiStatus};
+
+/// Largest legacy advertising or scan response payload
+pub const ADV_DATA_MAX_LEN: usize = 31;
+
+/// Bluetooth SIG company identifier reserved for testing
+pub const COMPANY_ID: u16 = 0xFFFF;
+
+/// Identifies this firmware among other 0xFFFF beacons
+pub const BEACON_MAGIC: [u8; 2] = *b"TG";
+
+pub const BEACON_VERSION: u8 = 1;
+
+/// Manufacturer data length, company identifier included
+pub const BEACON_LEN: usize = 9;
+
+/// Number of happiness buckets advertised
+pub const HAPPINESS_BUCKETS: u8 = 4;
+
+pub const AD_TYPE_FLAGS: u8 = 0x01;
+pub const AD_TYPE_INCOMPLETE_UUIDS16: u8 = 0x02;
+pub const AD_TYPE_COMPLETE_UUIDS16: u8 = 0x03;
+pub const AD_TYPE_SHORTENED_LOCAL_NAME: u8 = 0x08;
+pub const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+pub const AD_TYPE_MANUFACTURER_DATA: u8 = 0xFF;
+
+/// LE General Discoverable Mode, BR/EDR not supported
+pub const FLAGS_GENERAL_DISCOVERABLE: u8 = 0x06;
+
+/// Pet summary broadcast in the advertising data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PetBeacon {
+    pub species: Species,
+    pub stage: LifeStage,
+    pub mood: TamagotchiStatus,
+    /// 0 (sad) ..= 3 (very happy)
+    pub happiness_bucket: u8,
+}
+
+impl PetBeacon {
+    /// Maps a 0..=100 happiness meter onto [`HAPPINESS_BUCKETS`] buckets
+    pub fn happiness_bucket(happiness: u8) -> u8 {
+        (happiness.min(100) as u16 * HAPPINESS_BUCKETS as u16 / 101) as u8
+    }
+
+    /// Manufacturer data: company identifier followed by the beacon
+    pub fn to_bytes(&self) -> [u8; BEACON_LEN] {
+        let company = COMPANY_ID.to_le_bytes();
+        [
+            company[0],
+            company[1],
+            BEACON_MAGIC[0],
+            BEACON_MAGIC[1],
+            BEACON_VERSION,
+            self.species as u8,
+            self.stage as u8,
+            self.mood as u8,
+            self.happiness_bucket,
+        ]
+    }
+
+    /// Parses manufacturer data written by [`PetBeacon::to_bytes`]
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data: &[u8; BEACON_LEN] = data.try_into().ok()?;
+        if u16::from_le_bytes([data[0], data[1]]) != COMPANY_ID
+            || data[2..4] != BEACON_MAGIC
+            || data[4] != BEACON_VERSION
+            || data[8] >= HAPPINESS_BUCKETS
+        {
+            return None;
+        }
+
+        Some(PetBeacon {
+            species: Species::from_u8(data[5])?,
+            stage: LifeStage::from_u8(data[6])?,
+            mood: TamagotchiStatus::from_u8(data[7])?,
+            happiness_bucket: data[8],
+        })
+    }
+}
+
+/// Appends AD structures (`[len, type, data...]`) to a fixed 31-byte buffer
+pub struct AdWriter {
+    buffer: [u8; ADV_DATA_MAX_LEN],
+    len: usize,
+}
+
+impl Default for AdWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdWriter {
+    pub const fn new() -> Self {
+        AdWriter {
+            buffer: [0u8; ADV_DATA_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// Bytes still free, AD header included
+    pub fn remaining(&self) -> usize {
+        ADV_DATA_MAX_LEN - self.len
+    }
+
+    /// Appends one AD structure; returns `false` (and writes nothing) if it doesn't fit
+    pub fn push(&mut self, ad_type: u8, data: &[u8]) -> bool {
+        let total = 2 + data.len();
+        if total > self.remaining() {
+            return false;
+        }
+        self.buffer[self.len] = (1 + data.len()) as u8;
+        self.buffer[self.len + 1] = ad_type;
+        self.buffer[self.len + 2..self.len + total].copy_from_slice(data);
+        self.len += total;
+        true
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// Advertising data: flags, 16-bit service UUIDs and the pet beacon
+pub fn advertising_data(service_uuids: &[u16], beacon: &PetBeacon) -> AdWriter {
+    let mut writer = AdWriter::new();
+    writer.push(AD_TYPE_FLAGS, &[FLAGS_GENERAL_DISCOVERABLE]);
+
+    // Beacon first: it must always be present, the UUID list is best effort
+    let mut uuids = [0u8; ADV_DATA_MAX_LEN];
+    let room = (ADV_DATA_MAX_LEN - 3 - (2 + BEACON_LEN) - 2) / 2;
+    let count = service_uuids.len().min(room);
+    for (chunk, uuid) in uuids.chunks_exact_mut(2).zip(&service_uuids[..count]) {
+        chunk.copy_from_slice(&uuid.to_le_bytes());
+    }
+    if count > 0 {
+        let ad_type = if count == service_uuids.len() {
+            AD_TYPE_COMPLETE_UUIDS16
+        } else {
+            AD_TYPE_INCOMPLETE_UUIDS16
+        };
+        writer.push(ad_type, &uuids[..count * 2]);
+    }
+
+    writer.push(AD_TYPE_MANUFACTURER_DATA, &beacon.to_bytes());
+    writer
+}
+
+/// Scan response: the local name, shortened if it doesn't fit
+pub fn scan_response_data(name: &str) -> AdWriter {
+    let mut writer = AdWriter::new();
+    let max = ADV_DATA_MAX_LEN - 2;
+    let bytes = name.as_bytes();
+
+    if bytes.len() <= max {
+        writer.push(AD_TYPE_COMPLETE_LOCAL_NAME, bytes);
+    } else {
+        // Cut on a character boundary
+        let mut end = max;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        writer.push(AD_TYPE_SHORTENED_LOCAL_NAME, &bytes[..end]);
+    }
+    writer
+}
+
+/// Walks the AD structures of an advertising or scan response payload,
+/// yielding `(ad_type, data)`; stops at the first malformed structure
+pub fn ad_structures(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = data;
+    core::iter::from_fn(move || {
+        let (&len, tail) = rest.split_first()?;
+        let len = len as usize;
+        if len == 0 || len > tail.len() {
+            rest = &[];
+            return None;
+        }
+        let (structure, next) = tail.split_at(len);
+        rest = next;
+        Some((structure[0], &structure[1..]))
+    })
+}
+
+/// Finds the pet beacon in an advertising payload, `None` for devices not running this firmware
+pub fn find_beacon(data: &[u8]) -> Option<PetBeacon> {
+    ad_structures(data)
+        .filter(|(ad_type, _)| *ad_type == AD_TYPE_MANUFACTURER_DATA)
+        .find_map(|(_, manufacturer)| PetBeacon::from_bytes(manufacturer))
+}
+
+/// Complete or shortened local name of an advertising or scan response payload
+pub fn local_name(data: &[u8]) -> Option<&str> {
+    ad_structures(data)
+        .find(|(ad_type, _)| matches!(*ad_type, AD_TYPE_COMPLETE_LOCAL_NAME | AD_TYPE_SHORTENED_LOCAL_NAME))
+        .and_then(|(_, name)| core::str::from_utf8(name).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BATTERY: u16 = 0x180F;
+    const HID: u16 = 0x1812;
+
+    fn beacon() -> PetBeacon {
+        PetBeacon {
+            species: Species::Kitty,
+            stage: LifeStage::Child,
+            mood: TamagotchiStatus::Hungry,
+            happiness_bucket: 2,
+        }
+    }
+
+    #[test]
+    fn advertising_data_byte_layout() {
+        let data = advertising_data(&[BATTERY, HID], &beacon());
+
+        assert_eq!(
+            data.as_bytes(),
+            &[
+                0x02, 0x01, 0x06, // flags
+                0x05, 0x03, 0x0F, 0x18, 0x12, 0x18, // complete 16-bit UUIDs
+                0x0A, 0xFF, 0xFF, 0xFF, b'T', b'G', 0x01, 0x01, 0x02, 0x01, 0x02, // beacon
+            ]
+        );
+        assert!(data.as_bytes().len() <= ADV_DATA_MAX_LEN);
+    }
+
+    #[test]
+    fn scan_response_byte_layout() {
+        let data = scan_response_data("Tamagotchi");
+
+        assert_eq!(data.as_bytes()[..2], [11, AD_TYPE_COMPLETE_LOCAL_NAME]);
+        assert_eq!(&data.as_bytes()[2..], b"Tamagotchi");
+    }
+
+    #[test]
+    fn long_name_is_shortened_to_fit() {
+        let name = "Tamagotchi of the lab bench number 42";
+        let data = scan_response_data(name);
+
+        assert_eq!(data.as_bytes().len(), ADV_DATA_MAX_LEN);
+        assert_eq!(data.as_bytes()[..2], [30, AD_TYPE_SHORTENED_LOCAL_NAME]);
+        assert_eq!(&data.as_bytes()[2..], &name.as_bytes()[..29]);
+    }
+
+    #[test]
+    fn name_cut_respects_utf8_boundaries() {
+        // 28 ASCII bytes followed by a two byte character straddling the limit
+        let data = scan_response_data("aaaaaaaaaaaaaaaaaaaaaaaaaaaaéé");
+
+        assert_eq!(data.as_bytes().len(), 30);
+        assert!(core::str::from_utf8(&data.as_bytes()[2..]).is_ok());
+    }
+
+    #[test]
+    fn beacon_always_fits_even_with_many_uuids() {
+        let uuids = [0x1800, 0x1801, 0x180A, 0x180F, 0x1812, 0x181C, 0x1820, 0x1822, 0x1826];
+        let data = advertising_data(&uuids, &beacon());
+
+        assert!(data.as_bytes().len() <= ADV_DATA_MAX_LEN);
+        let mut structures = ad_structures(data.as_bytes());
+        assert_eq!(structures.next().unwrap().0, AD_TYPE_FLAGS);
+        assert_eq!(structures.next().unwrap().0, AD_TYPE_INCOMPLETE_UUIDS16);
+        let (ad_type, manufacturer) = structures.next().unwrap();
+        assert_eq!(ad_type, AD_TYPE_MANUFACTURER_DATA);
+        assert_eq!(PetBeacon::from_bytes(manufacturer), Some(beacon()));
+    }
+
+    #[test]
+    fn every_beacon_fits_in_31_bytes() {
+        for species in [Species::Blob, Species::Kitty, Species::Dino] {
+            for stage in 0..=4 {
+                for mood in TamagotchiStatus::ALL {
+                    for bucket in 0..HAPPINESS_BUCKETS {
+                        let beacon = PetBeacon {
+                            species,
+                            stage: LifeStage::from_u8(stage).unwrap(),
+                            mood,
+                            happiness_bucket: bucket,
+                        };
+                        let data = advertising_data(&[BATTERY, HID], &beacon);
+                        assert!(data.as_bytes().len() <= ADV_DATA_MAX_LEN);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn happiness_buckets() {
+        assert_eq!(PetBeacon::happiness_bucket(0), 0);
+        assert_eq!(PetBeacon::happiness_bucket(25), 0);
+        assert_eq!(PetBeacon::happiness_bucket(26), 1);
+        assert_eq!(PetBeacon::happiness_bucket(75), 2);
+        assert_eq!(PetBeacon::happiness_bucket(100), 3);
+        assert_eq!(PetBeacon::happiness_bucket(255), 3);
+    }
+
+    #[test]
+    fn beacon_roundtrip_and_rejection() {
+        let bytes = beacon().to_bytes();
+        assert_eq!(PetBeacon::from_bytes(&bytes), Some(beacon()));
+
+        let mut wrong_magic = bytes;
+        wrong_magic[2] = b'X';
+        assert_eq!(PetBeacon::from_bytes(&wrong_magic), None);
+        assert_eq!(PetBeacon::from_bytes(&bytes[..8]), None);
+    }
+
+    #[test]
+    fn scanner_recognises_our_advertising_data() {
+        let data = advertising_data(&[BATTERY, HID], &beacon());
+
+        assert_eq!(find_beacon(data.as_bytes()), Some(beacon()));
+        assert_eq!(local_name(scan_response_data("Tamagotchi").as_bytes()), Some("Tamagotchi"));
+    }
+
+    #[test]
+    fn scanner_ignores_other_devices() {
+        // A phone: flags, another company's manufacturer data and a name
+        let phone = [
+            0x02, 0x01, 0x1A, //
+            0x05, 0xFF, 0x4C, 0x00, 0x10, 0x05, //
+            0x06, 0x09, b'P', b'h', b'o', b'n', b'e',
+        ];
+        assert_eq!(find_beacon(&phone), None);
+        assert_eq!(local_name(&phone), Some("Phone"));
+
+        // Same company identifier and magic, but from a newer beacon version
+        let mut newer = beacon().to_bytes();
+        newer[4] = BEACON_VERSION + 1;
+        let mut writer = AdWriter::new();
+        writer.push(AD_TYPE_MANUFACTURER_DATA, &newer);
+        assert_eq!(find_beacon(writer.as_bytes()), None);
+    }
+
+    #[test]
+    fn scanner_survives_malformed_payloads() {
+        let data = advertising_data(&[BATTERY], &beacon());
+        let bytes = data.as_bytes();
+
+        // Cut inside the beacon: its length byte now points past the end
+        assert_eq!(find_beacon(&bytes[..bytes.len() - 1]), None);
+        // A zero length structure ends the walk before the beacon
+        let mut zero = [0u8; ADV_DATA_MAX_LEN];
+        zero[3..3 + bytes.len() - 3].copy_from_slice(&bytes[3..]);
+        assert_eq!(find_beacon(&zero), None);
+        assert_eq!(find_beacon(&[]), None);
+        assert_eq!(local_name(&[0x03, 0x09, 0xFF, 0xFE]), None);
+    }
+
+    #[test]
+    fn writer_refuses_structures_that_do_not_fit() {
+        let mut writer = AdWriter::new();
+        assert!(writer.push(AD_TYPE_MANUFACTURER_DATA, &[0u8; 29]));
+        assert!(!writer.push(AD_TYPE_FLAGS, &[0x06]));
+        assert_eq!(writer.as_bytes().len(), ADV_DATA_MAX_LEN);
+    }
+}
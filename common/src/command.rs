@@ -0,0 +1,212 @@
+//! Commands the phone writes to the command characteristic.
+//!
+//! A command is the payload of a [`MessageType::Command`](crate::protocol::MessageType::Command)
+//! frame:
+//!
+//! ```text
+//!  offset  size  field
+//!  0       1     opcode
+//!  1       n     arguments
+//! ```
+//!
+//! `Lights` takes one byte (0 = off, 1 = on), `Rename` takes 1..=16 bytes of
+//! UTF-8, `Language` takes one language code, every other opcode takes no
+//! arguments.
+
+use crate::i18n::Language;
+
+/// Maximum length, in bytes, of a pet name set through [`Command::Rename`]
+pub const PET_NAME_MAX: usize = 16;
+
+/// Size of a command payload: one opcode byte followed by its arguments
+pub const COMMAND_MAX_LEN: usize = 1 + PET_NAME_MAX;
+
+/// Opcodes accepted on the command characteristic
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandOpcode {
+    Feed = 0x01,
+    Play = 0x02,
+    Clean = 0x03,
+    Medicine = 0x04,
+    Lights = 0x05,
+    Rename = 0x06,
+    Query = 0x07,
+    Language = 0x08,
+}
+
+impl CommandOpcode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(CommandOpcode::Feed),
+            0x02 => Some(CommandOpcode::Play),
+            0x03 => Some(CommandOpcode::Clean),
+            0x04 => Some(CommandOpcode::Medicine),
+            0x05 => Some(CommandOpcode::Lights),
+            0x06 => Some(CommandOpcode::Rename),
+            0x07 => Some(CommandOpcode::Query),
+            0x08 => Some(CommandOpcode::Language),
+            _ => None,
+        }
+    }
+}
+
+/// Status code sent back in every command result notification
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    Ok = 0x00,
+    UnknownOpcode = 0x01,
+    InvalidLength = 0x02,
+    InvalidArgument = 0x03,
+    /// The command is valid but the pet can't do it right now (e.g. playing while sick)
+    Rejected = 0x04,
+    /// The write isn't a valid protocol frame (bad version, length or CRC) or isn't a command frame
+    InvalidFrame = 0x05,
+}
+
+/// Name carried by [`Command::Rename`]: 1..=[`PET_NAME_MAX`] bytes of UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PetName {
+    bytes: [u8; PET_NAME_MAX],
+    len: u8,
+}
+
+impl PetName {
+    /// `None` when `name` is empty or longer than [`PET_NAME_MAX`] bytes
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name.len() > PET_NAME_MAX {
+            return None;
+        }
+        let mut bytes = [0u8; PET_NAME_MAX];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(PetName { bytes, len: name.len() as u8 })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only built from validated UTF-8
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+/// A validated command written by the phone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Feed,
+    Play,
+    Clean,
+    Medicine,
+    Lights(bool),
+    Rename(PetName),
+    Query,
+    SetLanguage(Language),
+}
+
+impl Command {
+    /// Parses and validates the payload of a command frame
+    pub fn parse(data: &[u8]) -> Result<Self, CommandStatus> {
+        let (&opcode, args) = data.split_first().ok_or(CommandStatus::InvalidLength)?;
+        let opcode = CommandOpcode::from_u8(opcode).ok_or(CommandStatus::UnknownOpcode)?;
+
+        match opcode {
+            CommandOpcode::Lights => match args {
+                [0] => Ok(Command::Lights(false)),
+                [1] => Ok(Command::Lights(true)),
+                [_] => Err(CommandStatus::InvalidArgument),
+                _ => Err(CommandStatus::InvalidLength),
+            },
+            CommandOpcode::Language => match args {
+                [code] => Language::from_u8(*code)
+                    .map(Command::SetLanguage)
+                    .ok_or(CommandStatus::InvalidArgument),
+                _ => Err(CommandStatus::InvalidLength),
+            },
+            CommandOpcode::Rename => {
+                if args.is_empty() || args.len() > PET_NAME_MAX {
+                    return Err(CommandStatus::InvalidLength);
+                }
+                let name = core::str::from_utf8(args).map_err(|_| CommandStatus::InvalidArgument)?;
+                PetName::new(name).map(Command::Rename).ok_or(CommandStatus::InvalidLength)
+            }
+            _ if !args.is_empty() => Err(CommandStatus::InvalidLength),
+            CommandOpcode::Feed => Ok(Command::Feed),
+            CommandOpcode::Play => Ok(Command::Play),
+            CommandOpcode::Clean => Ok(Command::Clean),
+            CommandOpcode::Medicine => Ok(Command::Medicine),
+            CommandOpcode::Query => Ok(Command::Query),
+        }
+    }
+
+    pub fn opcode(&self) -> CommandOpcode {
+        match self {
+            Command::Feed => CommandOpcode::Feed,
+            Command::Play => CommandOpcode::Play,
+            Command::Clean => CommandOpcode::Clean,
+            Command::Medicine => CommandOpcode::Medicine,
+            Command::Lights(_) => CommandOpcode::Lights,
+            Command::Rename(_) => CommandOpcode::Rename,
+            Command::Query => CommandOpcode::Query,
+            Command::SetLanguage(_) => CommandOpcode::Language,
+        }
+    }
+
+    /// Encodes the payload, returns the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; COMMAND_MAX_LEN], usize) {
+        let mut buffer = [0u8; COMMAND_MAX_LEN];
+        buffer[0] = self.opcode() as u8;
+        let args: &[u8] = match self {
+            Command::Lights(on) => &[*on as u8],
+            Command::Rename(name) => name.as_str().as_bytes(),
+            Command::SetLanguage(language) => &[*language as u8],
+            _ => &[],
+        };
+        buffer[1..1 + args.len()].copy_from_slice(args);
+        (buffer, 1 + args.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_every_command() {
+        let commands = [
+            Command::Feed,
+            Command::Play,
+            Command::Clean,
+            Command::Medicine,
+            Command::Lights(false),
+            Command::Lights(true),
+            Command::Rename(PetName::new("Mochi").unwrap()),
+            Command::Query,
+            Command::SetLanguage(Language::Spanish),
+        ];
+        for command in commands {
+            let (bytes, len) = command.to_bytes();
+            assert_eq!(Command::parse(&bytes[..len]), Ok(command));
+        }
+
+        let (bytes, len) = Command::SetLanguage(Language::English).to_bytes();
+        assert_eq!(&bytes[..len], &[0x08, 0x01]);
+    }
+
+    #[test]
+    fn unknown_and_short_input_is_rejected() {
+        assert_eq!(Command::parse(&[]), Err(CommandStatus::InvalidLength));
+        assert_eq!(Command::parse(&[0x00]), Err(CommandStatus::UnknownOpcode));
+        assert_eq!(Command::parse(&[0x09]), Err(CommandStatus::UnknownOpcode));
+        assert_eq!(Command::parse(&[0x05]), Err(CommandStatus::InvalidLength));
+        assert_eq!(Command::parse(&[0x06]), Err(CommandStatus::InvalidLength));
+        assert_eq!(Command::parse(&[0x08]), Err(CommandStatus::InvalidLength));
+        assert_eq!(Command::parse(&[0x01, 0x00]), Err(CommandStatus::InvalidLength));
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert_eq!(Command::parse(&[0x05, 0x02]), Err(CommandStatus::InvalidArgument));
+        assert_eq!(Command::parse(&[0x08, 0x7F]), Err(CommandStatus::InvalidArgument));
+        assert_eq!(Command::parse(&[0x06, 0xFF, 0xFE]), Err(CommandStatus::InvalidArgument));
+        assert_eq!(Command::parse(&[0x06; 1 + PET_NAME_MAX + 1]), Err(CommandStatus::InvalidLength));
+    }
+}
@@ -0,0 +1,663 @@
+//! Firmware update over BLE (DFU): the messages and the chunk bookkeeping,
+//! kept apart from the radio and the flash so they run on the host too.
+//!
+//! The phone drives the update through the two characteristics of the DFU
+//! service. Their values are raw, not [`protocol`](crate::protocol) frames:
+//! the image is large and ATT already checks every write.
+//!
+//! ```text
+//!  control write   01 size:u32 sha256:[32]   start an update
+//!                  02                         finish: verify and boot the image
+//!                  03                         abort
+//!  data write      offset:u32 bytes...        a chunk of the image
+//!  control notify  opcode status next:u32     answer to a control write, or to a
+//!                                             refused chunk (opcode 04)
+//! ```
+//!
+//! Numbers are little endian. Chunks must arrive in order and all but the
+//! last must be a multiple of [`WRITE_ALIGN`] bytes long. A chunk that was
+//! already written (a retransmission) is accepted without touching the flash;
+//! a chunk past the expected offset means one was lost, and its refusal
+//! carries the offset to resume from. Every chunk is read back from flash
+//! into the running SHA-256 once written; on finish the image must match the
+//! announced size and digest.
+
+use core::convert::Infallible;
+
+use crate::sha256::{DIGEST_LEN, Sha256};
+
+/// Chunks are written to flash in words of this many bytes
+pub const WRITE_ALIGN: usize = 4;
+
+/// Offset in front of every chunk
+pub const CHUNK_HEADER_LEN: usize = 4;
+
+/// Largest image bytes in one chunk, fits a 247 byte ATT MTU with the offset
+pub const DFU_CHUNK_MAX_LEN: usize = 240;
+
+/// Largest data write
+pub const DFU_DATA_MAX_LEN: usize = CHUNK_HEADER_LEN + DFU_CHUNK_MAX_LEN;
+
+/// Largest control write, a start
+pub const DFU_CONTROL_MAX_LEN: usize = 1 + 4 + DIGEST_LEN;
+
+/// Bytes read back at once while verifying
+const VERIFY_BLOCK_LEN: usize = 256;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuOpcode {
+    Start = 0x01,
+    Finish = 0x02,
+    Abort = 0x03,
+    /// Only in notifications, a chunk written on the data characteristic
+    Chunk = 0x04,
+}
+
+impl DfuOpcode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(DfuOpcode::Start),
+            0x02 => Some(DfuOpcode::Finish),
+            0x03 => Some(DfuOpcode::Abort),
+            0x04 => Some(DfuOpcode::Chunk),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuStatus {
+    Ok = 0,
+    /// The write couldn't be decoded
+    Malformed = 1,
+    /// Chunk or finish without a start
+    NotStarted = 2,
+    /// Another connection is updating
+    Busy = 3,
+    /// Empty image, or larger than the partition
+    InvalidSize = 4,
+    /// Chunk length not a multiple of [`WRITE_ALIGN`] before the last one
+    Misaligned = 5,
+    /// A chunk was lost, resume from the offset of the notification
+    OutOfOrder = 6,
+    /// Chunk past the announced size
+    Overrun = 7,
+    /// Finish before every byte arrived
+    Incomplete = 8,
+    /// The image in flash doesn't match the announced SHA-256, start over
+    HashMismatch = 9,
+    /// The verified image isn't a firmware image the bootloader can start
+    InvalidImage = 10,
+    FlashError = 11,
+}
+
+impl DfuStatus {
+    pub const ALL: [DfuStatus; 12] = [
+        DfuStatus::Ok,
+        DfuStatus::Malformed,
+        DfuStatus::NotStarted,
+        DfuStatus::Busy,
+        DfuStatus::InvalidSize,
+        DfuStatus::Misaligned,
+        DfuStatus::OutOfOrder,
+        DfuStatus::Overrun,
+        DfuStatus::Incomplete,
+        DfuStatus::HashMismatch,
+        DfuStatus::InvalidImage,
+        DfuStatus::FlashError,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+/// Size and digest announced when an update starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub size: u32,
+    pub sha256: [u8; DIGEST_LEN],
+}
+
+/// A write on the control characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuControl {
+    Start(ImageInfo),
+    Finish,
+    Abort,
+}
+
+impl DfuControl {
+    pub fn opcode(&self) -> DfuOpcode {
+        match self {
+            DfuControl::Start(_) => DfuOpcode::Start,
+            DfuControl::Finish => DfuOpcode::Finish,
+            DfuControl::Abort => DfuOpcode::Abort,
+        }
+    }
+
+    /// Encodes the write, returns the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; DFU_CONTROL_MAX_LEN], usize) {
+        let mut buffer = [0u8; DFU_CONTROL_MAX_LEN];
+        buffer[0] = self.opcode() as u8;
+        let len = match self {
+            DfuControl::Start(image) => {
+                buffer[1..5].copy_from_slice(&image.size.to_le_bytes());
+                buffer[5..].copy_from_slice(&image.sha256);
+                DFU_CONTROL_MAX_LEN
+            }
+            DfuControl::Finish | DfuControl::Abort => 1,
+        };
+        (buffer, len)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&opcode, args) = bytes.split_first()?;
+        match (DfuOpcode::from_u8(opcode)?, args.len()) {
+            (DfuOpcode::Start, len) if len == DFU_CONTROL_MAX_LEN - 1 => Some(DfuControl::Start(ImageInfo {
+                size: u32::from_le_bytes(args[..4].try_into().ok()?),
+                sha256: args[4..].try_into().ok()?,
+            })),
+            (DfuOpcode::Finish, 0) => Some(DfuControl::Finish),
+            (DfuOpcode::Abort, 0) => Some(DfuControl::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// A write on the data characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuChunk<'a> {
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> DfuChunk<'a> {
+    /// Encodes the write, returns the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; DFU_DATA_MAX_LEN], usize) {
+        let mut buffer = [0u8; DFU_DATA_MAX_LEN];
+        let len = self.data.len().min(DFU_CHUNK_MAX_LEN);
+        buffer[..CHUNK_HEADER_LEN].copy_from_slice(&self.offset.to_le_bytes());
+        buffer[CHUNK_HEADER_LEN..CHUNK_HEADER_LEN + len].copy_from_slice(&self.data[..len]);
+        (buffer, CHUNK_HEADER_LEN + len)
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        let (offset, data) = bytes.split_first_chunk::<CHUNK_HEADER_LEN>()?;
+        Some(DfuChunk { offset: u32::from_le_bytes(*offset), data })
+    }
+}
+
+/// Notified on the control characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuResponse {
+    pub opcode: DfuOpcode,
+    pub status: DfuStatus,
+    /// Image bytes written so far, where the next chunk starts
+    pub next_offset: u32,
+}
+
+impl DfuResponse {
+    pub const LEN: usize = 6;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[0] = self.opcode as u8;
+        bytes[1] = self.status as u8;
+        bytes[2..].copy_from_slice(&self.next_offset.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [opcode, status, a, b, c, d] => Some(DfuResponse {
+                opcode: DfuOpcode::from_u8(*opcode)?,
+                status: DfuStatus::from_u8(*status)?,
+                next_offset: u32::from_le_bytes([*a, *b, *c, *d]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuError<E> {
+    NotStarted,
+    InvalidSize,
+    Misaligned,
+    OutOfOrder { expected: u32 },
+    Overrun,
+    Incomplete { received: u32 },
+    HashMismatch,
+    Flash(E),
+}
+
+impl<E> DfuError<E> {
+    pub fn status(&self) -> DfuStatus {
+        match self {
+            DfuError::NotStarted => DfuStatus::NotStarted,
+            DfuError::InvalidSize => DfuStatus::InvalidSize,
+            DfuError::Misaligned => DfuStatus::Misaligned,
+            DfuError::OutOfOrder { .. } => DfuStatus::OutOfOrder,
+            DfuError::Overrun => DfuStatus::Overrun,
+            DfuError::Incomplete { .. } => DfuStatus::Incomplete,
+            DfuError::HashMismatch => DfuStatus::HashMismatch,
+            DfuError::Flash(_) => DfuStatus::FlashError,
+        }
+    }
+}
+
+/// The partition an image is written to, addressed from its start.
+///
+/// Like NOR flash, bytes must be erased before they are written, erases
+/// cover whole [`ERASE_SIZE`](Self::ERASE_SIZE) sectors, and writes and
+/// reads are aligned to [`WRITE_ALIGN`].
+pub trait DfuFlash {
+    type Error;
+
+    const ERASE_SIZE: u32;
+
+    fn capacity(&self) -> u32;
+
+    /// Erases the sectors in `from..to`, both multiples of the erase size
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Writes the chunks of one image to flash and verifies it.
+///
+/// Sectors are erased as the chunks reach them, so starting an update is
+/// instant and an aborted one only wiped what it wrote.
+#[derive(Debug, Clone, Default)]
+pub struct DfuReceiver {
+    image: Option<ImageInfo>,
+    received: u32,
+    /// Sectors below this offset are erased
+    erased: u32,
+    /// Digest of what the flash holds up to `received`
+    hasher: Sha256,
+}
+
+impl DfuReceiver {
+    pub const fn new() -> Self {
+        DfuReceiver { image: None, received: 0, erased: 0, hasher: Sha256::new() }
+    }
+
+    /// The image being received, `None` when idle
+    pub fn image(&self) -> Option<ImageInfo> {
+        self.image
+    }
+
+    /// Where the next chunk starts
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// Starts receiving `image`, dropping any update in progress
+    pub fn start<F: DfuFlash>(&mut self, flash: &F, image: ImageInfo) -> Result<(), DfuError<F::Error>> {
+        self.abort();
+        // The last chunk is padded to a whole word
+        let padded = (image.size as usize).next_multiple_of(WRITE_ALIGN);
+        if image.size == 0 || padded > flash.capacity() as usize {
+            return Err(DfuError::InvalidSize);
+        }
+        self.image = Some(image);
+        Ok(())
+    }
+
+    pub fn abort(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Writes the chunk at `offset`, returns where the next one starts
+    pub fn write<F: DfuFlash>(&mut self, flash: &mut F, offset: u32, data: &[u8]) -> Result<u32, DfuError<F::Error>> {
+        let image = self.image.ok_or(DfuError::NotStarted)?;
+        let end = offset as u64 + data.len() as u64;
+        if end <= self.received as u64 {
+            // Retransmitted, already in flash
+            return Ok(self.received);
+        }
+        if offset != self.received {
+            return Err(DfuError::OutOfOrder { expected: self.received });
+        }
+        if end > image.size as u64 {
+            return Err(DfuError::Overrun);
+        }
+        let end = end as u32;
+        let tail_len = data.len() % WRITE_ALIGN;
+        if tail_len != 0 && end != image.size {
+            return Err(DfuError::Misaligned);
+        }
+
+        let padded_end = (end as usize).next_multiple_of(WRITE_ALIGN) as u32;
+        if padded_end > self.erased {
+            let to = padded_end.next_multiple_of(F::ERASE_SIZE).min(flash.capacity());
+            flash.erase(self.erased, to).map_err(DfuError::Flash)?;
+            self.erased = to;
+        }
+
+        let (words, tail) = data.split_at(data.len() - tail_len);
+        if !words.is_empty() {
+            flash.write(offset, words).map_err(DfuError::Flash)?;
+        }
+        if !tail.is_empty() {
+            let mut word = [0xFF; WRITE_ALIGN];
+            word[..tail.len()].copy_from_slice(tail);
+            flash.write(offset + words.len() as u32, &word).map_err(DfuError::Flash)?;
+        }
+        self.hash_written(flash, offset, end)?;
+
+        self.received = end;
+        Ok(end)
+    }
+
+    /// Reads `from..to` back into the digest, which only changes if every read succeeds
+    fn hash_written<F: DfuFlash>(&mut self, flash: &mut F, from: u32, to: u32) -> Result<(), DfuError<F::Error>> {
+        let mut hasher = self.hasher.clone();
+        let mut block = [0u8; VERIFY_BLOCK_LEN];
+        let mut offset = from;
+        while offset < to {
+            let len = (to - offset).min(VERIFY_BLOCK_LEN as u32) as usize;
+            flash
+                .read(offset, &mut block[..len.next_multiple_of(WRITE_ALIGN)])
+                .map_err(DfuError::Flash)?;
+            hasher.update(&block[..len]);
+            offset += len as u32;
+        }
+        self.hasher = hasher;
+        Ok(())
+    }
+
+    /// Checks the image against the announced size and digest.
+    ///
+    /// The flash was hashed chunk by chunk as it was written, so this doesn't
+    /// touch it. Missing bytes can still be sent after [`DfuError::Incomplete`];
+    /// after [`DfuError::HashMismatch`] the update has to start over.
+    pub fn finish(&mut self) -> Result<ImageInfo, DfuError<Infallible>> {
+        let image = self.image.ok_or(DfuError::NotStarted)?;
+        if self.received != image.size {
+            return Err(DfuError::Incomplete { received: self.received });
+        }
+
+        let digest = self.hasher.clone().finalize();
+        self.abort();
+        if digest != image.sha256 {
+            return Err(DfuError::HashMismatch);
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 4096;
+    const CAPACITY: usize = 4 * SECTOR;
+    /// Not a multiple of the word size, spans three sectors
+    const IMAGE_LEN: usize = 10_001;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Fault;
+
+    /// NOR flash in RAM that panics on anything real flash wouldn't accept
+    struct MemoryFlash {
+        bytes: [u8; CAPACITY],
+        erases: usize,
+        writes: usize,
+        fail_writes: bool,
+        /// Byte whose lowest bit stays erased when written
+        stuck_bit: Option<usize>,
+    }
+
+    impl MemoryFlash {
+        fn new() -> Self {
+            // Leftovers of the previous image
+            MemoryFlash { bytes: [0x5A; CAPACITY], erases: 0, writes: 0, fail_writes: false, stuck_bit: None }
+        }
+    }
+
+    impl DfuFlash for MemoryFlash {
+        type Error = Fault;
+
+        const ERASE_SIZE: u32 = SECTOR as u32;
+
+        fn capacity(&self) -> u32 {
+            CAPACITY as u32
+        }
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Fault> {
+            assert!(from.is_multiple_of(Self::ERASE_SIZE) && to.is_multiple_of(Self::ERASE_SIZE) && from < to);
+            self.bytes[from as usize..to as usize].fill(0xFF);
+            self.erases += ((to - from) / Self::ERASE_SIZE) as usize;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Fault> {
+            if self.fail_writes {
+                return Err(Fault);
+            }
+            let offset = offset as usize;
+            assert!(offset.is_multiple_of(WRITE_ALIGN) && bytes.len().is_multiple_of(WRITE_ALIGN));
+            for (old, new) in self.bytes[offset..offset + bytes.len()].iter_mut().zip(bytes) {
+                assert_eq!(*old, 0xFF, "write over bytes that weren't erased");
+                *old = *new;
+            }
+            if let Some(stuck) = self.stuck_bit.filter(|i| (offset..offset + bytes.len()).contains(i)) {
+                self.bytes[stuck] |= 0x01;
+            }
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Fault> {
+            let offset = offset as usize;
+            assert!(offset.is_multiple_of(WRITE_ALIGN) && bytes.len().is_multiple_of(WRITE_ALIGN));
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+    }
+
+    fn image() -> [u8; IMAGE_LEN] {
+        core::array::from_fn(|i| (i * 31 + i / 256) as u8)
+    }
+
+    fn info(image: &[u8]) -> ImageInfo {
+        ImageInfo { size: image.len() as u32, sha256: Sha256::digest(image) }
+    }
+
+    /// Sends `image` from `offset` in chunks of `chunk_len`, as a phone would
+    fn send_from(receiver: &mut DfuReceiver, flash: &mut MemoryFlash, image: &[u8], offset: usize, chunk_len: usize) {
+        for (i, chunk) in image[offset..].chunks(chunk_len).enumerate() {
+            let chunk_offset = (offset + i * chunk_len) as u32;
+            assert_eq!(receiver.write(flash, chunk_offset, chunk), Ok(chunk_offset + chunk.len() as u32));
+        }
+    }
+
+    #[test]
+    fn full_update_verifies_size_and_hash() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+
+        receiver.start(&flash, info(&image)).unwrap();
+        send_from(&mut receiver, &mut flash, &image, 0, DFU_CHUNK_MAX_LEN);
+        assert_eq!(receiver.finish(), Ok(info(&image)));
+
+        assert_eq!(&flash.bytes[..IMAGE_LEN], &image);
+        // The last word is padded and the sectors past the image are untouched
+        assert_eq!(&flash.bytes[IMAGE_LEN..IMAGE_LEN.next_multiple_of(WRITE_ALIGN)], &[0xFF; 3]);
+        assert_eq!(flash.bytes[3 * SECTOR], 0x5A);
+        assert_eq!(flash.erases, 3);
+        assert_eq!(receiver.image(), None);
+    }
+
+    #[test]
+    fn retransmitted_chunks_are_not_written_again() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        receiver.start(&flash, info(&image)).unwrap();
+
+        assert_eq!(receiver.write(&mut flash, 0, &image[..240]), Ok(240));
+        assert_eq!(receiver.write(&mut flash, 240, &image[240..480]), Ok(480));
+        let writes = flash.writes;
+        // The acknowledgement of the first chunk was lost and the phone sent it again
+        assert_eq!(receiver.write(&mut flash, 0, &image[..240]), Ok(480));
+        assert_eq!(flash.writes, writes);
+
+        send_from(&mut receiver, &mut flash, &image, 480, DFU_CHUNK_MAX_LEN);
+        assert!(receiver.finish().is_ok());
+    }
+
+    #[test]
+    fn a_lost_chunk_tells_where_to_resume() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        receiver.start(&flash, info(&image)).unwrap();
+
+        send_from(&mut receiver, &mut flash, &image[..960], 0, DFU_CHUNK_MAX_LEN);
+        // 960..1200 never arrived
+        assert_eq!(
+            receiver.write(&mut flash, 1200, &image[1200..1440]),
+            Err(DfuError::OutOfOrder { expected: 960 })
+        );
+        // A chunk overlapping the end of what was written isn't split either
+        assert_eq!(
+            receiver.write(&mut flash, 720, &image[720..1200]),
+            Err(DfuError::OutOfOrder { expected: 960 })
+        );
+        assert_eq!(receiver.received(), 960);
+
+        send_from(&mut receiver, &mut flash, &image, 960, DFU_CHUNK_MAX_LEN);
+        assert!(receiver.finish().is_ok());
+    }
+
+    #[test]
+    fn chunk_sizes_may_change_between_chunks() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        receiver.start(&flash, info(&image)).unwrap();
+
+        // The phone negotiated a larger MTU halfway
+        send_from(&mut receiver, &mut flash, &image[..4000], 0, 20);
+        send_from(&mut receiver, &mut flash, &image, 4000, DFU_CHUNK_MAX_LEN);
+        assert!(receiver.finish().is_ok());
+        assert_eq!(&flash.bytes[..IMAGE_LEN], &image);
+    }
+
+    #[test]
+    fn rejects_chunks_that_break_the_rules() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        assert_eq!(receiver.write(&mut flash, 0, &image[..240]), Err(DfuError::NotStarted));
+
+        receiver.start(&flash, info(&image)).unwrap();
+        assert_eq!(receiver.write(&mut flash, 0, &image[..239]), Err(DfuError::Misaligned));
+        send_from(&mut receiver, &mut flash, &image[..9840], 0, DFU_CHUNK_MAX_LEN);
+        assert_eq!(receiver.write(&mut flash, 9840, &[0; 200]), Err(DfuError::Overrun));
+        // The odd length is fine for the last chunk
+        assert_eq!(receiver.write(&mut flash, 9840, &image[9840..]), Ok(IMAGE_LEN as u32));
+    }
+
+    #[test]
+    fn rejects_images_that_do_not_fit() {
+        let flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        let sha256 = [0; DIGEST_LEN];
+
+        assert_eq!(receiver.start(&flash, ImageInfo { size: 0, sha256 }), Err(DfuError::InvalidSize));
+        assert_eq!(
+            receiver.start(&flash, ImageInfo { size: CAPACITY as u32 - 1, sha256 }),
+            Ok(())
+        );
+        assert_eq!(
+            receiver.start(&flash, ImageInfo { size: CAPACITY as u32 + 1, sha256 }),
+            Err(DfuError::InvalidSize)
+        );
+        assert_eq!(receiver.image(), None);
+    }
+
+    #[test]
+    fn finish_checks_size_and_hash() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        assert_eq!(receiver.finish(), Err(DfuError::NotStarted));
+
+        receiver.start(&flash, info(&image)).unwrap();
+        send_from(&mut receiver, &mut flash, &image[..4800], 0, DFU_CHUNK_MAX_LEN);
+        assert_eq!(receiver.finish(), Err(DfuError::Incomplete { received: 4800 }));
+
+        // Still running: the rest can be sent, but a bit the flash failed to program fails the hash
+        assert_eq!(image[5001] & 0x01, 0);
+        flash.stuck_bit = Some(5001);
+        send_from(&mut receiver, &mut flash, &image, 4800, DFU_CHUNK_MAX_LEN);
+        assert_eq!(receiver.finish(), Err(DfuError::HashMismatch));
+        assert_eq!(receiver.write(&mut flash, 0, &image[..240]), Err(DfuError::NotStarted));
+    }
+
+    #[test]
+    fn start_over_after_a_flash_error() {
+        let image = image();
+        let mut flash = MemoryFlash::new();
+        let mut receiver = DfuReceiver::new();
+        receiver.start(&flash, info(&image)).unwrap();
+
+        send_from(&mut receiver, &mut flash, &image[..480], 0, DFU_CHUNK_MAX_LEN);
+        flash.fail_writes = true;
+        assert_eq!(receiver.write(&mut flash, 480, &image[480..720]), Err(DfuError::Flash(Fault)));
+        assert_eq!(receiver.received(), 480);
+
+        flash.fail_writes = false;
+        flash.bytes.fill(0x5A);
+        receiver.start(&flash, info(&image)).unwrap();
+        send_from(&mut receiver, &mut flash, &image, 0, DFU_CHUNK_MAX_LEN);
+        assert!(receiver.finish().is_ok());
+    }
+
+    #[test]
+    fn control_roundtrip() {
+        let start = DfuControl::Start(ImageInfo { size: 0x0001_2345, sha256: [0xAB; DIGEST_LEN] });
+        let (bytes, len) = start.to_bytes();
+        assert_eq!(&bytes[..5], &[0x01, 0x45, 0x23, 0x01, 0x00]);
+        assert_eq!(DfuControl::from_bytes(&bytes[..len]), Some(start));
+
+        for control in [DfuControl::Finish, DfuControl::Abort] {
+            let (bytes, len) = control.to_bytes();
+            assert_eq!(DfuControl::from_bytes(&bytes[..len]), Some(control));
+        }
+    }
+
+    #[test]
+    fn control_rejects_bad_writes() {
+        let (bytes, len) = DfuControl::Start(ImageInfo { size: 1, sha256: [0; DIGEST_LEN] }).to_bytes();
+        assert_eq!(DfuControl::from_bytes(&bytes[..len - 1]), None);
+        assert_eq!(DfuControl::from_bytes(&[]), None);
+        assert_eq!(DfuControl::from_bytes(&[0x02, 0x00]), None);
+        // Chunks only go on the data characteristic
+        assert_eq!(DfuControl::from_bytes(&[0x04]), None);
+    }
+
+    #[test]
+    fn chunk_and_response_roundtrip() {
+        let chunk = DfuChunk { offset: 0x0102_0304, data: &[1, 2, 3] };
+        let (bytes, len) = chunk.to_bytes();
+        assert_eq!(&bytes[..len], &[0x04, 0x03, 0x02, 0x01, 1, 2, 3]);
+        assert_eq!(DfuChunk::from_bytes(&bytes[..len]), Some(chunk));
+        assert_eq!(DfuChunk::from_bytes(&[0, 0, 0]), None);
+
+        let response = DfuResponse { opcode: DfuOpcode::Chunk, status: DfuStatus::OutOfOrder, next_offset: 960 };
+        assert_eq!(response.to_bytes(), [0x04, 6, 0xC0, 0x03, 0, 0]);
+        assert_eq!(DfuResponse::from_bytes(&response.to_bytes()), Some(response));
+        assert_eq!(DfuResponse::from_bytes(&[0x01, 12, 0, 0, 0, 0]), None);
+    }
+}
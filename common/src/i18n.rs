@@ -0,0 +1,136 @@
+//! Compile-time string tables for the texts the pet sends to the phone.
+//!
+//! Each [`Language`] has one [`Strings`] table; adding a language means adding
+//! a variant and its table, and the tests make sure no entry is missing.
+
+use crate::pet::TamagotchiStatus;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    Portuguese = 0,
+    English = 1,
+    Spanish = 2,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::Portuguese, Language::English, Language::Spanish];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Language::Portuguese),
+            1 => Some(Language::English),
+            2 => Some(Language::Spanish),
+            _ => None,
+        }
+    }
+
+    pub const fn strings(self) -> &'static Strings {
+        match self {
+            Language::Portuguese => &PORTUGUESE,
+            Language::English => &ENGLISH,
+            Language::Spanish => &SPANISH,
+        }
+    }
+}
+
+/// Every text of one language, UTF-8 encoded
+#[derive(Debug)]
+pub struct Strings {
+    /// Sent when a phone starts listening to messages
+    pub welcome: &'static [u8],
+    /// One entry per [`TamagotchiStatus`], indexed by its code
+    pub status: [&'static [u8]; TamagotchiStatus::ALL.len()],
+}
+
+impl Strings {
+    pub fn status(&self, status: TamagotchiStatus) -> &'static [u8] {
+        self.status[status as usize]
+    }
+}
+
+const PORTUGUESE: Strings = Strings {
+    welcome: "Conectado!".as_bytes(),
+    status: [
+        "Estou feliz!".as_bytes(),
+        "Com fome...".as_bytes(),
+        "Cansado...".as_bytes(),
+        "Doente :(".as_bytes(),
+        "Brincando!".as_bytes(),
+        "Dormindo zzz".as_bytes(),
+        "Cadê você?".as_bytes(),
+    ],
+};
+
+const ENGLISH: Strings = Strings {
+    welcome: "Connected!".as_bytes(),
+    status: [
+        "I'm happy!".as_bytes(),
+        "Hungry...".as_bytes(),
+        "Tired...".as_bytes(),
+        "Sick :(".as_bytes(),
+        "Playing!".as_bytes(),
+        "Sleeping zzz".as_bytes(),
+        "Where are you?".as_bytes(),
+    ],
+};
+
+const SPANISH: Strings = Strings {
+    welcome: "¡Conectado!".as_bytes(),
+    status: [
+        "¡Estoy feliz!".as_bytes(),
+        "Tengo hambre...".as_bytes(),
+        "Cansado...".as_bytes(),
+        "Enfermo :(".as_bytes(),
+        "¡Jugando!".as_bytes(),
+        "Durmiendo zzz".as_bytes(),
+        "¿Dónde estás?".as_bytes(),
+    ],
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MESSAGE_MAX_LEN;
+
+    fn all_texts(strings: &Strings) -> impl Iterator<Item = &'static [u8]> + '_ {
+        core::iter::once(strings.welcome).chain(strings.status.iter().copied())
+    }
+
+    #[test]
+    fn every_status_is_translated_in_every_language() {
+        for language in Language::ALL {
+            let strings = language.strings();
+            for status in TamagotchiStatus::ALL {
+                assert!(!strings.status(status).is_empty(), "{:?} {:?}", language, status);
+            }
+        }
+    }
+
+    #[test]
+    fn status_table_follows_status_codes() {
+        // Portuguese was the original text, the table must keep the same order
+        assert_eq!(Language::Portuguese.strings().status(TamagotchiStatus::Happy), b"Estou feliz!");
+        assert_eq!(Language::Portuguese.strings().status(TamagotchiStatus::Sleeping), b"Dormindo zzz");
+        assert_eq!(Language::English.strings().status(TamagotchiStatus::Sick), b"Sick :(");
+    }
+
+    #[test]
+    fn texts_are_utf8_and_fit_the_message_characteristic() {
+        for language in Language::ALL {
+            for text in all_texts(language.strings()) {
+                assert!(core::str::from_utf8(text).is_ok());
+                assert!(!text.is_empty() && text.len() <= MESSAGE_MAX_LEN, "{:?}", language);
+            }
+        }
+    }
+
+    #[test]
+    fn language_codes_roundtrip() {
+        for language in Language::ALL {
+            assert_eq!(Language::from_u8(language as u8), Some(language));
+        }
+        assert_eq!(Language::from_u8(3), None);
+    }
+}
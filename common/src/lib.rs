@@ -0,0 +1,18 @@
+//! Platform independent pieces of the Tamagotchi firmware.
+//!
+//! Everything in here is `no_std` and free of ESP32 dependencies so the same
+//! code can be used by the firmware and by host-side companion tools.
+#![no_std]
+
+pub mod beacon;
+pub mod command;
+pub mod dfu;
+pub mod i18n;
+pub mod pet;
+pub mod playdate;
+pub mod protocol;
+pub mod proximity;
+pub mod sha256;
+pub mod shell;
+pub mod traffic;
+pub mod visit;
@@ -0,0 +1,89 @@
+//! Pet attributes shared between the firmware, its advertising data and the companion tools.
+
+use crate::i18n::Language;
+
+/// Estados possíveis do Tamagotchi para notificações
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamagotchiStatus {
+    Happy = 0,
+    Hungry = 1,
+    Tired = 2,
+    Sick = 3,
+    Playing = 4,
+    Sleeping = 5,
+    /// The owner's phone walked away
+    Lonely = 6,
+}
+
+impl TamagotchiStatus {
+    pub const ALL: [TamagotchiStatus; 7] = [
+        TamagotchiStatus::Happy,
+        TamagotchiStatus::Hungry,
+        TamagotchiStatus::Tired,
+        TamagotchiStatus::Sick,
+        TamagotchiStatus::Playing,
+        TamagotchiStatus::Sleeping,
+        TamagotchiStatus::Lonely,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TamagotchiStatus::Happy),
+            1 => Some(TamagotchiStatus::Hungry),
+            2 => Some(TamagotchiStatus::Tired),
+            3 => Some(TamagotchiStatus::Sick),
+            4 => Some(TamagotchiStatus::Playing),
+            5 => Some(TamagotchiStatus::Sleeping),
+            6 => Some(TamagotchiStatus::Lonely),
+            _ => None,
+        }
+    }
+
+    /// Texto enviado ao telefone para este status
+    pub fn as_message(&self, language: Language) -> &'static [u8] {
+        language.strings().status(*self)
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Species {
+    Blob = 0,
+    Kitty = 1,
+    Dino = 2,
+}
+
+impl Species {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Species::Blob),
+            1 => Some(Species::Kitty),
+            2 => Some(Species::Dino),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LifeStage {
+    Egg = 0,
+    Baby = 1,
+    Child = 2,
+    Teen = 3,
+    Adult = 4,
+}
+
+impl LifeStage {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(LifeStage::Egg),
+            1 => Some(LifeStage::Baby),
+            2 => Some(LifeStage::Child),
+            3 => Some(LifeStage::Teen),
+            4 => Some(LifeStage::Adult),
+            _ => None,
+        }
+    }
+}
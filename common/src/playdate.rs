@@ -0,0 +1,446 @@
+//! Play dates: the short interaction two pets run when they meet.
+//!
+//! The visiting pet is the initiator; every message is answered by exactly
+//! one message from the other side, so both state machines stay in lockstep
+//! over any reliable, ordered link:
+//!
+//! ```text
+//!  initiator               responder
+//!  Greeting          ->
+//!                    <-    Greeting
+//!  Move (round 0)    ->
+//!                    <-    Move (round 0)
+//!  ...                     ... ROUNDS rounds of rock-paper-scissors
+//!  Gift              ->
+//!                    <-    Gift
+//!  Farewell          ->
+//!                    <-    Farewell
+//! ```
+//!
+//! Each message is the payload of a [`MessageType::PlayDate`](crate::protocol::MessageType::PlayDate)
+//! frame, starting with a kind byte:
+//!
+//! ```text
+//!  kind  message   arguments
+//!  0x01  Greeting  friendship level the sender has for the other pet, visit payload
+//!  0x02  Move      round, hand
+//!  0x03  Gift      gift
+//!  0x04  Farewell  friendship level after the play date
+//! ```
+
+use crate::visit::{VISIT_MAX_LEN, Visit};
+
+/// Rounds of the mini-game
+pub const ROUNDS: usize = 3;
+
+/// Friendship grows by one per play date up to this level
+pub const FRIENDSHIP_MAX: u8 = 10;
+
+/// Largest encoded message, a greeting
+pub const PLAY_MESSAGE_MAX_LEN: usize = 2 + VISIT_MAX_LEN;
+
+/// Happiness every play date gives, plus a bonus per round won and for the gift
+const PLAY_DATE_JOY: u8 = 10;
+const WIN_JOY: u8 = 5;
+const GIFT_JOY: u8 = 5;
+
+const GREETING: u8 = 0x01;
+const MOVE: u8 = 0x02;
+const GIFT: u8 = 0x03;
+const FAREWELL: u8 = 0x04;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hand {
+    Rock = 0,
+    Paper = 1,
+    Scissors = 2,
+}
+
+impl Hand {
+    pub const ALL: [Hand; 3] = [Hand::Rock, Hand::Paper, Hand::Scissors];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    /// Picks a hand from any random number
+    pub fn from_random(value: u32) -> Self {
+        Self::ALL[value as usize % Self::ALL.len()]
+    }
+
+    pub fn beats(self, other: Hand) -> bool {
+        matches!(
+            (self, other),
+            (Hand::Rock, Hand::Scissors) | (Hand::Paper, Hand::Rock) | (Hand::Scissors, Hand::Paper)
+        )
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gift {
+    Flower = 0,
+    Snack = 1,
+    Toy = 2,
+}
+
+impl Gift {
+    pub const ALL: [Gift; 3] = [Gift::Flower, Gift::Snack, Gift::Toy];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    pub fn from_random(value: u32) -> Self {
+        Self::ALL[value as usize % Self::ALL.len()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The visiting pet, sends the first message
+    Initiator,
+    Responder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayMessage {
+    Greeting { friendship: u8, visit: Visit },
+    Move { round: u8, hand: Hand },
+    Gift(Gift),
+    Farewell { friendship: u8 },
+}
+
+impl PlayMessage {
+    /// Encodes the payload, returns the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; PLAY_MESSAGE_MAX_LEN], usize) {
+        let mut buffer = [0u8; PLAY_MESSAGE_MAX_LEN];
+        let len = match self {
+            PlayMessage::Greeting { friendship, visit } => {
+                let (payload, len) = visit.to_bytes();
+                buffer[0] = GREETING;
+                buffer[1] = *friendship;
+                buffer[2..2 + len].copy_from_slice(&payload[..len]);
+                2 + len
+            }
+            PlayMessage::Move { round, hand } => {
+                buffer[..3].copy_from_slice(&[MOVE, *round, *hand as u8]);
+                3
+            }
+            PlayMessage::Gift(gift) => {
+                buffer[..2].copy_from_slice(&[GIFT, *gift as u8]);
+                2
+            }
+            PlayMessage::Farewell { friendship } => {
+                buffer[..2].copy_from_slice(&[FAREWELL, *friendship]);
+                2
+            }
+        };
+        (buffer, len)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        match data {
+            [GREETING, friendship, visit @ ..] if *friendship <= FRIENDSHIP_MAX => Some(PlayMessage::Greeting {
+                friendship: *friendship,
+                visit: Visit::from_bytes(visit)?,
+            }),
+            [MOVE, round, hand] if (*round as usize) < ROUNDS => Some(PlayMessage::Move {
+                round: *round,
+                hand: Hand::from_u8(*hand)?,
+            }),
+            [GIFT, gift] => Some(PlayMessage::Gift(Gift::from_u8(*gift)?)),
+            [FAREWELL, friendship] if *friendship <= FRIENDSHIP_MAX => {
+                Some(PlayMessage::Farewell { friendship: *friendship })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayDateError {
+    /// The message doesn't belong at this point of the play date
+    Unexpected(PlayMessage),
+    /// The play date already ended
+    Finished,
+}
+
+/// How the play date went, from this pet's side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub friend: Visit,
+    pub wins: u8,
+    pub losses: u8,
+    pub ties: u8,
+    /// What the other pet gave us
+    pub gift: Gift,
+    /// Our friendship level with the other pet, to be stored
+    pub friendship: u8,
+    /// Happiness the pet gains
+    pub happiness: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Greeting,
+    Playing(u8),
+    Gifting,
+    Farewell,
+    Finished,
+}
+
+/// One side of a play date, independent of the link that carries it
+#[derive(Debug, Clone)]
+pub struct PlayDate {
+    role: Role,
+    me: Visit,
+    friendship: u8,
+    hands: [Hand; ROUNDS],
+    gift: Gift,
+    state: State,
+    friend: Option<Visit>,
+    wins: u8,
+    losses: u8,
+    ties: u8,
+    received: Option<Gift>,
+}
+
+impl PlayDate {
+    /// `friendship` is the level stored for the other pet (0 when they never met);
+    /// the hands and the gift are picked by the caller, usually at random
+    pub fn new(role: Role, me: Visit, friendship: u8, hands: [Hand; ROUNDS], gift: Gift) -> Self {
+        PlayDate {
+            role,
+            me,
+            friendship: friendship.min(FRIENDSHIP_MAX),
+            hands,
+            gift,
+            state: State::Greeting,
+            friend: None,
+            wins: 0,
+            losses: 0,
+            ties: 0,
+            received: None,
+        }
+    }
+
+    /// First message to send: the greeting for the initiator, nothing for the responder
+    pub fn start(&self) -> Option<PlayMessage> {
+        match (self.role, self.state) {
+            (Role::Initiator, State::Greeting) => Some(self.greeting()),
+            _ => None,
+        }
+    }
+
+    /// Handles a message from the other pet and returns the answer to send, if any
+    pub fn on_message(&mut self, message: PlayMessage) -> Result<Option<PlayMessage>, PlayDateError> {
+        let initiator = self.role == Role::Initiator;
+        let reply = match (self.state, message) {
+            (State::Finished, _) => return Err(PlayDateError::Finished),
+            (State::Greeting, PlayMessage::Greeting { visit, .. }) => {
+                self.friend = Some(visit);
+                self.state = State::Playing(0);
+                if initiator {
+                    Some(PlayMessage::Move { round: 0, hand: self.hands[0] })
+                } else {
+                    Some(self.greeting())
+                }
+            }
+            (State::Playing(current), PlayMessage::Move { round, hand }) if round == current => {
+                let mine = self.hands[round as usize];
+                self.score(mine, hand);
+
+                let next = round + 1;
+                self.state = if (next as usize) < ROUNDS { State::Playing(next) } else { State::Gifting };
+                match (initiator, self.state) {
+                    (false, _) => Some(PlayMessage::Move { round, hand: mine }),
+                    (true, State::Playing(next)) => Some(PlayMessage::Move { round: next, hand: self.hands[next as usize] }),
+                    (true, _) => Some(PlayMessage::Gift(self.gift)),
+                }
+            }
+            (State::Gifting, PlayMessage::Gift(gift)) => {
+                self.received = Some(gift);
+                self.state = State::Farewell;
+                if initiator {
+                    self.friendship = self.next_friendship();
+                    Some(PlayMessage::Farewell { friendship: self.friendship })
+                } else {
+                    Some(PlayMessage::Gift(self.gift))
+                }
+            }
+            (State::Farewell, PlayMessage::Farewell { .. }) => {
+                self.state = State::Finished;
+                if initiator {
+                    None
+                } else {
+                    self.friendship = self.next_friendship();
+                    Some(PlayMessage::Farewell { friendship: self.friendship })
+                }
+            }
+            (_, message) => return Err(PlayDateError::Unexpected(message)),
+        };
+        Ok(reply)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Finished
+    }
+
+    /// Result of a finished play date
+    pub fn outcome(&self) -> Option<Outcome> {
+        if !self.is_finished() {
+            return None;
+        }
+        Some(Outcome {
+            friend: self.friend?,
+            wins: self.wins,
+            losses: self.losses,
+            ties: self.ties,
+            gift: self.received?,
+            friendship: self.friendship,
+            happiness: PLAY_DATE_JOY + WIN_JOY * self.wins + GIFT_JOY,
+        })
+    }
+
+    fn greeting(&self) -> PlayMessage {
+        PlayMessage::Greeting { friendship: self.friendship, visit: self.me }
+    }
+
+    fn score(&mut self, mine: Hand, theirs: Hand) {
+        if mine.beats(theirs) {
+            self.wins += 1;
+        } else if theirs.beats(mine) {
+            self.losses += 1;
+        } else {
+            self.ties += 1;
+        }
+    }
+
+    fn next_friendship(&self) -> u8 {
+        (self.friendship + 1).min(FRIENDSHIP_MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beacon::PetBeacon;
+    use crate::pet::{LifeStage, Species, TamagotchiStatus};
+
+    fn visit(name: &str, species: Species) -> Visit {
+        let pet = PetBeacon {
+            species,
+            stage: LifeStage::Child,
+            mood: TamagotchiStatus::Happy,
+            happiness_bucket: 2,
+        };
+        Visit::new(species as u32, pet, name)
+    }
+
+    /// Runs both sides against each other, every message going through its byte encoding
+    fn play(initiator: &mut PlayDate, responder: &mut PlayDate) -> usize {
+        let mut message = initiator.start();
+        let mut to_responder = true;
+        let mut exchanged = 0;
+
+        while let Some(sent) = message {
+            let (bytes, len) = sent.to_bytes();
+            let received = PlayMessage::from_bytes(&bytes[..len]).unwrap();
+            assert_eq!(received, sent);
+
+            let side = if to_responder { &mut *responder } else { &mut *initiator };
+            message = side.on_message(received).unwrap();
+            to_responder = !to_responder;
+            exchanged += 1;
+        }
+        exchanged
+    }
+
+    #[test]
+    fn two_pets_play_to_the_end() {
+        let blob = visit("Blob", Species::Blob);
+        let rex = visit("Rex", Species::Dino);
+        let mut initiator = PlayDate::new(Role::Initiator, blob, 2, [Hand::Rock, Hand::Paper, Hand::Rock], Gift::Toy);
+        let mut responder = PlayDate::new(Role::Responder, rex, 0, [Hand::Scissors, Hand::Paper, Hand::Paper], Gift::Snack);
+
+        // Greetings, three rounds, gifts and farewells, two messages each
+        assert_eq!(play(&mut initiator, &mut responder), 2 * (1 + ROUNDS + 2));
+        assert!(initiator.is_finished() && responder.is_finished());
+
+        let mine = initiator.outcome().unwrap();
+        let theirs = responder.outcome().unwrap();
+        assert_eq!(mine.friend, rex);
+        assert_eq!(theirs.friend, blob);
+        assert_eq!((mine.wins, mine.ties, mine.losses), (1, 1, 1));
+        assert_eq!((theirs.wins, theirs.ties, theirs.losses), (1, 1, 1));
+        assert_eq!(mine.gift, Gift::Snack);
+        assert_eq!(theirs.gift, Gift::Toy);
+        assert_eq!(mine.friendship, 3);
+        assert_eq!(theirs.friendship, 1);
+        assert_eq!(mine.happiness, PLAY_DATE_JOY + WIN_JOY + GIFT_JOY);
+    }
+
+    #[test]
+    fn scores_mirror_each_other() {
+        for (a, b) in Hand::ALL.iter().zip(Hand::ALL.iter().rev()) {
+            let mut initiator = PlayDate::new(Role::Initiator, visit("A", Species::Kitty), 0, [*a; ROUNDS], Gift::Flower);
+            let mut responder = PlayDate::new(Role::Responder, visit("B", Species::Blob), 0, [*b; ROUNDS], Gift::Flower);
+            play(&mut initiator, &mut responder);
+
+            let mine = initiator.outcome().unwrap();
+            let theirs = responder.outcome().unwrap();
+            assert_eq!((mine.wins, mine.ties, mine.losses), (theirs.losses, theirs.ties, theirs.wins));
+        }
+    }
+
+    #[test]
+    fn friendship_is_capped() {
+        let mut initiator =
+            PlayDate::new(Role::Initiator, visit("A", Species::Kitty), FRIENDSHIP_MAX, [Hand::Rock; ROUNDS], Gift::Toy);
+        let mut responder =
+            PlayDate::new(Role::Responder, visit("B", Species::Blob), 200, [Hand::Rock; ROUNDS], Gift::Toy);
+        play(&mut initiator, &mut responder);
+
+        assert_eq!(initiator.outcome().unwrap().friendship, FRIENDSHIP_MAX);
+        assert_eq!(responder.outcome().unwrap().friendship, FRIENDSHIP_MAX);
+    }
+
+    #[test]
+    fn out_of_order_messages_are_rejected() {
+        let mut responder = PlayDate::new(Role::Responder, visit("B", Species::Blob), 0, [Hand::Rock; ROUNDS], Gift::Toy);
+        assert_eq!(responder.start(), None);
+
+        let early = PlayMessage::Move { round: 0, hand: Hand::Rock };
+        assert_eq!(responder.on_message(early), Err(PlayDateError::Unexpected(early)));
+        assert!(responder.outcome().is_none());
+
+        let greeting = PlayMessage::Greeting { friendship: 0, visit: visit("A", Species::Kitty) };
+        assert!(responder.on_message(greeting).unwrap().is_some());
+        let skipped = PlayMessage::Move { round: 1, hand: Hand::Rock };
+        assert_eq!(responder.on_message(skipped), Err(PlayDateError::Unexpected(skipped)));
+    }
+
+    #[test]
+    fn finished_play_dates_take_no_more_messages() {
+        let mut initiator = PlayDate::new(Role::Initiator, visit("A", Species::Kitty), 0, [Hand::Rock; ROUNDS], Gift::Toy);
+        let mut responder = PlayDate::new(Role::Responder, visit("B", Species::Blob), 0, [Hand::Rock; ROUNDS], Gift::Toy);
+        play(&mut initiator, &mut responder);
+
+        let farewell = PlayMessage::Farewell { friendship: 1 };
+        assert_eq!(initiator.on_message(farewell), Err(PlayDateError::Finished));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert_eq!(PlayMessage::from_bytes(&[]), None);
+        assert_eq!(PlayMessage::from_bytes(&[0x7F]), None);
+        assert_eq!(PlayMessage::from_bytes(&[MOVE, ROUNDS as u8, 0]), None);
+        assert_eq!(PlayMessage::from_bytes(&[MOVE, 0, 3]), None);
+        assert_eq!(PlayMessage::from_bytes(&[MOVE, 0, 0, 0]), None);
+        assert_eq!(PlayMessage::from_bytes(&[GIFT, 9]), None);
+        assert_eq!(PlayMessage::from_bytes(&[FAREWELL, FRIENDSHIP_MAX + 1]), None);
+        assert_eq!(PlayMessage::from_bytes(&[GREETING, 0, 0x00]), None);
+    }
+}
@@ -0,0 +1,319 @@
+//! Fragmentation of encoded frames into MTU-sized characteristic values.
+//!
+//! Every fragment starts with a one byte header followed by a slice of the
+//! encoded frame:
+//!
+//! ```text
+//!  bit 7     LAST   set on the final fragment of a frame
+//!  bits 0-6  INDEX  position of the fragment, starting at 0
+//! ```
+//!
+//! A frame therefore spans at most [`MAX_FRAGMENTS`] values. A fragment with
+//! index 0 always starts a new frame, discarding any partial one.
+
+/// Bytes taken by the fragment header
+pub const FRAGMENT_HEADER_LEN: usize = 1;
+
+/// Maximum number of fragments per frame
+pub const MAX_FRAGMENTS: usize = 128;
+
+const LAST_FLAG: u8 = 0x80;
+const INDEX_MASK: u8 = 0x7F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// The value is too short to hold a fragment header
+    Empty,
+    /// A fragment arrived without the ones before it
+    OutOfOrder { expected: u8, received: u8 },
+    /// The reassembled frame doesn't fit in the reassembly buffer
+    Overflow,
+    /// The data needs more than [`MAX_FRAGMENTS`] fragments, or the fragment size leaves no room for data
+    TooManyFragments,
+}
+
+/// One fragment produced by [`Fragments`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment<'a> {
+    pub index: u8,
+    pub last: bool,
+    pub data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Number of bytes [`Fragment::write_to`] writes
+    pub fn encoded_len(&self) -> usize {
+        FRAGMENT_HEADER_LEN + self.data.len()
+    }
+
+    /// Writes header and data at the start of `buffer`, returning the number of bytes written
+    pub fn write_to(&self, buffer: &mut [u8]) -> Option<usize> {
+        let len = self.encoded_len();
+        if buffer.len() < len {
+            return None;
+        }
+        buffer[0] = (self.index & INDEX_MASK) | if self.last { LAST_FLAG } else { 0 };
+        buffer[FRAGMENT_HEADER_LEN..len].copy_from_slice(self.data);
+        Some(len)
+    }
+
+    pub fn parse(value: &'a [u8]) -> Result<Self, FragmentError> {
+        let (&header, data) = value.split_first().ok_or(FragmentError::Empty)?;
+        Ok(Fragment {
+            index: header & INDEX_MASK,
+            last: header & LAST_FLAG != 0,
+            data,
+        })
+    }
+}
+
+/// Iterator splitting `data` into fragments of at most `max_fragment_len` bytes, header included
+#[derive(Debug, Clone)]
+pub struct Fragments<'a> {
+    data: &'a [u8],
+    chunk_len: usize,
+    index: u8,
+    done: bool,
+}
+
+impl<'a> Fragments<'a> {
+    pub fn new(data: &'a [u8], max_fragment_len: usize) -> Result<Self, FragmentError> {
+        let chunk_len = max_fragment_len.saturating_sub(FRAGMENT_HEADER_LEN);
+        if chunk_len == 0 || data.len().div_ceil(chunk_len) > MAX_FRAGMENTS {
+            return Err(FragmentError::TooManyFragments);
+        }
+        Ok(Fragments {
+            data,
+            chunk_len,
+            index: 0,
+            done: false,
+        })
+    }
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = Fragment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let len = self.data.len().min(self.chunk_len);
+        let (data, rest) = self.data.split_at(len);
+        let last = rest.is_empty();
+        let fragment = Fragment {
+            index: self.index,
+            last,
+            data,
+        };
+
+        self.data = rest;
+        self.index += 1;
+        self.done = last;
+        Some(fragment)
+    }
+}
+
+/// Rebuilds a frame from fragments received in order
+#[derive(Debug)]
+pub struct Reassembler<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    expected: u8,
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new() -> Self {
+        Reassembler {
+            buffer: [0u8; N],
+            len: 0,
+            expected: 0,
+        }
+    }
+
+    /// Drops any partially received frame
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.expected = 0;
+    }
+
+    /// Feeds one characteristic value.
+    ///
+    /// Returns the complete frame once the last fragment arrives, `None` while
+    /// more fragments are expected. On error the partial frame is discarded.
+    pub fn push(&mut self, value: &[u8]) -> Result<Option<&[u8]>, FragmentError> {
+        let fragment = Fragment::parse(value)?;
+
+        if fragment.index == 0 {
+            self.reset();
+        } else if fragment.index != self.expected {
+            let expected = self.expected;
+            self.reset();
+            return Err(FragmentError::OutOfOrder {
+                expected,
+                received: fragment.index,
+            });
+        }
+
+        let end = self.len + fragment.data.len();
+        if end > N {
+            self.reset();
+            return Err(FragmentError::Overflow);
+        }
+        self.buffer[self.len..end].copy_from_slice(fragment.data);
+        self.len = end;
+        self.expected = fragment.index.wrapping_add(1);
+
+        if fragment.last {
+            let len = self.len;
+            self.len = 0;
+            self.expected = 0;
+            Ok(Some(&self.buffer[..len]))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> [u8; 300] {
+        let mut data = [0u8; 300];
+        for (i, byte) in data.iter_mut().enumerate().take(len) {
+            *byte = i as u8;
+        }
+        data
+    }
+
+    #[test]
+    fn short_data_is_a_single_last_fragment() {
+        let mut fragments = Fragments::new(b"oi", 20).unwrap();
+        let fragment = fragments.next().unwrap();
+
+        assert_eq!(fragment, Fragment { index: 0, last: true, data: b"oi" });
+        assert!(fragments.next().is_none());
+    }
+
+    #[test]
+    fn empty_data_is_a_single_empty_fragment() {
+        let fragments: collect::Collected = Fragments::new(&[], 20).unwrap().collect();
+        assert_eq!(fragments.count, 1);
+        assert!(fragments.last_flags[0]);
+    }
+
+    #[test]
+    fn split_sizes_and_flags() {
+        let data = sample(45);
+        let fragments: collect::Collected = Fragments::new(&data[..45], 20).unwrap().collect();
+
+        // 19 data bytes per fragment: 19 + 19 + 7
+        assert_eq!(fragments.count, 3);
+        assert_eq!(&fragments.lens[..3], &[19, 19, 7]);
+        assert_eq!(&fragments.last_flags[..3], &[false, false, true]);
+    }
+
+    #[test]
+    fn header_byte_layout() {
+        let mut buffer = [0u8; 4];
+        let fragment = Fragment { index: 5, last: true, data: &[0xAA] };
+
+        assert_eq!(fragment.write_to(&mut buffer), Some(2));
+        assert_eq!(&buffer[..2], &[0x85, 0xAA]);
+        assert_eq!(Fragment::parse(&buffer[..2]), Ok(fragment));
+    }
+
+    #[test]
+    fn roundtrip_through_reassembler() {
+        let data = sample(300);
+        let mut reassembler = Reassembler::<512>::new();
+        let mut value = [0u8; 23];
+        let mut result = None;
+
+        for fragment in Fragments::new(&data, value.len()).unwrap() {
+            let len = fragment.write_to(&mut value).unwrap();
+            if let Some(frame) = reassembler.push(&value[..len]).unwrap() {
+                let mut copy = [0u8; 300];
+                copy[..frame.len()].copy_from_slice(frame);
+                result = Some((copy, frame.len()));
+            }
+        }
+
+        let (copy, len) = result.unwrap();
+        assert_eq!(&copy[..len], &data[..]);
+    }
+
+    #[test]
+    fn missing_fragment_is_out_of_order() {
+        let mut reassembler = Reassembler::<64>::new();
+        assert_eq!(reassembler.push(&[0x00, 1, 2]), Ok(None));
+        assert_eq!(
+            reassembler.push(&[0x82, 5]),
+            Err(FragmentError::OutOfOrder { expected: 1, received: 2 })
+        );
+        // The partial frame was dropped
+        assert_eq!(
+            reassembler.push(&[0x81, 3]),
+            Err(FragmentError::OutOfOrder { expected: 0, received: 1 })
+        );
+    }
+
+    #[test]
+    fn index_zero_restarts_the_frame() {
+        let mut reassembler = Reassembler::<64>::new();
+        assert_eq!(reassembler.push(&[0x00, 1, 2]), Ok(None));
+        assert_eq!(reassembler.push(&[0x80, 9]), Ok(Some(&[9u8][..])));
+    }
+
+    #[test]
+    fn overflow_is_rejected() {
+        let mut reassembler = Reassembler::<4>::new();
+        assert_eq!(reassembler.push(&[0x00, 1, 2, 3]), Ok(None));
+        assert_eq!(reassembler.push(&[0x81, 4, 5]), Err(FragmentError::Overflow));
+    }
+
+    #[test]
+    fn empty_value_is_rejected() {
+        let mut reassembler = Reassembler::<4>::new();
+        assert_eq!(reassembler.push(&[]), Err(FragmentError::Empty));
+    }
+
+    #[test]
+    fn too_many_fragments_is_rejected() {
+        let data = sample(300);
+        assert_eq!(Fragments::new(&data, 2).err(), Some(FragmentError::TooManyFragments));
+        assert_eq!(Fragments::new(&data, 1).err(), Some(FragmentError::TooManyFragments));
+    }
+
+    /// Collects fragment metadata without an allocator
+    mod collect {
+        use super::super::Fragment;
+
+        #[derive(Default)]
+        pub struct Collected {
+            pub count: usize,
+            pub lens: [usize; 8],
+            pub last_flags: [bool; 8],
+        }
+
+        impl<'a> FromIterator<Fragment<'a>> for Collected {
+            fn from_iter<I: IntoIterator<Item = Fragment<'a>>>(iter: I) -> Self {
+                let mut collected = Collected::default();
+                for fragment in iter {
+                    collected.lens[collected.count] = fragment.data.len();
+                    collected.last_flags[collected.count] = fragment.last;
+                    collected.count += 1;
+                }
+                collected
+            }
+        }
+    }
+}
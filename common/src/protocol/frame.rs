@@ -0,0 +1,337 @@
+use crate::protocol::crc::crc16;
+use crate::protocol::{CRC_LEN, FRAME_OVERHEAD, HEADER_LEN, PROTOCOL_VERSION};
+
+/// Kind of message carried by a [`Frame`]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Command = 0x01,
+    CommandResult = 0x02,
+    Message = 0x03,
+    Counter = 0x04,
+    Status = 0x05,
+    TransferStart = 0x06,
+    TransferData = 0x07,
+    TransferEnd = 0x08,
+    Visit = 0x09,
+    PlayDate = 0x0A,
+}
+
+impl MessageType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(MessageType::Command),
+            0x02 => Some(MessageType::CommandResult),
+            0x03 => Some(MessageType::Message),
+            0x04 => Some(MessageType::Counter),
+            0x05 => Some(MessageType::Status),
+            0x06 => Some(MessageType::TransferStart),
+            0x07 => Some(MessageType::TransferData),
+            0x08 => Some(MessageType::TransferEnd),
+            0x09 => Some(MessageType::Visit),
+            0x0A => Some(MessageType::PlayDate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The output buffer can't hold the encoded frame
+    BufferTooSmall,
+    /// The payload doesn't fit in the 16-bit length field
+    PayloadTooLarge,
+    /// The input ends before the header, payload or CRC is complete
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    CrcMismatch { expected: u16, actual: u16 },
+}
+
+/// One protocol frame, borrowing its payload from the decoded buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub message_type: MessageType,
+    pub sequence: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(message_type: MessageType, sequence: u16, payload: &'a [u8]) -> Self {
+        Frame {
+            message_type,
+            sequence,
+            payload,
+        }
+    }
+
+    /// Number of bytes [`Frame::encode`] writes
+    pub fn encoded_len(&self) -> usize {
+        self.payload.len() + FRAME_OVERHEAD
+    }
+
+    /// Writes the frame at the start of `buffer` and returns the number of bytes written
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, FrameError> {
+        let length = u16::try_from(self.payload.len()).map_err(|_| FrameError::PayloadTooLarge)?;
+        let total = self.encoded_len();
+        if buffer.len() < total {
+            return Err(FrameError::BufferTooSmall);
+        }
+
+        buffer[0] = PROTOCOL_VERSION;
+        buffer[1] = self.message_type as u8;
+        buffer[2..4].copy_from_slice(&self.sequence.to_le_bytes());
+        buffer[4..6].copy_from_slice(&length.to_le_bytes());
+        buffer[HEADER_LEN..HEADER_LEN + self.payload.len()].copy_from_slice(self.payload);
+
+        let crc_offset = total - CRC_LEN;
+        let crc = crc16(&buffer[..crc_offset]);
+        buffer[crc_offset..total].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(total)
+    }
+
+    /// Decodes the frame at the start of `buffer`.
+    ///
+    /// Returns the frame and the number of bytes it occupies; anything after
+    /// that (e.g. zero padding of a fixed-size characteristic) is ignored.
+    pub fn decode(buffer: &'a [u8]) -> Result<(Self, usize), FrameError> {
+        if buffer.len() < HEADER_LEN {
+            return Err(FrameError::Truncated);
+        }
+
+        let version = buffer[0];
+        if version != PROTOCOL_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+
+        let length = u16::from_le_bytes([buffer[4], buffer[5]]) as usize;
+        let total = HEADER_LEN + length + CRC_LEN;
+        if buffer.len() < total {
+            return Err(FrameError::Truncated);
+        }
+
+        let crc_offset = HEADER_LEN + length;
+        let expected = u16::from_le_bytes([buffer[crc_offset], buffer[crc_offset + 1]]);
+        let actual = crc16(&buffer[..crc_offset]);
+        if expected != actual {
+            return Err(FrameError::CrcMismatch { expected, actual });
+        }
+
+        // The type is checked after the CRC so corruption is reported as such
+        let message_type =
+            MessageType::from_u8(buffer[1]).ok_or(FrameError::UnknownMessageType(buffer[1]))?;
+        let sequence = u16::from_le_bytes([buffer[2], buffer[3]]);
+
+        Ok((
+            Frame {
+                message_type,
+                sequence,
+                payload: &buffer[HEADER_LEN..crc_offset],
+            },
+            total,
+        ))
+    }
+}
+
+/// Wrapping per-sender sequence number generator
+#[derive(Debug, Clone, Default)]
+pub struct SequenceCounter {
+    next: u16,
+}
+
+impl SequenceCounter {
+    pub const fn new() -> Self {
+        SequenceCounter { next: 0 }
+    }
+
+    pub fn next_sequence(&mut self) -> u16 {
+        let sequence = self.next;
+        self.next = self.next.wrapping_add(1);
+        sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"Estou feliz!";
+
+    fn encoded(message_type: MessageType, sequence: u16, payload: &[u8]) -> ([u8; 64], usize) {
+        let mut buffer = [0u8; 64];
+        let len = Frame::new(message_type, sequence, payload).encode(&mut buffer).unwrap();
+        (buffer, len)
+    }
+
+    /// Small deterministic xorshift generator so the fuzz tests are reproducible
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let (buffer, len) = encoded(MessageType::Message, 0x1234, PAYLOAD);
+
+        assert_eq!(len, PAYLOAD.len() + FRAME_OVERHEAD);
+        assert_eq!(buffer[0], PROTOCOL_VERSION);
+        assert_eq!(buffer[1], MessageType::Message as u8);
+        assert_eq!(&buffer[2..4], &[0x34, 0x12]);
+        assert_eq!(&buffer[4..6], &(PAYLOAD.len() as u16).to_le_bytes());
+        assert_eq!(&buffer[6..6 + PAYLOAD.len()], PAYLOAD);
+        let crc = crc16(&buffer[..len - CRC_LEN]);
+        assert_eq!(&buffer[len - CRC_LEN..len], &crc.to_le_bytes());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (buffer, len) = encoded(MessageType::Counter, 7, &42u32.to_le_bytes());
+        let (frame, consumed) = Frame::decode(&buffer[..len]).unwrap();
+
+        assert_eq!(consumed, len);
+        assert_eq!(frame.message_type, MessageType::Counter);
+        assert_eq!(frame.sequence, 7);
+        assert_eq!(frame.payload, &42u32.to_le_bytes());
+    }
+
+    #[test]
+    fn empty_payload_roundtrip() {
+        let (buffer, len) = encoded(MessageType::Status, u16::MAX, &[]);
+        let (frame, consumed) = Frame::decode(&buffer[..len]).unwrap();
+
+        assert_eq!(consumed, FRAME_OVERHEAD);
+        assert!(frame.payload.is_empty());
+        assert_eq!(frame.sequence, u16::MAX);
+    }
+
+    #[test]
+    fn trailing_padding_is_ignored() {
+        let (buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        let (frame, consumed) = Frame::decode(&buffer).unwrap();
+
+        assert_eq!(consumed, len);
+        assert_eq!(frame.payload, PAYLOAD);
+    }
+
+    #[test]
+    fn encode_into_small_buffer_fails() {
+        let mut buffer = [0u8; FRAME_OVERHEAD + 3];
+        let frame = Frame::new(MessageType::Message, 0, PAYLOAD);
+        assert_eq!(frame.encode(&mut buffer), Err(FrameError::BufferTooSmall));
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let (mut buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        buffer[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Frame::decode(&buffer[..len]),
+            Err(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_message_type_with_valid_crc() {
+        let (mut buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        buffer[1] = 0xEE;
+        let crc = crc16(&buffer[..len - CRC_LEN]);
+        buffer[len - CRC_LEN..len].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(Frame::decode(&buffer[..len]), Err(FrameError::UnknownMessageType(0xEE)));
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let (mut buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        buffer[HEADER_LEN] ^= 0x01;
+        assert!(matches!(Frame::decode(&buffer[..len]), Err(FrameError::CrcMismatch { .. })));
+    }
+
+    #[test]
+    fn every_truncation_is_rejected() {
+        let (buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        for cut in 0..len {
+            assert_eq!(Frame::decode(&buffer[..cut]), Err(FrameError::Truncated), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn length_field_past_end_is_truncated() {
+        let (mut buffer, len) = encoded(MessageType::Message, 1, PAYLOAD);
+        buffer[4..6].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(Frame::decode(&buffer[..len]), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn fuzz_every_single_bit_flip_is_rejected() {
+        let (buffer, len) = encoded(MessageType::CommandResult, 0xBEEF, PAYLOAD);
+        for bit in 0..len * 8 {
+            let mut corrupted = buffer;
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            assert!(Frame::decode(&corrupted[..len]).is_err(), "bit {bit} flip accepted");
+        }
+    }
+
+    #[test]
+    fn fuzz_random_buffers_never_panic() {
+        let mut rng = XorShift(0x2545_F491);
+        let mut buffer = [0u8; 96];
+
+        for _ in 0..20_000 {
+            let len = (rng.next() as usize) % buffer.len();
+            for byte in buffer[..len].iter_mut() {
+                *byte = rng.next() as u8;
+            }
+            // Bias towards the interesting paths: valid version and small lengths
+            if len > 5 && rng.next().is_multiple_of(2) {
+                buffer[0] = PROTOCOL_VERSION;
+                buffer[5] = 0;
+                buffer[4] %= 96;
+            }
+
+            if let Ok((frame, consumed)) = Frame::decode(&buffer[..len]) {
+                assert!(consumed <= len);
+                assert_eq!(consumed, frame.payload.len() + FRAME_OVERHEAD);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_random_frames_roundtrip_and_detect_byte_corruption() {
+        let mut rng = XorShift(0xC0FF_EE11);
+        let mut payload = [0u8; 48];
+
+        for _ in 0..2_000 {
+            let payload_len = (rng.next() as usize) % payload.len();
+            for byte in payload[..payload_len].iter_mut() {
+                *byte = rng.next() as u8;
+            }
+            let sequence = rng.next() as u16;
+            let (buffer, len) = encoded(MessageType::Message, sequence, &payload[..payload_len]);
+
+            let (frame, _) = Frame::decode(&buffer[..len]).unwrap();
+            assert_eq!(frame.payload, &payload[..payload_len]);
+            assert_eq!(frame.sequence, sequence);
+
+            let mut corrupted = buffer;
+            let index = (rng.next() as usize) % len;
+            let mask = (rng.next() as u8) | 0x01;
+            corrupted[index] ^= mask;
+            assert!(Frame::decode(&corrupted[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn sequence_counter_wraps() {
+        let mut counter = SequenceCounter { next: u16::MAX };
+        assert_eq!(counter.next_sequence(), u16::MAX);
+        assert_eq!(counter.next_sequence(), 0);
+    }
+}
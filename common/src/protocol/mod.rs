@@ -0,0 +1,67 @@
+//! Framed binary protocol shared by every characteristic of the custom
+//! `12345678-1234-5678-1234-56789abcdef0` service.
+//!
+//! Each characteristic value (notification, indication, read or write) carries
+//! exactly one frame:
+//!
+//! ```text
+//!  offset  size  field
+//!  0       1     version       protocol version, currently 1
+//!  1       1     message type  see [`MessageType`]
+//!  2       2     sequence      u16 little endian, incremented per message by the sender
+//!  4       2     length        u16 little endian, number of payload bytes
+//!  6       len   payload       message specific
+//!  6+len   2     crc           CRC-16/CCITT-FALSE over bytes 0..6+len, little endian
+//! ```
+//!
+//! Replies (e.g. a command result) echo the sequence number of the request
+//! they answer. Receivers must reject frames with an unknown version; bytes
+//! after the CRC are ignored so fixed-size characteristic buffers can be
+//! decoded directly.
+//!
+//! Payloads per message type:
+//!
+//! | type            | direction      | payload                                           |
+//! |-----------------|----------------|---------------------------------------------------|
+//! | `Command`       | phone → device | `[opcode, args...]`                               |
+//! | `CommandResult` | device → phone | `[opcode, status, stats...]`                      |
+//! | `Message`       | device → phone | UTF-8 text                                        |
+//! | `Counter`       | device → phone | u32 little endian                                 |
+//! | `Status`        | device → phone | pet status code                                   |
+//! | `TransferStart` | both           | `[kind, total length u32 LE]`                     |
+//! | `TransferData`  | both           | next chunk of the transfer                        |
+//! | `TransferEnd`   | both           | total length u32 LE                               |
+//! | `Visit`         | pet ↔ pet      | see [`crate::visit`]                              |
+//! | `PlayDate`      | pet ↔ pet      | see [`crate::playdate`]                           |
+//!
+//! Frames longer than one characteristic value are split with the
+//! [`fragment`] layer; the message and command characteristics always carry
+//! fragments, even when the frame fits in a single one.
+//!
+//! Bulk transfers (sprites, save exports, logs) use the `Transfer*` messages
+//! over an L2CAP connection-oriented channel instead, one frame per SDU. Play
+//! dates between two pets run on the same channel.
+
+pub mod crc;
+pub mod fragment;
+pub mod frame;
+pub mod transfer;
+
+pub use fragment::{Fragment, FragmentError, Fragments, Reassembler};
+pub use frame::{Frame, FrameError, MessageType, SequenceCounter};
+pub use transfer::{TransferKind, TransferStart};
+
+/// Version written in every frame header
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Version, message type, sequence and length
+pub const HEADER_LEN: usize = 6;
+
+/// Trailing CRC-16
+pub const CRC_LEN: usize = 2;
+
+/// Bytes added by the framing around a payload
+pub const FRAME_OVERHEAD: usize = HEADER_LEN + CRC_LEN;
+
+/// Largest text carried by one `Message` frame, split in fragments when sent
+pub const MESSAGE_MAX_LEN: usize = 512;
@@ -0,0 +1,74 @@
+//! Payloads of the bulk transfer messages sent over the L2CAP channel.
+
+/// What a bulk transfer carries
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    /// Phone → device: a sprite image
+    SpriteUpload = 0x01,
+    /// Device → phone: the serialized pet state
+    SaveExport = 0x02,
+    /// Device → phone: recent log lines
+    LogDownload = 0x03,
+}
+
+impl TransferKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(TransferKind::SpriteUpload),
+            0x02 => Some(TransferKind::SaveExport),
+            0x03 => Some(TransferKind::LogDownload),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of a `TransferStart` frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStart {
+    pub kind: TransferKind,
+    /// Number of data bytes that follow; 0 when requesting a download
+    pub total_len: u32,
+}
+
+impl TransferStart {
+    pub const LEN: usize = 5;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[0] = self.kind as u8;
+        bytes[1..].copy_from_slice(&self.total_len.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [kind, a, b, c, d] => Some(TransferStart {
+                kind: TransferKind::from_u8(*kind)?,
+                total_len: u32::from_le_bytes([*a, *b, *c, *d]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_roundtrip() {
+        let start = TransferStart {
+            kind: TransferKind::SpriteUpload,
+            total_len: 0x0102_0304,
+        };
+        assert_eq!(start.to_bytes(), [0x01, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(TransferStart::from_bytes(&start.to_bytes()), Some(start));
+    }
+
+    #[test]
+    fn start_rejects_bad_payloads() {
+        assert_eq!(TransferStart::from_bytes(&[0x01, 0, 0, 0]), None);
+        assert_eq!(TransferStart::from_bytes(&[0x09, 0, 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,208 @@
+//! How close the owner's phone is, estimated from the RSSI of its connection.
+//!
+//! Raw RSSI jumps by 10 dB or more between consecutive readings (body
+//! shadowing, multipath, channel hopping), so readings first go through a
+//! scalar Kalman filter. The filtered value is then split in three zones;
+//! crossing a boundary takes [`HYSTERESIS_DB`] past it, so a phone sitting
+//! right at a boundary doesn't flip the zone back and forth.
+
+/// Filtered RSSI above which the phone is near (same room, in hand)
+pub const NEAR_DBM: f32 = -60.0;
+
+/// Filtered RSSI below which the phone is far (other room, about to drop)
+pub const FAR_DBM: f32 = -80.0;
+
+/// Margin past a boundary needed to change zone
+pub const HYSTERESIS_DB: f32 = 5.0;
+
+/// Reported by the controller when the RSSI isn't available
+pub const RSSI_UNAVAILABLE: i8 = 127;
+
+/// How much the true signal may move between two readings, in dB²
+const PROCESS_NOISE: f32 = 0.5;
+
+/// Variance of a single reading around the true signal, in dB²
+const MEASUREMENT_NOISE: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Near,
+    Medium,
+    Far,
+}
+
+impl Zone {
+    /// Zone of `rssi` without hysteresis, used for the first reading
+    pub fn of(rssi: f32) -> Self {
+        if rssi >= NEAR_DBM {
+            Zone::Near
+        } else if rssi >= FAR_DBM {
+            Zone::Medium
+        } else {
+            Zone::Far
+        }
+    }
+
+    /// Next zone towards `rssi`, if it is far enough past the boundary
+    fn step(self, rssi: f32) -> Self {
+        match self {
+            Zone::Near if rssi < NEAR_DBM - HYSTERESIS_DB => Zone::Medium,
+            Zone::Medium if rssi >= NEAR_DBM + HYSTERESIS_DB => Zone::Near,
+            Zone::Medium if rssi < FAR_DBM - HYSTERESIS_DB => Zone::Far,
+            Zone::Far if rssi >= FAR_DBM + HYSTERESIS_DB => Zone::Medium,
+            zone => zone,
+        }
+    }
+}
+
+/// One-dimensional Kalman filter over RSSI readings
+#[derive(Debug, Clone, Default)]
+pub struct RssiFilter {
+    /// Estimate and its variance, `None` until the first reading
+    state: Option<(f32, f32)>,
+}
+
+impl RssiFilter {
+    pub const fn new() -> Self {
+        RssiFilter { state: None }
+    }
+
+    /// Folds `rssi` into the estimate and returns it
+    pub fn update(&mut self, rssi: i8) -> f32 {
+        let measurement = rssi as f32;
+        let (estimate, variance) = match self.state {
+            None => (measurement, MEASUREMENT_NOISE),
+            Some((estimate, variance)) => {
+                let predicted = variance + PROCESS_NOISE;
+                let gain = predicted / (predicted + MEASUREMENT_NOISE);
+                (estimate + gain * (measurement - estimate), (1.0 - gain) * predicted)
+            }
+        };
+        self.state = Some((estimate, variance));
+        estimate
+    }
+
+    pub fn estimate(&self) -> Option<f32> {
+        self.state.map(|(estimate, _)| estimate)
+    }
+}
+
+/// Filter and zone of one connection
+#[derive(Debug, Clone, Default)]
+pub struct ProximityTracker {
+    filter: RssiFilter,
+    zone: Option<Zone>,
+}
+
+impl ProximityTracker {
+    pub const fn new() -> Self {
+        ProximityTracker { filter: RssiFilter::new(), zone: None }
+    }
+
+    pub fn zone(&self) -> Option<Zone> {
+        self.zone
+    }
+
+    /// Feeds one RSSI reading, returns the new zone when it changed
+    pub fn update(&mut self, rssi: i8) -> Option<Zone> {
+        if rssi == RSSI_UNAVAILABLE {
+            return None;
+        }
+        let estimate = self.filter.update(rssi);
+
+        let zone = match self.zone {
+            None => Zone::of(estimate),
+            // At most two steps, from one end to the other
+            Some(zone) => zone.step(estimate).step(estimate),
+        };
+        if self.zone == Some(zone) {
+            return None;
+        }
+        self.zone = Some(zone);
+        Some(zone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RSSI traces, one reading per 2 s poll of the connection
+    const WALK_AWAY: [i8; 40] = [
+        -48, -51, -47, -50, -53, -49, -46, -52, -50, -48, //
+        -55, -58, -54, -61, -63, -60, -66, -68, -65, -71, //
+        -73, -70, -76, -79, -75, -82, -84, -80, -86, -88, //
+        -85, -91, -87, -89, -92, -86, -90, -88, -93, -89,
+    ];
+    const COME_BACK: [i8; 30] = [
+        -90, -88, -93, -87, -91, -89, -86, -92, -88, -90, //
+        -72, -65, -61, -58, -55, -52, -54, -50, -49, -53, //
+        -47, -51, -48, -50, -52, -46, -49, -51, -48, -50,
+    ];
+    /// Phone on a desk right at the near boundary
+    const AT_BOUNDARY: [i8; 20] = [
+        -57, -63, -58, -66, -55, -62, -61, -54, -65, -59, //
+        -60, -64, -56, -62, -58, -67, -57, -61, -63, -55,
+    ];
+    /// Near the whole time, with one deep fade and a few unavailable readings
+    const FADE: [i8; 16] = [-52, -50, -55, -49, -95, -53, -51, 127, -54, -50, -92, -52, 127, -51, -53, -50];
+
+    /// Zone changes along a trace, in order, padded with `None`
+    fn changes(trace: &[i8]) -> [Option<Zone>; 4] {
+        let mut tracker = ProximityTracker::new();
+        let mut changes = [None; 4];
+        let mut len = 0;
+        for &rssi in trace {
+            if let Some(zone) = tracker.update(rssi) {
+                changes[len] = Some(zone);
+                len += 1;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn walking_away_goes_through_every_zone() {
+        assert_eq!(changes(&WALK_AWAY), [Some(Zone::Near), Some(Zone::Medium), Some(Zone::Far), None]);
+    }
+
+    #[test]
+    fn coming_back_returns_to_near() {
+        assert_eq!(changes(&COME_BACK), [Some(Zone::Far), Some(Zone::Medium), Some(Zone::Near), None]);
+    }
+
+    #[test]
+    fn noise_at_a_boundary_does_not_flap() {
+        // The raw readings cross -60 dBm on almost every sample
+        let raw_changes = AT_BOUNDARY.windows(2).filter(|w| Zone::of(w[0] as f32) != Zone::of(w[1] as f32)).count();
+        assert!(raw_changes > 10);
+
+        assert_eq!(changes(&AT_BOUNDARY), [Some(Zone::Near), None, None, None]);
+    }
+
+    #[test]
+    fn fades_and_unavailable_readings_are_ignored() {
+        assert_eq!(changes(&FADE), [Some(Zone::Near), None, None, None]);
+    }
+
+    #[test]
+    fn filter_converges_on_a_steady_signal() {
+        let mut filter = RssiFilter::new();
+        assert_eq!(filter.estimate(), None);
+        filter.update(-40);
+        for _ in 0..50 {
+            filter.update(-70);
+        }
+        let estimate = filter.estimate().unwrap();
+        assert!((estimate + 70.0).abs() < 1.0, "{}", estimate);
+    }
+
+    #[test]
+    fn hysteresis_holds_the_zone_just_past_a_boundary() {
+        assert_eq!(Zone::Near.step(NEAR_DBM - 1.0), Zone::Near);
+        assert_eq!(Zone::Near.step(NEAR_DBM - HYSTERESIS_DB - 1.0), Zone::Medium);
+        assert_eq!(Zone::Medium.step(NEAR_DBM + 1.0), Zone::Medium);
+        assert_eq!(Zone::Far.step(FAR_DBM + 1.0), Zone::Far);
+        assert_eq!(Zone::Far.step(FAR_DBM + HYSTERESIS_DB), Zone::Medium);
+    }
+}
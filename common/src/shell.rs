@@ -0,0 +1,294 @@
+//! Line oriented debug shell, shared by the Nordic UART Service and the serial console.
+//!
+//! One command per line, words separated by spaces, case insensitive:
+//!
+//! ```text
+//!  help               lists the commands
+//!  status             pet stats and status
+//!  feed               feeds the pet
+//!  bonds list         bonded phones
+//!  storage stats      items kept in flash
+//!  log level [LEVEL]  shows or sets the log level: off, error, warn, info, debug, trace
+//!  reboot             restarts the device
+//! ```
+//!
+//! Bytes arrive in arbitrary pieces (one GATT write, a few UART bytes), so
+//! they go through a [`LineBuffer`] first. The serial console is the UART the
+//! logs go to, so its answers are mixed with log lines unless they are off.
+
+/// Longest line accepted, longer ones are rejected whole
+pub const LINE_MAX: usize = 64;
+
+/// Answer to `help`
+pub const HELP: &str = "commands: status, feed, bonds list, storage stats, log level [off|error|warn|info|debug|trace], reboot\r\n\
+    on the serial port logs share the line, 'log level off' quiets them";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellCommand {
+    Help,
+    Status,
+    Feed,
+    BondsList,
+    StorageStats,
+    /// `None` shows the current level
+    LogLevel(Option<LogLevel>),
+    Reboot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    TooManyArguments,
+}
+
+impl ShellError {
+    /// Text sent back to the terminal
+    pub fn message(self) -> &'static str {
+        match self {
+            ShellError::Empty => "empty line",
+            ShellError::UnknownCommand => "unknown command, try help",
+            ShellError::MissingArgument => "missing argument",
+            ShellError::InvalidArgument => "invalid argument",
+            ShellError::TooManyArguments => "too many arguments",
+        }
+    }
+}
+
+impl ShellCommand {
+    pub fn parse(line: &str) -> Result<Self, ShellError> {
+        let mut words = line.split_ascii_whitespace();
+        let command = words.next().ok_or(ShellError::Empty)?;
+        let is = |word: &str, expected: &str| word.eq_ignore_ascii_case(expected);
+
+        let parsed = if is(command, "help") {
+            ShellCommand::Help
+        } else if is(command, "status") {
+            ShellCommand::Status
+        } else if is(command, "feed") {
+            ShellCommand::Feed
+        } else if is(command, "reboot") {
+            ShellCommand::Reboot
+        } else if is(command, "bonds") {
+            match words.next() {
+                Some(sub) if is(sub, "list") => ShellCommand::BondsList,
+                Some(_) => return Err(ShellError::InvalidArgument),
+                None => return Err(ShellError::MissingArgument),
+            }
+        } else if is(command, "storage") {
+            match words.next() {
+                Some(sub) if is(sub, "stats") => ShellCommand::StorageStats,
+                Some(_) => return Err(ShellError::InvalidArgument),
+                None => return Err(ShellError::MissingArgument),
+            }
+        } else if is(command, "log") {
+            match words.next() {
+                Some(sub) if is(sub, "level") => match words.next() {
+                    Some(level) => ShellCommand::LogLevel(Some(LogLevel::from_name(level).ok_or(ShellError::InvalidArgument)?)),
+                    None => ShellCommand::LogLevel(None),
+                },
+                Some(_) => return Err(ShellError::InvalidArgument),
+                None => return Err(ShellError::MissingArgument),
+            }
+        } else {
+            return Err(ShellError::UnknownCommand);
+        };
+
+        match words.next() {
+            Some(_) => Err(ShellError::TooManyArguments),
+            None => Ok(parsed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineError {
+    /// The line was longer than the buffer and was dropped
+    TooLong,
+    InvalidUtf8,
+}
+
+/// Collects bytes until a line ending.
+///
+/// `\r`, `\n` and `\r\n` all end a line and empty lines are skipped;
+/// backspace and delete remove the last byte, as sent by serial terminals.
+#[derive(Debug, Clone)]
+pub struct LineBuffer<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        LineBuffer { buffer: [0; N], len: 0, overflow: false }
+    }
+
+    /// Adds one byte, returns the line it completes
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, LineError>> {
+        match byte {
+            b'\r' | b'\n' => self.finish(),
+            0x08 | 0x7F => {
+                self.len = self.len.saturating_sub(1);
+                None
+            }
+            _ if self.len == N => {
+                self.overflow = true;
+                None
+            }
+            _ => {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+                None
+            }
+        }
+    }
+
+    /// Ends the pending line as if a line ending arrived, `None` when nothing is pending
+    pub fn finish(&mut self) -> Option<Result<&str, LineError>> {
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(LineError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(core::str::from_utf8(&self.buffer[..len]).map_err(|_| LineError::InvalidUtf8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(ShellCommand::parse("help"), Ok(ShellCommand::Help));
+        assert_eq!(ShellCommand::parse("status"), Ok(ShellCommand::Status));
+        assert_eq!(ShellCommand::parse("feed"), Ok(ShellCommand::Feed));
+        assert_eq!(ShellCommand::parse("bonds list"), Ok(ShellCommand::BondsList));
+        assert_eq!(ShellCommand::parse("storage stats"), Ok(ShellCommand::StorageStats));
+        assert_eq!(ShellCommand::parse("log level"), Ok(ShellCommand::LogLevel(None)));
+        assert_eq!(ShellCommand::parse("log level debug"), Ok(ShellCommand::LogLevel(Some(LogLevel::Debug))));
+        assert_eq!(ShellCommand::parse("reboot"), Ok(ShellCommand::Reboot));
+    }
+
+    #[test]
+    fn ignores_case_and_extra_spaces() {
+        assert_eq!(ShellCommand::parse("  Bonds   LIST \t"), Ok(ShellCommand::BondsList));
+        assert_eq!(ShellCommand::parse("LOG level Warn"), Ok(ShellCommand::LogLevel(Some(LogLevel::Warn))));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(ShellCommand::parse("   "), Err(ShellError::Empty));
+        assert_eq!(ShellCommand::parse("dance"), Err(ShellError::UnknownCommand));
+        assert_eq!(ShellCommand::parse("bonds"), Err(ShellError::MissingArgument));
+        assert_eq!(ShellCommand::parse("bonds clear"), Err(ShellError::InvalidArgument));
+        assert_eq!(ShellCommand::parse("log level loud"), Err(ShellError::InvalidArgument));
+        assert_eq!(ShellCommand::parse("feed twice"), Err(ShellError::TooManyArguments));
+        assert_eq!(ShellCommand::parse("log level info now"), Err(ShellError::TooManyArguments));
+    }
+
+    #[test]
+    fn log_level_names_roundtrip() {
+        for level in LogLevel::ALL {
+            assert_eq!(LogLevel::from_name(level.name()), Some(level));
+        }
+    }
+
+    /// Pushes `input` and checks every completed line against `expected`, in order
+    fn assert_lines<const N: usize>(buffer: &mut LineBuffer<N>, input: &[u8], expected: &[Result<&str, LineError>]) {
+        let mut expected = expected.iter();
+        for &byte in input {
+            if let Some(line) = buffer.push(byte) {
+                assert_eq!(Some(&line), expected.next());
+            }
+        }
+        assert_eq!(expected.next(), None);
+    }
+
+    #[test]
+    fn splits_lines_on_any_line_ending() {
+        let mut buffer = LineBuffer::<LINE_MAX>::new();
+        assert_lines(&mut buffer, b"status\r\nfeed\n\nreboot\r", &[Ok("status"), Ok("feed"), Ok("reboot")]);
+    }
+
+    #[test]
+    fn lines_can_arrive_in_pieces() {
+        let mut buffer = LineBuffer::<LINE_MAX>::new();
+        assert_lines(&mut buffer, b"sta", &[]);
+        assert_lines(&mut buffer, b"tus\nfe", &[Ok("status")]);
+        assert_lines(&mut buffer, b"ed\n", &[Ok("feed")]);
+    }
+
+    #[test]
+    fn backspace_edits_the_line() {
+        let mut buffer = LineBuffer::<LINE_MAX>::new();
+        assert_lines(&mut buffer, b"fex\x08ed\x7F\x7Fed\r", &[Ok("feed")]);
+    }
+
+    #[test]
+    fn long_lines_are_dropped_whole() {
+        let mut buffer = LineBuffer::<8>::new();
+        assert_lines(&mut buffer, b"storage stats\nfeed\n", &[Err(LineError::TooLong), Ok("feed")]);
+    }
+
+    #[test]
+    fn finish_flushes_a_line_without_ending() {
+        let mut buffer = LineBuffer::<LINE_MAX>::new();
+        assert_eq!(buffer.finish(), None);
+        for &byte in b"help" {
+            assert_eq!(buffer.push(byte), None);
+        }
+        assert_eq!(buffer.finish(), Some(Ok("help")));
+        assert_eq!(buffer.finish(), None);
+
+        buffer.push(0xFF);
+        assert_eq!(buffer.finish(), Some(Err(LineError::InvalidUtf8)));
+    }
+}
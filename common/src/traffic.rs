@@ -0,0 +1,147 @@
+//! Traffic level of a connection, sampled in fixed windows.
+//!
+//! The device picks its connection parameters from the level: long intervals
+//! with slave latency while idle, short ones while data flows. Busier levels
+//! are entered on the first busy window; falling back to a quieter one takes
+//! [`SETTLE_WINDOWS`] quiet windows in a row, so short pauses in a transfer
+//! don't make the parameters flap.
+
+/// Events per window from which the link counts as busy
+pub const BUSY_EVENTS: u32 = 10;
+
+/// Consecutive quieter windows needed before the level drops
+pub const SETTLE_WINDOWS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrafficLevel {
+    /// Nothing but keep-alives
+    Idle,
+    /// Occasional reads, writes and notifications
+    Normal,
+    /// A bulk transfer or a burst of GATT traffic
+    Busy,
+}
+
+impl TrafficLevel {
+    /// Level a single window shows on its own
+    pub fn of(sample: TrafficSample) -> Self {
+        if sample.transfer || sample.events >= BUSY_EVENTS {
+            TrafficLevel::Busy
+        } else if sample.events > 0 {
+            TrafficLevel::Normal
+        } else {
+            TrafficLevel::Idle
+        }
+    }
+}
+
+/// What happened on the link during one window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficSample {
+    pub events: u32,
+    /// A bulk transfer was running at some point of the window
+    pub transfer: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficMonitor {
+    level: TrafficLevel,
+    /// Quieter windows seen in a row
+    quiet_windows: u8,
+}
+
+impl Default for TrafficMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrafficMonitor {
+    /// New connections start at [`TrafficLevel::Normal`]: discovery and subscriptions follow right away
+    pub const fn new() -> Self {
+        TrafficMonitor { level: TrafficLevel::Normal, quiet_windows: 0 }
+    }
+
+    pub fn level(&self) -> TrafficLevel {
+        self.level
+    }
+
+    /// Feeds one window, returns the new level when it changed
+    pub fn update(&mut self, sample: TrafficSample) -> Option<TrafficLevel> {
+        let observed = TrafficLevel::of(sample);
+        if observed >= self.level {
+            self.quiet_windows = 0;
+            if observed == self.level {
+                return None;
+            }
+            self.level = observed;
+            return Some(observed);
+        }
+
+        self.quiet_windows += 1;
+        if self.quiet_windows < SETTLE_WINDOWS {
+            return None;
+        }
+        // Step down one level at a time, a busy link rarely goes silent at once
+        self.quiet_windows = 0;
+        self.level = match self.level {
+            TrafficLevel::Busy => TrafficLevel::Normal,
+            _ => TrafficLevel::Idle,
+        };
+        Some(self.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(events: u32) -> TrafficSample {
+        TrafficSample { events, transfer: false }
+    }
+
+    #[test]
+    fn busier_levels_are_entered_at_once() {
+        let mut monitor = TrafficMonitor::new();
+        assert_eq!(monitor.update(events(3)), None);
+        assert_eq!(monitor.update(events(BUSY_EVENTS)), Some(TrafficLevel::Busy));
+
+        let mut monitor = TrafficMonitor::new();
+        assert_eq!(monitor.update(TrafficSample { events: 0, transfer: true }), Some(TrafficLevel::Busy));
+    }
+
+    #[test]
+    fn quieter_levels_wait_for_settled_windows() {
+        let mut monitor = TrafficMonitor::new();
+        for _ in 1..SETTLE_WINDOWS {
+            assert_eq!(monitor.update(events(0)), None);
+        }
+        assert_eq!(monitor.update(events(0)), Some(TrafficLevel::Idle));
+        assert_eq!(monitor.update(events(0)), None);
+    }
+
+    #[test]
+    fn short_pauses_keep_the_level() {
+        let mut monitor = TrafficMonitor::new();
+        monitor.update(events(20));
+
+        // A pause shorter than the settle time, then the transfer goes on
+        let trace = [0, 0, 15, 0, 0, 12, 0];
+        for sample in trace {
+            assert_eq!(monitor.update(events(sample)), None);
+        }
+        assert_eq!(monitor.level(), TrafficLevel::Busy);
+    }
+
+    #[test]
+    fn levels_step_down_one_at_a_time() {
+        let mut monitor = TrafficMonitor::new();
+        monitor.update(events(20));
+
+        let changes: [Option<TrafficLevel>; 6] = core::array::from_fn(|_| monitor.update(events(0)));
+        assert_eq!(
+            changes,
+            [None, None, Some(TrafficLevel::Normal), None, None, Some(TrafficLevel::Idle)]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Payload two pets exchange when one visits the other.
+//!
+//! The visitor connects as a central, writes its own visit to the visit
+//! characteristic of the host pet and reads the host's visit back. Both
+//! directions carry a [`MessageType::Visit`](crate::protocol::MessageType::Visit)
+//! frame with this payload:
+//!
+//! ```text
+//!  offset  size  field
+//!  0       1     species
+//!  1       1     life stage
+//!  2       1     mood (pet status code)
+//!  3       1     happiness bucket, 0..=3
+//!  4       4     pet id, little endian
+//!  8       1     name length, 1..=16
+//!  9       len   name, UTF-8
+//! ```
+//!
+//! The pet id stays the same for the life of the device, unlike its
+//! advertising address, so friendships are remembered by it.
+
+use crate::beacon::{HAPPINESS_BUCKETS, PetBeacon};
+use crate::pet::{LifeStage, Species, TamagotchiStatus};
+
+/// Custom pet service, shared with the phone protocol
+pub const PET_SERVICE_UUID: u128 = 0x12345678_1234_5678_1234_56789abcdef0;
+
+/// Characteristic a visitor writes its visit to and reads the host's from
+pub const VISIT_CHARACTERISTIC_UUID: u128 = 0x12345678_1234_5678_1234_56789abcdef6;
+
+/// Longest pet name carried by a visit, same as a rename command
+pub const VISIT_NAME_MAX: usize = 16;
+
+const VISIT_HEADER_LEN: usize = 9;
+
+/// Largest encoded visit payload
+pub const VISIT_MAX_LEN: usize = VISIT_HEADER_LEN + VISIT_NAME_MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Visit {
+    /// Identifies the pet across visits
+    pub id: u32,
+    pub pet: PetBeacon,
+    name: [u8; VISIT_NAME_MAX],
+    name_len: u8,
+}
+
+impl Visit {
+    /// Builds a visit, cutting `name` on a character boundary if it's too long
+    pub fn new(id: u32, pet: PetBeacon, name: &str) -> Self {
+        let mut end = name.len().min(VISIT_NAME_MAX);
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buffer = [0u8; VISIT_NAME_MAX];
+        buffer[..end].copy_from_slice(&name.as_bytes()[..end]);
+
+        Visit {
+            id,
+            pet,
+            name: buffer,
+            name_len: end as u8,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        // Only built from validated UTF-8
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+
+    /// Encodes the payload, returns the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; VISIT_MAX_LEN], usize) {
+        let mut buffer = [0u8; VISIT_MAX_LEN];
+        let len = VISIT_HEADER_LEN + self.name_len as usize;
+        buffer[0] = self.pet.species as u8;
+        buffer[1] = self.pet.stage as u8;
+        buffer[2] = self.pet.mood as u8;
+        buffer[3] = self.pet.happiness_bucket;
+        buffer[4..8].copy_from_slice(&self.id.to_le_bytes());
+        buffer[8] = self.name_len;
+        buffer[VISIT_HEADER_LEN..len].copy_from_slice(&self.name[..self.name_len as usize]);
+        (buffer, len)
+    }
+
+    /// Parses a payload written by [`Visit::to_bytes`], rejecting anything malformed
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let (header, name) = data.split_at_checked(VISIT_HEADER_LEN)?;
+        let name_len = header[8] as usize;
+        if name_len == 0 || name_len > VISIT_NAME_MAX || name.len() != name_len || header[3] >= HAPPINESS_BUCKETS {
+            return None;
+        }
+        let name = core::str::from_utf8(name).ok()?;
+
+        let pet = PetBeacon {
+            species: Species::from_u8(header[0])?,
+            stage: LifeStage::from_u8(header[1])?,
+            mood: TamagotchiStatus::from_u8(header[2])?,
+            happiness_bucket: header[3],
+        };
+        let id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Some(Visit::new(id, pet, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pet() -> PetBeacon {
+        PetBeacon {
+            species: Species::Dino,
+            stage: LifeStage::Teen,
+            mood: TamagotchiStatus::Playing,
+            happiness_bucket: 3,
+        }
+    }
+
+    #[test]
+    fn byte_layout_and_roundtrip() {
+        let visit = Visit::new(0x0403_0201, pet(), "Rex");
+        let (bytes, len) = visit.to_bytes();
+
+        assert_eq!(
+            &bytes[..len],
+            &[0x02, 0x03, 0x04, 0x03, 0x01, 0x02, 0x03, 0x04, 0x03, b'R', b'e', b'x']
+        );
+        assert_eq!(Visit::from_bytes(&bytes[..len]), Some(visit));
+        assert_eq!(visit.name(), "Rex");
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_character_boundary() {
+        let visit = Visit::new(7, pet(), "aaaaaaaaaaaaaaaéé");
+
+        assert_eq!(visit.name(), "aaaaaaaaaaaaaaa");
+        let (bytes, len) = visit.to_bytes();
+        assert!(len <= VISIT_MAX_LEN);
+        assert_eq!(Visit::from_bytes(&bytes[..len]), Some(visit));
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        let (bytes, len) = Visit::new(7, pet(), "Rex").to_bytes();
+
+        assert_eq!(Visit::from_bytes(&bytes[..len - 1]), None);
+        assert_eq!(Visit::from_bytes(&bytes[..8]), None);
+
+        let mut bad_species = bytes;
+        bad_species[0] = 9;
+        assert_eq!(Visit::from_bytes(&bad_species[..len]), None);
+
+        let mut bad_bucket = bytes;
+        bad_bucket[3] = HAPPINESS_BUCKETS;
+        assert_eq!(Visit::from_bytes(&bad_bucket[..len]), None);
+
+        let mut bad_utf8 = bytes;
+        bad_utf8[9] = 0xFF;
+        assert_eq!(Visit::from_bytes(&bad_utf8[..len]), None);
+
+        let empty_name = [0x02, 0x03, 0x04, 0x03, 0x07, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(Visit::from_bytes(&empty_name), None);
+    }
+}
@@ -8,31 +8,42 @@
 #![deny(clippy::large_stack_frames)]
 
 use embassy_embedded_hal::adapter::BlockingAsync;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use esp_hal::clock::CpuClock;
-use esp_radio::ble;
-use esp_radio::ble::controller::BleConnector;
 use esp_storage::FlashStorage;
+use esp32_tamagotchi::controller::ble_controller::BluetoothController;
 use esp32_tamagotchi::factory::factory::Factory;
+use esp32_tamagotchi::peripherals::bluetooth::BluetoothPeripherals;
+#[cfg(feature = "battery")]
+use esp32_tamagotchi::peripherals::battery::BatteryPeripherals;
+use esp32_tamagotchi::peripherals::button::ButtonPeripherals;
 use esp32_tamagotchi::peripherals::timer::TimerPeripherals;
+use esp32_tamagotchi::peripherals::uart::UartPeripherals;
+use esp32_tamagotchi::service::ble::console_service::ConsoleService;
+use esp32_tamagotchi::service::ble::ota_service::OtaService;
+#[cfg(feature = "battery")]
+use esp32_tamagotchi::service::ble::battery_service::BatteryService;
+use esp32_tamagotchi::service::ble::address_service::AddressService;
 use esp32_tamagotchi::service::ble::advertise_service::AdvertiseService;
-use esp32_tamagotchi::service::ble::gatt_service::GattService;
-use esp32_tamagotchi::service::ble::storage_service::get_first_bonded;
-// Novos imports para notificações
-use esp32_tamagotchi::service::ble::notification_characteristics::{ NotificationCharacteristics, TamagotchiStatus };
-use esp32_tamagotchi::service::ble::notification_service::NotificationService;
-use log::info;
-use trouble_host::Address;
-use trouble_host::prelude::{ BdAddr, EventHandler, ExternalController };
+use esp32_tamagotchi::service::ble::storage_service::{load_language, load_pet, store_language, store_pet};
+use esp32_tamagotchi::service::ble::pairing_service::ButtonPairingUi;
+use esp32_tamagotchi::service::ble::outbound_service::{Delivery, Priority};
+use esp32_tamagotchi::service::ble::notification_characteristics::TamagotchiStatus;
+use esp32_tamagotchi::service::ble::notification_service::{Notification, NotificationService};
+use esp32_tamagotchi::service::ble::command_service::{COMMAND_CHANNEL, CommandResult};
+use esp32_tamagotchi::service::ble::visit_service::VISIT_CHANNEL;
+use esp32_tamagotchi::service::ble::play_date_service::PLAY_DATE_CHANNEL;
+use esp32_tamagotchi::service::ble::proximity_service::PROXIMITY_CHANNEL;
+use esp32_tamagotchi::pet::pet_state::PetState;
+use log::{error, info};
 use core::cell::RefCell;
-use heapless::Deque;
-use trouble_host::prelude::*;
-use embassy_futures::join::join;
+use embassy_futures::join::{join4, join5};
+use tamagotchi_common::shell::{LINE_MAX, LineBuffer};
 
 #[panic_handler]
-fn panic(_: &core::panic::PanicInfo) -> ! {
-    loop {
-    }
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Reinicia em vez de travar até alguém desligar a placa; o pet volta do último save
+    esp_println::println!("{}", info);
+    esp_hal::system::software_reset()
 }
 
 extern crate alloc;
@@ -41,16 +52,28 @@ extern crate alloc;
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
+/// Intervalo entre as procuras por outros pets
+const VISIT_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(300);
+const SCAN_DURATION: embassy_time::Duration = embassy_time::Duration::from_secs(10);
+
+/// Tempo rodando sem travar até uma imagem nova ser confirmada
+const CONFIRM_AFTER: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
+/// Espera depois de um erro de leitura da UART do console, dobrando a cada erro seguido
+const UART_RETRY_MIN: embassy_time::Duration = embassy_time::Duration::from_millis(10);
+const UART_RETRY_MAX: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+/// Intervalo entre os saves do pet, o que se perde num reinício inesperado
+const SAVE_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(300);
+
+/// Intervalo entre as leituras da bateria
+#[cfg(feature = "battery")]
+const BATTERY_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
 #[allow(
     clippy::large_stack_frames,
     reason = "it's not unusual to allocate larger buffers etc. in main"
 )]
-const CONNECTIONS_MAX: usize = 1;
-const DESCRIPTORS_MAX: usize = 3;
-const L2CAP_CHANNELS_MAX: usize = 4;
-const BLE_STACK_RESOURCES_MAX: usize = 20;
-const ATTRIBUTE_TABLE_SIZE: usize =  20; // Tamanho suficiente para o NotificationService
-
 #[esp_rtos::main]
 async fn main(_spawner: embassy_executor::Spawner) {
     // generator version: 1.1.0
@@ -67,142 +90,256 @@ async fn main(_spawner: embassy_executor::Spawner) {
     let timg0 = Factory::create_timer_group0(timer_peripherals);
     esp_rtos::start(timg0.timer0);
 
-    // Init RNG
-    let _trng_source = esp_hal::rng::TrngSource::new(peripherals.RNG, peripherals.ADC1);
+    // Imagem nova que reiniciou sem se confirmar volta para a anterior
+    OtaService::check_boot();
+
+    // Botão BOOT confirma o pareamento (comparação numérica)
+    let button = Factory::create_button(ButtonPeripherals::new(peripherals.GPIO0));
+    let pairing_ui = ButtonPairingUi::new(button);
+
+    // Console serial na mesma porta dos logs
+    let uart_peripherals = UartPeripherals::new(peripherals.UART0, peripherals.GPIO1, peripherals.GPIO3);
+    let mut uart = Factory::create_console_uart(uart_peripherals);
+
+    // Init RNG. O TRNG só precisa do ADC1 para semear a pilha BLE
+    let _trng_source = esp_hal::rng::TrngSource::new(peripherals.RNG, peripherals.ADC1.reborrow());
     let mut trng = esp_hal::rng::Trng::try_new().unwrap();
 
     // Init Flash and Storage
     let flash = BlockingAsync::new(FlashStorage::new(peripherals.FLASH));
-    let mut storage = esp32_tamagotchi::service::ble::storage_service::init_storage(flash);
-
-    // Init BLE
-    let radio_init = esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller");
-    let device_ble = peripherals.BT;
-    let ble_config = ble::Config::default();
-    let ble = match BleConnector::new(&radio_init, device_ble, ble_config) {
-        Ok(ble) => ble,
-        Err(e) => panic!("Failed to initialize BLE: {:?}", e),
-    };
-    let controller: ExternalController<BleConnector<'_>, 20> = ExternalController::new(ble);
-    let address = Address::random([0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]);
-    info!("Our address = {:?}", address);
-
-    info!("Set BLE Config");
-    let mut resources: trouble_host::HostResources<
-        trouble_host::prelude::DefaultPacketPool,
-        CONNECTIONS_MAX,
-        L2CAP_CHANNELS_MAX
-    > = trouble_host::HostResources::new();
-    let stack = trouble_host
-        ::new(controller, &mut resources)
-        .set_random_address(address)
-        .set_random_generator_seed(&mut trng);
-    //let stack = &stack;
-
-    info!("Loading bonded devices from storage");
-    let mut bond_stored = false;
-    let bond = get_first_bonded(&mut storage).await;
-    match &bond {
-        Ok(Some(bond2)) => {
-            info!("Found bonded device: {:?}", bond2.identity.bd_addr);
-            let _ = stack.add_bond_information(bond2.clone());
-            bond_stored = true;
-        }
-        Ok(None) => {
-            info!("No bonded devices found in storage");
-        }
+    let mut storage = esp32_tamagotchi::service::ble::storage_service::init_storage(flash).await;
+
+    // Idioma das mensagens escolhido pelo telefone, português até ele escolher outro
+    let language = match load_language(&mut storage).await {
+        Ok(language) => language.unwrap_or_default(),
         Err(e) => {
-            info!("Error retrieving bonded devices: {:?}. Continuing without bonds.", e);
+            info!("Error loading language: {:?}. Using the default.", e);
+            Default::default()
         }
+    };
+    info!("Language = {:?}", language);
+
+    // O pet continua de onde parou antes do último reinício
+    let mut state = PetState::new("Tamagotchi");
+    match load_pet(&mut storage, &mut state).await {
+        Ok(true) => info!("Pet restored, age {}", state.age),
+        Ok(false) => info!("No saved pet, a new one hatches"),
+        Err(e) => info!("Error loading the pet: {:?}. Starting a new one.", e),
     }
+    state.language = language;
+    state.id = AddressService::pet_id();
 
-    info!("Init Host");
-    let host = stack.build();
+    // Init BLE: rádio, endereço, bonds e servidor GATT ficam com o controller
+    let bluetooth_peripherals = BluetoothPeripherals::new(peripherals.BT);
+    let controller = BluetoothController::new("Tamagotchi", bluetooth_peripherals, storage, &mut trng, pairing_ui).await;
+    let controller = &controller;
 
-    info!("Init peripheral");
-    let mut peripheral = host.peripheral;
-    info!("Init runner");
-    let mut runner = host.runner;
+    // Com a pilha semeada o ADC1 passa para a leitura da bateria; o Rng continua funcionando
+    #[cfg(feature = "battery")]
+    let mut battery = {
+        drop(trng);
+        drop(_trng_source);
+        Factory::create_battery_sensor(BatteryPeripherals::new(peripherals.ADC1, peripherals.GPIO35))
+    };
+
+    let pet = RefCell::new(state);
+
+    // Task do pet: aplica comandos do telefone e publica mudanças de status para todas as conexões
+    let pet_task = async {
+        let mut last_status = pet.borrow().status();
+        let mut last_beacon = pet.borrow().beacon();
+        let mut last_language = pet.borrow().language;
 
-    info!("Starting advertising loop with notifications support...");
-    let _ = join(runner.run(), async {
         loop {
-            let mut advertise_service = AdvertiseService::new("Tamagotchi").await;
-
-            // Criar tabela de atributos com tamanho adequado
-            let mut attribute_table: AttributeTable<
-                '_,
-                CriticalSectionRawMutex,
-                ATTRIBUTE_TABLE_SIZE
-            > = AttributeTable::new();
-
-            // Criar e registrar serviço de notificações
-            let notification_service = NotificationCharacteristics::new(&mut attribute_table);
-
-            let mut server = AttributeServer::new(attribute_table);
-
-            info!("Advertising, waiting for connection...");
-            let conn = advertise_service.advertise::<
-                ExternalController<_, BLE_STACK_RESOURCES_MAX>,
-                ATTRIBUTE_TABLE_SIZE,
-                DESCRIPTORS_MAX,
-                CONNECTIONS_MAX
-            >(&mut peripheral, &mut server).await;
-
-            let raw: &Connection<'_, DefaultPacketPool> = conn.raw();
-            raw.set_bondable(!bond_stored).unwrap();
-
-            // Enviar notificação de boas-vindas
-            info!("Sending welcome notification...");
-            let _ = NotificationService::send_message(
-                &notification_service,
-                &conn,
-                b"Conectado!"
-            ).await;
-
-            let gatt_service = GattService::new();
-            let gatt_task = gatt_service.handle_gatt_events(&mut storage, &conn, &mut bond_stored);
-
-            // Keep connection alive
-            let keep_alive_task =
-                esp32_tamagotchi::service::ble::advertise_service::keep_connection_alive(
-                    &conn,
-                    &stack
-                );
-
-            // Task de notificações periódicas
-            let notification_task = async {
-                let mut counter = 0u32;
-
-                loop {
-                    embassy_time::Timer::after(embassy_time::Duration::from_secs(10)).await;
-                    // A cada 20 segundos (contador par), mudar status
-                    if counter % 2 == 0 {
-                        let status_index = (counter / 2) % 6;
-                        let status = match status_index {
-                            0 => TamagotchiStatus::Happy,
-                            1 => TamagotchiStatus::Hungry,
-                            2 => TamagotchiStatus::Tired,
-                            3 => TamagotchiStatus::Sick,
-                            4 => TamagotchiStatus::Playing,
-                            _ => TamagotchiStatus::Sleeping,
+            let tick = embassy_time::Timer::after(embassy_time::Duration::from_secs(10));
+            let company = embassy_futures::select::select3(
+                VISIT_CHANNEL.receive(),
+                PLAY_DATE_CHANNEL.receive(),
+                PROXIMITY_CHANNEL.receive(),
+            );
+            match embassy_futures::select::select3(COMMAND_CHANNEL.receive(), company, tick).await {
+                embassy_futures::select::Either3::First(request) => {
+                    let result = {
+                        let mut pet = pet.borrow_mut();
+                        let status = match &request.command {
+                            Ok(command) => pet.apply(command),
+                            Err(status) => *status,
                         };
+                        info!("[pet_task] Command {:#04x} -> {:?}", request.opcode, status);
+                        CommandResult::new(&request, status, pet.stats())
+                    };
+                    NotificationService::publish(Notification::CommandResult(result));
+                }
+                embassy_futures::select::Either3::Second(embassy_futures::select::Either3::First(visit)) => {
+                    pet.borrow_mut().receive_visit(&visit);
+                }
+                embassy_futures::select::Either3::Second(embassy_futures::select::Either3::Second(outcome)) => {
+                    pet.borrow_mut().receive_play_date(&outcome);
+                }
+                // Dono se afastando ou voltando
+                embassy_futures::select::Either3::Second(embassy_futures::select::Either3::Third(zone)) => {
+                    pet.borrow_mut().owner_moved(zone);
+                }
+                embassy_futures::select::Either3::Third(_) => {
+                    pet.borrow_mut().tick();
+                }
+            }
 
-                        info!("[notification_task] Sending status: {:?}", status);
-                        let _ = NotificationService::send_tamagotchi_status(
-                            &notification_service,
-                            &conn,
-                            status
-                        ).await;
-                    }
-                    counter += 1;
+            let status = pet.borrow().status();
+            if status != last_status {
+                info!("[pet_task] Publishing status: {:?}", status);
+                NotificationService::publish(Notification::Status(status));
+
+                // Fome e doença precisam chegar ao telefone, mesmo que ele esteja desconectado agora
+                let (priority, delivery) = match status {
+                    TamagotchiStatus::Hungry | TamagotchiStatus::Sick => (Priority::High, Delivery::Indicate),
+                    _ => (Priority::Normal, Delivery::Notify),
+                };
+                let message = status.as_message(pet.borrow().language);
+                if let Err(e) = controller.send(message, priority, delivery) {
+                    info!("[pet_task] Status message not queued: {:?}", e);
+                }
+                last_status = status;
+            }
+
+            // O idioma sobrevive a reinícios
+            let language = pet.borrow().language;
+            if language != last_language {
+                if let Err(e) = store_language(&mut *controller.storage().lock().await, language).await {
+                    error!("[pet_task] Failed to store language: {:?}", e);
+                }
+                last_language = language;
+            }
+
+            // Mantém o anúncio em dia para quem está procurando o pet
+            let beacon = pet.borrow().beacon();
+            if beacon != last_beacon {
+                AdvertiseService::publish_beacon(beacon);
+                last_beacon = beacon;
+            }
+        }
+    };
+
+    // De tempos em tempos procura outros pets, visita o mais próximo e brinca com ele
+    let visit_task = async {
+        loop {
+            embassy_time::Timer::after(VISIT_INTERVAL).await;
+            controller.scan(SCAN_DURATION).await;
+            for nearby in controller.nearby_pets() {
+                info!("[visit_task] Nearby: {:?} {:?} ({} dBm)", nearby.beacon.species, nearby.beacon.mood, nearby.rssi);
+            }
+
+            let Some(nearby) = controller.closest_pet() else {
+                continue;
+            };
+            let ours = pet.borrow().visit();
+            let theirs = match controller.visit(nearby.address, &ours).await {
+                Ok(theirs) => theirs,
+                Err(e) => {
+                    info!("[visit_task] Visit failed: {:?}", e);
+                    continue;
+                }
+            };
+            pet.borrow_mut().receive_visit(&theirs);
+
+            // Só brinca com quem recebeu a visita; a amizade fica guardada pelo id do outro pet
+            match controller.play_date(nearby.address, &ours, &theirs).await {
+                Ok(outcome) => pet.borrow_mut().receive_play_date(&outcome),
+                Err(e) => info!("[visit_task] Play date failed: {:?}", e),
+            }
+        }
+    };
+
+    // Registra as conexões que chegam e saem
+    let events_task = async {
+        let events = controller.events();
+        loop {
+            info!("[ble] {:?}", events.receive().await);
+        }
+    };
+
+    // Console serial: os mesmos comandos do console BLE, digitados no monitor do espflash
+    let console_task = async {
+        let mut line = LineBuffer::<LINE_MAX>::new();
+        let mut bytes = [0u8; 16];
+        let mut retry = UART_RETRY_MIN;
+        loop {
+            let len = match uart.read_async(&mut bytes).await {
+                Ok(len) => {
+                    retry = UART_RETRY_MIN;
+                    len
+                }
+                Err(e) => {
+                    // Um erro que se repete (ruído na linha, overflow) não pode prender o executor
+                    error!("[console] UART read failed: {:?}", e);
+                    embassy_time::Timer::after(retry).await;
+                    retry = (retry * 2).min(UART_RETRY_MAX);
+                    continue;
                 }
             };
+            for &byte in &bytes[..len] {
+                let Some(result) = line.push(byte) else {
+                    continue;
+                };
+                let reply = ConsoleService::handle(result, &pet, controller.storage()).await;
+                for mut text in [reply.text.as_bytes(), b"\r\n".as_slice()] {
+                    // A escrita pode aceitar só parte dos bytes
+                    while !text.is_empty() {
+                        match uart.write_async(text).await {
+                            Ok(written) => text = &text[written..],
+                            Err(e) => {
+                                error!("[console] UART write failed: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                if reply.reboot {
+                    ConsoleService::reboot().await;
+                }
+            }
+        }
+    };
+
+    // Uma imagem recebida por BLE só vale depois de um minuto funcionando
+    let confirm_task = async {
+        embassy_time::Timer::after(CONFIRM_AFTER).await;
+        OtaService::confirm();
+    };
 
-            // Executar todas as tasks em paralelo
-            embassy_futures::select::select3(gatt_task, keep_alive_task, notification_task).await;
+    // Salva o pet de tempos em tempos, só quando ele mudou
+    let save_task = async {
+        let mut last_record = pet.borrow().record();
+        loop {
+            embassy_time::Timer::after(SAVE_INTERVAL).await;
+            let record = pet.borrow().record();
+            if record == last_record {
+                continue;
+            }
+            let saved = pet.borrow().clone();
+            match store_pet(&mut *controller.storage().lock().await, &saved).await {
+                Ok(()) => last_record = record,
+                Err(e) => error!("[save_task] Failed to save the pet: {:?}", e),
+            }
+        }
+    };
 
-            info!("Connection dropped, restarting advertising...");
+    // Publica o nível da bateria, lido pelo telefone na característica padrão
+    let battery_task = async {
+        #[cfg(feature = "battery")]
+        loop {
+            BatteryService::publish(battery.sample());
+            embassy_time::Timer::after(BATTERY_INTERVAL).await;
         }
-    }).await;
-}
\ No newline at end of file
+    };
+
+    info!("Starting advertising loop with notifications support...");
+    let _ = join5(
+        controller.start(&pet),
+        pet_task,
+        visit_task,
+        join4(events_task, confirm_task, save_task, battery_task),
+        console_task,
+    )
+    .await;
+}
@@ -1,3 +1,681 @@
+use core::cell::{Cell, RefCell};
 
-struct BluetoothController {
-}
\ No newline at end of file
+use embassy_futures::join::join_array;
+use embassy_futures::select::{Either, Either4, select, select3, select4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use embedded_storage_async::nor_flash::MultiwriteNorFlash;
+use esp_hal::rng::Trng;
+use esp_radio::ble::controller::BleConnector;
+use log::{error, info, warn};
+use sequential_storage::cache::NoCache;
+use sequential_storage::map::MapStorage;
+use static_cell::StaticCell;
+use tamagotchi_common::dfu::DFU_CONTROL_MAX_LEN;
+use tamagotchi_common::playdate::{self, Outcome, PlayMessage, Role};
+use tamagotchi_common::protocol::TransferKind;
+use tamagotchi_common::visit::Visit;
+use trouble_host::prelude::*;
+use trouble_host::{Address, HostResources, Identity};
+
+use crate::peripherals::bluetooth::BluetoothPeripherals;
+use crate::pet::pet_state::PetState;
+use crate::service::ble::address_service::AddressService;
+use crate::service::ble::advertise_service::{AdvertiseService, Backoff, keep_connection_alive};
+#[cfg(feature = "battery")]
+use crate::service::ble::battery_service::BatteryService;
+use crate::service::ble::connection_params_service::{ConnectionParamsService, Traffic};
+use crate::service::ble::console_service::{ConsoleLines, ConsoleService};
+use crate::service::ble::disconnect_service::DisconnectKind;
+use crate::service::ble::gatt_server::{ATTRIBUTE_TABLE_SIZE, CCCD_TABLE_SIZE, TamagotchiServer};
+use crate::service::ble::gatt_service::GattService;
+use crate::service::ble::l2cap_service::{BulkRequest, L2capError, L2capService, L2capStream};
+use crate::service::ble::notification_characteristics::NotificationCharacteristics;
+use crate::service::ble::notification_service::{CONNECTIONS_MAX, NotificationService};
+use crate::service::ble::ota_service::{DfuRequests, OtaService};
+use crate::service::ble::outbound_service::{Delivery, OutboundError, OutboundQueue, Priority};
+use crate::service::ble::pairing_service::{PairingPrompts, PairingService, PairingUi, SharedPairingUi};
+use crate::service::ble::play_date_service::{PlayDateError, PlayDateService};
+use crate::service::ble::proximity_service::ProximityService;
+use crate::service::ble::read_service::ReadRegistry;
+use crate::service::ble::security_policy::{Access, SecurityPolicy};
+use crate::service::ble::storage_service::{
+    SharedStorage, StorageKey, get_first_bonded, load_friendship, store_friendship, store_pet,
+};
+use crate::service::ble::subscription_service::Subscriptions;
+use crate::service::ble::visit_service::{NEARBY_MAX, NearbyPet, NearbyPets, VisitError, VisitService};
+
+pub const L2CAP_CHANNELS_MAX: usize = 4;
+pub const BLE_STACK_RESOURCES_MAX: usize = 20;
+
+/// Connection events waiting for the application, the oldest are dropped when full
+pub const EVENTS_MAX: usize = 8;
+
+/// How long [`BluetoothController::stop`] waits for a phone to acknowledge the disconnect
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type BleController = ExternalController<BleConnector<'static>, BLE_STACK_RESOURCES_MAX>;
+type BleStack = Stack<'static, BleController, DefaultPacketPool>;
+
+static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
+static RESOURCES: StaticCell<HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>> =
+    StaticCell::new();
+static STACK: StaticCell<BleStack> = StaticCell::new();
+
+/// What happened to a connection, for the application to react to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleEvent {
+    Connected { slot: usize, peer: Address },
+    /// `kind` is `None` when the link was lost without a disconnect event
+    Disconnected { slot: usize, peer: Address, kind: Option<DisconnectKind> },
+}
+
+/// Owns the BLE stack: radio, host, address, bonds and the connection slots.
+///
+/// Built once at boot; [`start`](Self::start) advertises and serves phones
+/// until [`stop`](Self::stop) is called, [`send`](Self::send) queues a text
+/// for the message characteristic and [`events`](Self::events) reports
+/// connections coming and going. As a central it also [`scan`](Self::scan)s
+/// for other pets, [`visit`](Self::visit)s them and [`play_date`](Self::play_date)s
+/// with them; friendships are kept in storage next to the bonds. The owner's
+/// phone can also install new firmware through the DFU service.
+pub struct BluetoothController<U: PairingUi, S: MultiwriteNorFlash> {
+    name: &'static str,
+    stack: &'static BleStack,
+    runner: Mutex<CriticalSectionRawMutex, Runner<'static, BleController, DefaultPacketPool>>,
+    /// Only one connection slot at a time may advertise and accept
+    peripheral: Mutex<CriticalSectionRawMutex, Peripheral<'static, BleController, DefaultPacketPool>>,
+    /// Scans and visits other pets; taken by the scanner while a scan runs
+    central: Mutex<CriticalSectionRawMutex, Option<Central<'static, BleController, DefaultPacketPool>>>,
+    nearby: NearbyPets,
+    server: TamagotchiServer<'static>,
+    security_policy: SecurityPolicy,
+    storage: SharedStorage<S>,
+    pairing_ui: SharedPairingUi<U>,
+    bond_stored: Cell<bool>,
+    /// Last bonded phone, target of directed advertising
+    last_peer: Cell<Option<Address>>,
+    /// Set by [`stop`](Self::stop) until the next [`start`](Self::start)
+    stopping: Cell<bool>,
+    /// Wakes each connection slot when [`stop`](Self::stop) is called
+    stop: [Signal<CriticalSectionRawMutex, ()>; CONNECTIONS_MAX],
+    events: Channel<CriticalSectionRawMutex, BleEvent, EVENTS_MAX>,
+}
+
+impl<U: PairingUi, S: MultiwriteNorFlash> BluetoothController<U, S> {
+    /// Brings the radio up and loads the stored bond. Can only be called once.
+    pub async fn new(
+        name: &'static str,
+        peripherals: BluetoothPeripherals,
+        mut storage: MapStorage<StorageKey, S, NoCache>,
+        trng: &mut Trng,
+        pairing_ui: U,
+    ) -> Self {
+        let radio = RADIO.init(esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller"));
+        let ble = match BleConnector::new(radio, peripherals.bt, Default::default()) {
+            Ok(ble) => ble,
+            Err(e) => panic!("Failed to initialize BLE: {:?}", e),
+        };
+        let controller: BleController = ExternalController::new(ble);
+
+        let address = AddressService::static_address();
+        info!("Our address = {:?}", address);
+
+        let resources = RESOURCES.init(HostResources::new());
+        let stack = STACK.init(
+            trouble_host::new(controller, resources)
+                .set_random_address(address)
+                .set_random_generator_seed(trng),
+        );
+        stack.set_io_capabilities(pairing_ui.io_capabilities());
+
+        info!("Loading bonded devices from storage");
+        let mut bond_stored = false;
+        let mut last_peer = None;
+        match get_first_bonded(&mut storage).await {
+            Ok(Some((bond, kind))) => {
+                info!("Found bonded device: {:?}", bond.identity.bd_addr);
+                last_peer = Some(Address { kind, addr: bond.identity.bd_addr });
+                let _ = stack.add_bond_information(bond);
+                bond_stored = true;
+            }
+            Ok(None) => info!("No bonded devices found in storage"),
+            Err(e) => info!("Error retrieving bonded devices: {:?}. Continuing without bonds.", e),
+        }
+
+        let host = stack.build();
+        let server = TamagotchiServer::new();
+
+        // Public reads; owner writes need the strongest pairing the UI allows
+        let owner = Access::owner(pairing_ui.io_capabilities());
+        let notifications = &server.notifications;
+        let security_policy = SecurityPolicy::new()
+            .with_characteristic(&notifications.message, Access::OPEN, Access::DENIED)
+            .with_characteristic(&notifications.counter, Access::OPEN, Access::DENIED)
+            .with_characteristic(&notifications.tamagotchi_status, Access::OPEN, Access::DENIED)
+            .with_characteristic(&notifications.command, Access::DENIED, owner)
+            .with_characteristic(&notifications.command_result, Access::ENCRYPTED, Access::DENIED)
+            // Visiting pets never pair
+            .with_characteristic(&notifications.visit, Access::OPEN, Access::OPEN)
+            // The console can reboot the device and list bonds
+            .with_characteristic(&server.nus.rx, Access::DENIED, owner)
+            // Only the owner's phone installs firmware
+            .with_characteristic(&server.dfu.control, Access::DENIED, owner)
+            .with_characteristic(&server.dfu.data, Access::DENIED, owner);
+
+        // HID over GATT needs an encrypted link for every characteristic, the CCCD included
+        #[cfg(feature = "hid")]
+        let security_policy = {
+            let hid = &server.hid;
+            let policy = security_policy
+                .with_handle(hid.hid_info.handle, Access::ENCRYPTED, Access::DENIED)
+                .with_handle(hid.report_map.handle, Access::ENCRYPTED, Access::DENIED)
+                .with_handle(hid.hid_control_point.handle, Access::DENIED, Access::ENCRYPTED)
+                .with_handle(hid.protocol_mode.handle, Access::ENCRYPTED, Access::ENCRYPTED)
+                .with_handle(hid.input_keyboard.handle, Access::ENCRYPTED, Access::DENIED)
+                .with_handle(hid.output_keyboard.handle, Access::ENCRYPTED, Access::ENCRYPTED);
+            match hid.input_keyboard.cccd_handle {
+                Some(cccd) => policy.with_handle(cccd, Access::ENCRYPTED, Access::ENCRYPTED),
+                None => policy,
+            }
+        };
+
+        BluetoothController {
+            name,
+            stack,
+            runner: Mutex::new(host.runner),
+            peripheral: Mutex::new(host.peripheral),
+            central: Mutex::new(Some(host.central)),
+            nearby: NearbyPets::new(),
+            server,
+            security_policy,
+            storage: Mutex::new(storage),
+            pairing_ui: Mutex::new(pairing_ui),
+            bond_stored: Cell::new(bond_stored),
+            last_peer: Cell::new(last_peer),
+            stopping: Cell::new(false),
+            stop: [const { Signal::new() }; CONNECTIONS_MAX],
+            events: Channel::new(),
+        }
+    }
+
+    /// Storage shared with the connections, for settings the application keeps next to the bonds
+    pub fn storage(&self) -> &SharedStorage<S> {
+        &self.storage
+    }
+
+    /// Queues `text` for the message characteristic, see [`OutboundQueue::push`]
+    pub fn send(&self, text: &[u8], priority: Priority, delivery: Delivery) -> Result<(), OutboundError> {
+        OutboundQueue::push(text, priority, delivery)
+    }
+
+    pub fn events(&self) -> Receiver<'_, CriticalSectionRawMutex, BleEvent, EVENTS_MAX> {
+        self.events.receiver()
+    }
+
+    /// Listens for other pets for `duration`; what was heard ends up in [`nearby_pets`](Self::nearby_pets)
+    pub async fn scan(&self, duration: Duration) {
+        let mut central = self.central.lock().await;
+        let Some(idle) = central.take() else {
+            error!("[scan] Central role lost by an interrupted scan");
+            return;
+        };
+
+        let mut scanner = Scanner::new(idle);
+        let config = ScanConfig { active: false, ..Default::default() };
+        match scanner.scan(&config).await {
+            // Reports go to the runner's event handler while the session is alive
+            Ok(_session) => Timer::after(duration).await,
+            Err(e) => warn!("[scan] Failed to start scanning: {:?}", e),
+        }
+        *central = Some(scanner.into_inner());
+    }
+
+    /// Pets heard recently, in no particular order
+    pub fn nearby_pets(&self) -> Vec<NearbyPet, NEARBY_MAX> {
+        self.nearby.list()
+    }
+
+    pub fn closest_pet(&self) -> Option<NearbyPet> {
+        self.nearby.closest()
+    }
+
+    /// Connects to `peer` as a central and swaps visits with it
+    pub async fn visit(&self, peer: Address, ours: &Visit) -> Result<Visit, VisitError> {
+        let mut central = self.central.lock().await;
+        let Some(central) = central.as_mut() else {
+            error!("[visit] Central role lost by an interrupted scan");
+            return Err(VisitError::Connect);
+        };
+        VisitService::visit(self.stack, central, peer, ours).await
+    }
+
+    /// Connects to `peer`, the pet that sent `theirs` on a visit, as a central and plays
+    /// with it; the new friendship level is stored under the pet id of the greeting
+    pub async fn play_date(&self, peer: Address, ours: &Visit, theirs: &Visit) -> Result<Outcome, PlayDateError> {
+        let friendship = self.friendship(theirs.id).await;
+        let mut play_date = PlayDateService::prepare(Role::Initiator, *ours, friendship);
+
+        let outcome = {
+            let mut central = self.central.lock().await;
+            let Some(central) = central.as_mut() else {
+                error!("[play] Central role lost by an interrupted scan");
+                return Err(PlayDateError::Connect);
+            };
+            PlayDateService::play(self.stack, central, peer, &mut play_date).await?
+        };
+        self.remember_friend(outcome.friend.id, outcome.friendship).await;
+        Ok(outcome)
+    }
+
+    /// Makes [`start`](Self::start) stop advertising, disconnect every phone and return
+    pub fn stop(&self) {
+        self.stopping.set(true);
+        for stop in &self.stop {
+            stop.signal(());
+        }
+    }
+
+    /// Runs the host and the connection slots until [`stop`](Self::stop) is called or the
+    /// host runner fails; it can be called again once it returned
+    pub async fn start(&self, pet: &RefCell<PetState>) {
+        self.stopping.set(false);
+        for stop in &self.stop {
+            stop.reset();
+        }
+        let notifications = &self.server.notifications;
+        let server = &self.server.server;
+
+        // Status and counter (pet age) reads are computed on the spot from the pet state
+        let read_status = || {
+            let frame = NotificationService::status_frame(pet.borrow().status() as u8)?;
+            notifications.tamagotchi_status.set(server, &frame)
+        };
+        let read_counter = || {
+            let frame = NotificationService::counter_frame(pet.borrow().age)?;
+            notifications.counter.set(server, &frame)
+        };
+        // A visiting pet reads ours right after writing its own
+        let read_visit = || {
+            let frame = VisitService::frame(&pet.borrow().visit())?;
+            notifications.visit.set(server, &frame)
+        };
+        // Last battery sample; the level stays 0 until the application publishes one
+        #[cfg(feature = "battery")]
+        let read_battery = || match BatteryService::level() {
+            Some(level) => self.server.battery.level.set(server, &level),
+            None => Ok(()),
+        };
+        let read_registry = ReadRegistry::new()
+            .with_callback(notifications.tamagotchi_status.handle, &read_status)
+            .with_callback(notifications.counter.handle, &read_counter)
+            .with_callback(notifications.visit.handle, &read_visit);
+        #[cfg(feature = "battery")]
+        let read_registry = read_registry.with_callback(self.server.battery.level.handle, &read_battery);
+        let read_registry = &read_registry;
+
+        let runner = async {
+            // Advertising reports feed the list of nearby pets
+            if let Err(e) = self.runner.lock().await.run_with_handler(&self.nearby).await {
+                error!("[ble] Host runner stopped: {:?}", e);
+            }
+        };
+        let connections = join_array(core::array::from_fn::<_, CONNECTIONS_MAX, _>(|slot| {
+            self.connection_slot(slot, pet, read_registry)
+        }));
+
+        info!("[ble] Starting advertising loop...");
+        // The runner keeps going until the last slot has disconnected its phone
+        select(runner, connections).await;
+        info!("[ble] Stopped");
+    }
+
+    /// Advertises, accepts a connection and serves it until it drops, until the controller stops
+    async fn connection_slot(&self, slot: usize, pet: &RefCell<PetState>, read_registry: &ReadRegistry<'_>) {
+        let notifications = &self.server.notifications;
+        let mut backoff = Backoff::new();
+        // Peer that timed out on this slot, re-advertised to directly
+        let mut reconnect_peer: Option<Address> = None;
+        while !self.stopping.get() {
+            let advertise = async {
+                let mut peripheral = self.peripheral.lock().await;
+                let mut advertise_service = AdvertiseService::new(self.name, pet.borrow().beacon())
+                    .await
+                    .with_directed_peer(reconnect_peer.take().or(self.last_peer.get()));
+
+                info!("[slot {}] Advertising, waiting for connection...", slot);
+                advertise_service
+                    .advertise::<BleController, ATTRIBUTE_TABLE_SIZE, CCCD_TABLE_SIZE, CONNECTIONS_MAX>(
+                        &mut peripheral,
+                        &self.server.server,
+                    )
+                    .await
+            };
+            // Dropping the advertiser stops advertising
+            let Either::First(result) = select(advertise, self.stop[slot].wait()).await else {
+                break;
+            };
+
+            let conn = match result {
+                Ok(conn) => {
+                    backoff.reset();
+                    conn
+                }
+                Err(e) => {
+                    error!("[slot {}] Advertising failed: {:?}", slot, e);
+                    match backoff.next_delay() {
+                        Some(delay) => Timer::after(delay).await,
+                        None => self.reboot_after_radio_failure(pet).await,
+                    }
+                    continue;
+                }
+            };
+
+            let raw = conn.raw();
+            let peer = raw.peer_address();
+            info!("[slot {}] Connected to {:?}", slot, peer);
+            self.publish(BleEvent::Connected { slot, peer });
+            OutboundQueue::connected(slot);
+            if let Err(e) = raw.set_bondable(!self.bond_stored.get()) {
+                error!("[slot {}] Failed to set bondable: {:?}", slot, e);
+            }
+
+            // CCCD state of this connection
+            let subscriptions = Subscriptions::new()
+                .with_characteristic(&notifications.message)
+                .with_characteristic(&notifications.counter)
+                .with_characteristic(&notifications.tamagotchi_status)
+                .with_characteristic(&notifications.command_result)
+                .with_characteristic(&self.server.nus.tx)
+                .with_characteristic(&self.server.dfu.control);
+
+            // Requests, transfers and play dates of this connection, sampled to pick its parameters
+            let traffic = Traffic::new();
+            let console_lines = ConsoleLines::new();
+            let dfu_requests = DfuRequests::new();
+            let pairing_prompts = PairingPrompts::new();
+
+            // Every connection has its own GattService (MTU, security, commands in progress)
+            let gatt_service = GattService::new()
+                .with_command_handle(notifications.command.handle)
+                .with_visit_handle(notifications.visit.handle)
+                .with_console(self.server.nus.rx.handle, &console_lines)
+                .with_dfu(self.server.dfu.control.handle, self.server.dfu.data.handle, &dfu_requests)
+                .with_pairing_prompts(&pairing_prompts)
+                .with_security_policy(self.security_policy.clone())
+                .with_read_registry(read_registry)
+                .with_subscriptions(&subscriptions)
+                .with_traffic(&traffic);
+            let gatt_task = gatt_service.handle_gatt_events(&self.storage, &conn, &self.bond_stored);
+
+            // Link upkeep: supervision, connection parameters, the pairing prompts and stopping
+            let link_task = select4(
+                keep_connection_alive(&conn, self.stack),
+                ConnectionParamsService::adapt(&conn, self.stack, &traffic),
+                PairingService::serve(&self.pairing_ui, &conn, &pairing_prompts),
+                self.disconnect_on_stop(slot, &conn),
+            );
+
+            // Pet notifications, the snapshot on subscribe and queued messages
+            let forward_task = select3(
+                NotificationService::forward(notifications, &conn, &subscriptions),
+                Self::send_snapshots(slot, notifications, &conn, &subscriptions, pet),
+                OutboundQueue::run(slot, notifications, &conn, &subscriptions),
+            );
+
+            // Requests outside of the pet service: bulk channel, debug console and firmware updates
+            let requests_task = select3(
+                self.serve_bulk(&conn, pet, &traffic),
+                self.serve_console(&conn, &subscriptions, &console_lines, pet),
+                self.serve_dfu(&conn, &subscriptions, &dfu_requests),
+            );
+
+            let kind = match select4(gatt_task, link_task, forward_task, requests_task).await {
+                Either4::First(kind) => Some(kind),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                let identity = gatt_service.peer_identity(peer);
+                // The phone no longer accepts our keys: forget the bond in the host too
+                if kind.invalidates_bond() {
+                    let _ = self.stack.remove_bond_information(Identity { bd_addr: identity.addr, irk: None });
+                    if self.last_peer.get().is_some_and(|last| last.addr == identity.addr) {
+                        self.last_peer.set(None);
+                    }
+                }
+                // Supervision timeout: the phone is probably nearby, call it back first
+                if kind.expects_reconnect() {
+                    reconnect_peer = Some(identity);
+                }
+            }
+            ProximityService::disconnected(peer);
+            self.publish(BleEvent::Disconnected { slot, peer, kind });
+
+            OutboundQueue::disconnected(slot);
+            info!("[slot {}] Connection dropped", slot);
+        }
+        info!("[slot {}] Stopped", slot);
+    }
+
+    /// Disconnects the phone once [`stop`](Self::stop) is called; the disconnect event ends
+    /// the GATT task, this only returns if it doesn't arrive within [`DISCONNECT_TIMEOUT`]
+    async fn disconnect_on_stop(&self, slot: usize, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+        self.stop[slot].wait().await;
+        info!("[slot {}] Stopping, disconnecting {:?}", slot, conn.raw().peer_address());
+        conn.raw().disconnect();
+        Timer::after(DISCONNECT_TIMEOUT).await;
+        warn!("[slot {}] No disconnect event, dropping the connection", slot);
+    }
+
+    /// Saves the pet and reboots the whole device when advertising keeps failing.
+    ///
+    /// The BLE stack isn't restarted in place: the host keeps controller state
+    /// (connections, resolving list, advertising sets) that can't be rebuilt, so
+    /// this is a software reset of the chip. Every connection drops and the
+    /// firmware boots again with the pet and the bonds read back from flash.
+    async fn reboot_after_radio_failure(&self, pet: &RefCell<PetState>) -> ! {
+        error!("[ble] Too many advertising failures, rebooting the device");
+        let saved = pet.borrow().clone();
+        if let Err(e) = store_pet(&mut *self.storage.lock().await, &saved).await {
+            error!("[ble] Failed to save the pet: {:?}", e);
+        }
+        esp_hal::system::software_reset()
+    }
+
+    /// Sends the current state as soon as the phone subscribes, without waiting for the next change
+    async fn send_snapshots(
+        slot: usize,
+        notifications: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+        pet: &RefCell<PetState>,
+    ) {
+        loop {
+            let handle = subscriptions.subscribed().await;
+            if handle == notifications.message.handle {
+                info!("[slot {}] Sending welcome notification...", slot);
+                let welcome = pet.borrow().language.strings().welcome;
+                let _ = NotificationService::send_message(notifications, conn, welcome).await;
+            } else if handle == notifications.tamagotchi_status.handle {
+                let status = pet.borrow().status();
+                let _ = NotificationService::send_status(notifications, conn, status as u8).await;
+            } else if handle == notifications.counter.handle {
+                let age = pet.borrow().age;
+                let _ = NotificationService::send_counter(notifications, conn, age).await;
+            }
+        }
+    }
+
+    /// L2CAP channel for large transfers, faster than GATT notifications, and play dates.
+    /// A refused request closes the channel; the peer may open it again.
+    async fn serve_bulk(
+        &self,
+        conn: &GattConnection<'static, '_, DefaultPacketPool>,
+        pet: &RefCell<PetState>,
+        traffic: &Traffic,
+    ) {
+        let peer = conn.raw().peer_address();
+        loop {
+            let mut stream = match L2capService::accept(self.stack, conn.raw()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    info!("[bulk] L2CAP accept failed: {:?}", e);
+                    Timer::after(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            loop {
+                let start = match stream.receive_request().await {
+                    Ok(BulkRequest::Transfer(start)) => start,
+                    Ok(BulkRequest::PlayDate(greeting)) => {
+                        traffic.transfer_started();
+                        let result = self.answer_play_date(&mut stream, peer, greeting, pet).await;
+                        traffic.transfer_finished();
+                        if let Err(e) = result {
+                            info!("[play] Play date failed: {:?}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        info!("[bulk] Bulk channel closed: {:?}", e);
+                        break;
+                    }
+                };
+
+                if let Err(code) = L2capService::check_transfer(conn.raw()) {
+                    warn!("[bulk] {:?} transfer from {:?} refused: {:?}", start.kind, peer, code);
+                    break;
+                }
+
+                traffic.transfer_started();
+                let result = match start.kind {
+                    // Nothing keeps or shows sprites yet: refuse it rather than drop the data
+                    TransferKind::SpriteUpload => Err(L2capError::Refused),
+                    TransferKind::SaveExport => {
+                        let (save, len) = pet.borrow().export();
+                        stream.send_transfer(start.kind, &save[..len]).await
+                    }
+                    // No log buffer yet, answer with an empty transfer
+                    TransferKind::LogDownload => stream.send_transfer(start.kind, &[]).await,
+                };
+                traffic.transfer_finished();
+
+                if let Err(e) = result {
+                    info!("[bulk] Transfer failed: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Runs the lines typed in a terminal connected to the UART service
+    async fn serve_console(
+        &self,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+        lines: &ConsoleLines,
+        pet: &RefCell<PetState>,
+    ) {
+        let nus = &self.server.nus;
+        loop {
+            let line = lines.receive().await;
+            let reply = ConsoleService::handle(line.as_deref().map_err(|e| *e), pet, &self.storage).await;
+
+            if subscriptions.is_subscribed(nus.tx.handle) {
+                if let Err(e) = ConsoleService::send(nus, conn, &reply).await {
+                    warn!("[console] Failed to send reply: {:?}", e);
+                }
+            } else {
+                info!("[console] Reply dropped, terminal not subscribed: {}", reply.text);
+            }
+            if reply.reboot {
+                ConsoleService::reboot().await;
+            }
+        }
+    }
+
+    /// Writes the firmware image sent on the DFU service and reboots into it once verified.
+    ///
+    /// An update still running when the connection drops is abandoned; the
+    /// phone starts over on the next connection.
+    async fn serve_dfu(
+        &self,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+        requests: &DfuRequests,
+    ) {
+        let control = &self.server.dfu.control;
+        let mut ota = OtaService::new();
+        loop {
+            let reply = ota.handle(requests.receive().await);
+
+            if let Some(response) = reply.response {
+                if subscriptions.is_subscribed(control.handle) {
+                    let value: Vec<u8, DFU_CONTROL_MAX_LEN> = Vec::from_slice(&response.to_bytes()).unwrap_or_default();
+                    if let Err(e) = control.notify(conn, &value).await {
+                        warn!("[ota] Failed to send {:?}: {:?}", response, e);
+                    }
+                } else {
+                    info!("[ota] Response dropped, phone not subscribed: {:?}", response);
+                }
+            }
+            if reply.reboot {
+                OtaService::reboot().await;
+            }
+        }
+    }
+
+    /// Plays with a pet that greeted us on the bulk channel
+    async fn answer_play_date(
+        &self,
+        stream: &mut L2capStream<'_, BleController>,
+        peer: Address,
+        greeting: PlayMessage,
+        pet: &RefCell<PetState>,
+    ) -> Result<(), PlayDateError> {
+        let PlayMessage::Greeting { visit: theirs, .. } = greeting else {
+            warn!("[play] {:?} didn't start with a greeting", peer);
+            return Err(PlayDateError::Rules(playdate::PlayDateError::Unexpected(greeting)));
+        };
+        let friendship = self.friendship(theirs.id).await;
+        let ours = pet.borrow().visit();
+        let mut play_date = PlayDateService::prepare(Role::Responder, ours, friendship);
+
+        let outcome = PlayDateService::respond(stream, &mut play_date, greeting).await?;
+        self.remember_friend(outcome.friend.id, outcome.friendship).await;
+        PlayDateService::finished(outcome);
+        Ok(())
+    }
+
+    /// Stored friendship level with the pet `id`, 0 when it can't be read
+    async fn friendship(&self, id: u32) -> u8 {
+        match load_friendship(&mut *self.storage.lock().await, id).await {
+            Ok(level) => level,
+            Err(e) => {
+                warn!("[play] Failed to load friendship with pet {:08x}: {:?}", id, e);
+                0
+            }
+        }
+    }
+
+    async fn remember_friend(&self, id: u32, level: u8) {
+        if let Err(e) = store_friendship(&mut *self.storage.lock().await, id, level).await {
+            error!("[play] Failed to store friendship with pet {:08x}: {:?}", id, e);
+        }
+    }
+
+    fn publish(&self, event: BleEvent) {
+        if self.events.is_full() {
+            // Nobody is reading, keep the newest
+            let _ = self.events.try_receive();
+            warn!("[ble] Event queue full, dropped the oldest event");
+        }
+        let _ = self.events.try_send(event);
+    }
+}
@@ -1,5 +1,13 @@
+use esp_hal::Async;
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
+use esp_hal::gpio::{Input, InputConfig, Pull};
 use esp_hal::peripherals::TIMG0;
+use esp_hal::uart::{Config, Uart};
+use crate::peripherals::battery::BatteryPeripherals;
+use crate::peripherals::button::ButtonPeripherals;
 use crate::peripherals::timer::TimerPeripherals;
+use crate::peripherals::uart::UartPeripherals;
+use crate::service::ble::battery_service::BatterySensor;
 
 pub struct Factory;
 
@@ -7,4 +15,24 @@ impl<'a> Factory {
     pub fn create_timer_group0(timer_peripherals: TimerPeripherals) -> esp_hal::timer::timg::TimerGroup<'a, TIMG0<'a>> {
         esp_hal::timer::timg::TimerGroup::new(timer_peripherals.timer0)
     }
-}
\ No newline at end of file
+
+    pub fn create_button(button_peripherals: ButtonPeripherals) -> Input<'a> {
+        Input::new(button_peripherals.pin, InputConfig::default().with_pull(Pull::Up))
+    }
+
+    /// Serial console at 115200 baud, the same port and speed as the logs
+    pub fn create_console_uart(uart_peripherals: UartPeripherals) -> Uart<'a, Async> {
+        Uart::new(uart_peripherals.uart, Config::default().with_baudrate(115_200))
+            .expect("Failed to configure the console UART")
+            .with_tx(uart_peripherals.tx)
+            .with_rx(uart_peripherals.rx)
+            .into_async()
+    }
+
+    /// Battery voltage at 11 dB attenuation, covering the divided LiPo range
+    pub fn create_battery_sensor(battery_peripherals: BatteryPeripherals) -> BatterySensor {
+        let mut config = AdcConfig::new();
+        let pin = config.enable_pin(battery_peripherals.pin, Attenuation::_11dB);
+        BatterySensor::new(Adc::new(battery_peripherals.adc, config), pin)
+    }
+}
@@ -1,6 +1,9 @@
 #![no_std]
 
+extern crate alloc;
+
 pub mod peripherals;
 pub mod factory;
 pub mod controller;
-pub mod service;
\ No newline at end of file
+pub mod service;
+pub mod pet;
\ No newline at end of file
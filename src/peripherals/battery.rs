@@ -0,0 +1,14 @@
+use esp_hal::peripherals::{ADC1, GPIO35};
+
+
+pub struct BatteryPeripherals {
+    pub adc: ADC1<'static>,
+    /// Battery through a 1:1 divider, as on most LiPo dev kits
+    pub pin: GPIO35<'static>,
+}
+
+impl BatteryPeripherals {
+    pub fn new(adc: ADC1<'static>, pin: GPIO35<'static>) -> Self {
+        BatteryPeripherals { adc, pin }
+    }
+}
@@ -1,4 +1,12 @@
+use esp_hal::peripherals::BT;
+
 
 pub struct BluetoothPeripherals {
-    pub bt: esp_hal::peripherals::BT<'static>,
-}
\ No newline at end of file
+    pub bt: BT<'static>,
+}
+
+impl BluetoothPeripherals {
+    pub fn new(bt: BT<'static>) -> Self {
+        BluetoothPeripherals { bt }
+    }
+}
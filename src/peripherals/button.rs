@@ -0,0 +1,13 @@
+use esp_hal::peripherals::GPIO0;
+
+
+pub struct ButtonPeripherals {
+    /// BOOT button of the dev kit, active low
+    pub pin: GPIO0<'static>,
+}
+
+impl ButtonPeripherals {
+    pub fn new(pin: GPIO0<'static>) -> Self {
+        ButtonPeripherals { pin }
+    }
+}
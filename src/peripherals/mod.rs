@@ -1,2 +1,5 @@
 pub mod timer;
-pub mod bluetooth;
\ No newline at end of file
+pub mod bluetooth;
+pub mod button;
+pub mod uart;
+pub mod battery;
\ No newline at end of file
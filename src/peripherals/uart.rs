@@ -0,0 +1,15 @@
+use esp_hal::peripherals::{GPIO1, GPIO3, UART0};
+
+
+pub struct UartPeripherals {
+    /// USB serial of the dev kit, shared with the log output
+    pub uart: UART0<'static>,
+    pub tx: GPIO1<'static>,
+    pub rx: GPIO3<'static>,
+}
+
+impl UartPeripherals {
+    pub fn new(uart: UART0<'static>, tx: GPIO1<'static>, rx: GPIO3<'static>) -> Self {
+        UartPeripherals { uart, tx, rx }
+    }
+}
@@ -0,0 +1 @@
+pub mod pet_state;
\ No newline at end of file
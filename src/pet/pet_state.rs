@@ -0,0 +1,279 @@
+use heapless::String;
+use tamagotchi_common::beacon::PetBeacon;
+use tamagotchi_common::i18n::Language;
+use tamagotchi_common::pet::{LifeStage, Species, TamagotchiStatus};
+use tamagotchi_common::playdate::Outcome;
+use tamagotchi_common::proximity::Zone;
+use tamagotchi_common::visit::Visit;
+
+use crate::service::ble::command_service::{Command, CommandStatus, PET_NAME_MAX, PET_STATS_LEN};
+
+/// Size of a save export: stats, name length and name bytes
+pub const PET_SAVE_MAX_LEN: usize = PET_STATS_LEN + 1 + PET_NAME_MAX;
+
+/// Species, age, the need meters, flags and name length, ahead of the name in a record
+const RECORD_HEADER_LEN: usize = 11;
+
+/// Size of the record kept in flash across reboots
+pub const PET_RECORD_MAX_LEN: usize = RECORD_HEADER_LEN + PET_NAME_MAX;
+
+/// Upper bound of every need meter
+pub const STAT_MAX: u8 = 100;
+
+const FEED_AMOUNT: u8 = 30;
+const PLAY_AMOUNT: u8 = 20;
+const PLAY_HUNGER_COST: u8 = 10;
+const NEED_THRESHOLD: u8 = 30;
+const VISIT_AMOUNT: u8 = 15;
+const REUNION_AMOUNT: u8 = 20;
+
+/// Age, in ticks, at which the pet reaches each stage after the egg
+const STAGE_AGES: [(u32, LifeStage); 4] = [
+    (6, LifeStage::Baby),
+    (360, LifeStage::Child),
+    (8_640, LifeStage::Teen),
+    (25_920, LifeStage::Adult),
+];
+
+/// Application state of the pet, driven by commands and by the periodic tick
+#[derive(Debug, Clone)]
+pub struct PetState {
+    /// Identifies the pet to other pets, 0 until the application sets it
+    pub id: u32,
+    pub name: String<PET_NAME_MAX>,
+    pub species: Species,
+    /// Number of ticks lived
+    pub age: u32,
+    /// 0 = starving, 100 = full
+    pub fullness: u8,
+    pub happiness: u8,
+    pub hygiene: u8,
+    pub health: u8,
+    pub lights_on: bool,
+    pub sick: bool,
+    /// Language of the messages sent to the phone
+    pub language: Language,
+    /// The owner's phone went out of range and hasn't come back yet
+    pub owner_away: bool,
+}
+
+impl PetState {
+    pub fn new(name: &str) -> Self {
+        let mut pet_name = String::new();
+        for c in name.chars() {
+            if pet_name.push(c).is_err() {
+                break;
+            }
+        }
+
+        PetState {
+            id: 0,
+            name: pet_name,
+            species: Species::Blob,
+            age: 0,
+            fullness: STAT_MAX,
+            happiness: STAT_MAX,
+            hygiene: STAT_MAX,
+            health: STAT_MAX,
+            lights_on: true,
+            sick: false,
+            language: Language::default(),
+            owner_away: false,
+        }
+    }
+
+    /// Applies a command from the phone and returns the status to report back
+    pub fn apply(&mut self, command: &Command) -> CommandStatus {
+        match command {
+            Command::Feed => {
+                if !self.lights_on || self.fullness == STAT_MAX {
+                    return CommandStatus::Rejected;
+                }
+                self.fullness = self.fullness.saturating_add(FEED_AMOUNT).min(STAT_MAX);
+            }
+            Command::Play => {
+                if !self.lights_on || self.sick {
+                    return CommandStatus::Rejected;
+                }
+                self.happiness = self.happiness.saturating_add(PLAY_AMOUNT).min(STAT_MAX);
+                self.fullness = self.fullness.saturating_sub(PLAY_HUNGER_COST);
+            }
+            Command::Clean => {
+                self.hygiene = STAT_MAX;
+            }
+            Command::Medicine => {
+                if !self.sick {
+                    return CommandStatus::Rejected;
+                }
+                self.sick = false;
+                self.health = STAT_MAX;
+            }
+            Command::Lights(on) => {
+                self.lights_on = *on;
+            }
+            Command::Rename(name) => {
+                self.name.clear();
+                // Both hold up to PET_NAME_MAX bytes
+                let _ = self.name.push_str(name.as_str());
+            }
+            Command::Query => {}
+            Command::SetLanguage(language) => {
+                self.language = *language;
+            }
+        }
+        CommandStatus::Ok
+    }
+
+    /// Advances the simulation by one step: needs decay, and neglect makes the pet sick
+    pub fn tick(&mut self) {
+        let sleeping = !self.lights_on;
+        self.age = self.age.saturating_add(1);
+
+        self.fullness = self.fullness.saturating_sub(if sleeping { 1 } else { 2 });
+        self.hygiene = self.hygiene.saturating_sub(1);
+        if !sleeping {
+            // Missing the owner makes the time drag
+            let boredom = if self.owner_away { 4 } else { 2 };
+            self.happiness = self.happiness.saturating_sub(boredom);
+        }
+
+        if self.fullness == 0 || self.hygiene == 0 {
+            self.sick = true;
+        }
+        if self.sick {
+            self.health = self.health.saturating_sub(5);
+        }
+    }
+
+    pub fn stage(&self) -> LifeStage {
+        STAGE_AGES
+            .iter()
+            .rev()
+            .find(|(age, _)| self.age >= *age)
+            .map_or(LifeStage::Egg, |(_, stage)| *stage)
+    }
+
+    /// Summary broadcast in the advertising data
+    pub fn beacon(&self) -> PetBeacon {
+        PetBeacon {
+            species: self.species,
+            stage: self.stage(),
+            mood: self.status(),
+            happiness_bucket: PetBeacon::happiness_bucket(self.happiness),
+        }
+    }
+
+    /// What this pet tells another one it visits, or that visits it
+    pub fn visit(&self) -> Visit {
+        Visit::new(self.id, self.beacon(), &self.name)
+    }
+
+    /// Company cheers the pet up, unless it's asleep
+    pub fn receive_visit(&mut self, _visit: &Visit) {
+        if self.lights_on {
+            self.happiness = self.happiness.saturating_add(VISIT_AMOUNT).min(STAT_MAX);
+        }
+    }
+
+    /// The owner walking away makes the pet lonely, coming back cheers it up
+    pub fn owner_moved(&mut self, zone: Zone) {
+        match zone {
+            Zone::Far => self.owner_away = true,
+            Zone::Near | Zone::Medium if self.owner_away => {
+                self.owner_away = false;
+                self.happiness = self.happiness.saturating_add(REUNION_AMOUNT).min(STAT_MAX);
+            }
+            Zone::Near | Zone::Medium => {}
+        }
+    }
+
+    /// Playing with a friend is fun, and tiring; a sleeping pet just sleeps through it
+    pub fn receive_play_date(&mut self, outcome: &Outcome) {
+        if self.lights_on {
+            self.happiness = self.happiness.saturating_add(outcome.happiness).min(STAT_MAX);
+            self.fullness = self.fullness.saturating_sub(PLAY_HUNGER_COST);
+        }
+    }
+
+    pub fn status(&self) -> TamagotchiStatus {
+        if self.sick {
+            TamagotchiStatus::Sick
+        } else if !self.lights_on {
+            TamagotchiStatus::Sleeping
+        } else if self.fullness < NEED_THRESHOLD {
+            TamagotchiStatus::Hungry
+        } else if self.owner_away {
+            TamagotchiStatus::Lonely
+        } else if self.happiness < NEED_THRESHOLD {
+            TamagotchiStatus::Tired
+        } else {
+            TamagotchiStatus::Happy
+        }
+    }
+
+    /// Compact snapshot sent with command results:
+    /// `[fullness, happiness, hygiene, health, flags, status]`, flags bit 0 = lights, bit 1 = sick
+    pub fn stats(&self) -> [u8; PET_STATS_LEN] {
+        let flags = (self.lights_on as u8) | ((self.sick as u8) << 1);
+        [
+            self.fullness,
+            self.happiness,
+            self.hygiene,
+            self.health,
+            flags,
+            self.status() as u8,
+        ]
+    }
+
+    /// Serializes the pet for a save export: `[stats..., name_len, name...]`
+    pub fn export(&self) -> ([u8; PET_SAVE_MAX_LEN], usize) {
+        let mut save = [0u8; PET_SAVE_MAX_LEN];
+        let name = self.name.as_bytes();
+
+        save[..PET_STATS_LEN].copy_from_slice(&self.stats());
+        save[PET_STATS_LEN] = name.len() as u8;
+        save[PET_STATS_LEN + 1..PET_STATS_LEN + 1 + name.len()].copy_from_slice(name);
+        (save, PET_STATS_LEN + 1 + name.len())
+    }
+
+    /// Serializes what survives a reboot:
+    /// `[species, age (u32 LE), fullness, happiness, hygiene, health, flags, name_len, name...]`.
+    /// The id comes from the address and the language is stored on its own.
+    pub fn record(&self) -> ([u8; PET_RECORD_MAX_LEN], usize) {
+        let mut record = [0u8; PET_RECORD_MAX_LEN];
+        let name = self.name.as_bytes();
+
+        record[0] = self.species as u8;
+        record[1..5].copy_from_slice(&self.age.to_le_bytes());
+        // Need meters and flags, without the derived status
+        record[5..10].copy_from_slice(&self.stats()[..5]);
+        record[10] = name.len() as u8;
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + name.len()].copy_from_slice(name);
+        (record, RECORD_HEADER_LEN + name.len())
+    }
+
+    /// Takes back a [`record`](Self::record); the pet is left untouched when it can't be read
+    pub fn restore(&mut self, record: &[u8]) -> bool {
+        let Some((header, name)) = record.split_first_chunk::<RECORD_HEADER_LEN>() else {
+            return false;
+        };
+        let Some(species) = Species::from_u8(header[0]) else {
+            return false;
+        };
+        let name = name.get(..header[10] as usize).and_then(|name| core::str::from_utf8(name).ok());
+        let Some(Ok(name)) = name.map(String::try_from) else {
+            return false;
+        };
+
+        self.species = species;
+        self.age = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+        self.fullness = header[5].min(STAT_MAX);
+        self.happiness = header[6].min(STAT_MAX);
+        self.hygiene = header[7].min(STAT_MAX);
+        self.health = header[8].min(STAT_MAX);
+        self.lights_on = header[9] & 0x01 != 0;
+        self.sick = header[9] & 0x02 != 0;
+        self.name = name;
+        true
+    }
+}
@@ -0,0 +1,50 @@
+use esp_hal::efuse::Efuse;
+use trouble_host::Address;
+use trouble_host::prelude::{AddrKind, BdAddr};
+
+pub struct AddressService;
+
+impl AddressService {
+    /// Static random address derived from the chip's eFuse MAC.
+    ///
+    /// Stays the same across reboots and differs between boards. The two most
+    /// significant bits are set, as the spec requires for static addresses.
+    pub fn static_address() -> Address {
+        let mac = Efuse::mac_address();
+
+        // The MAC is written most significant byte first, BdAddr is little endian
+        let mut raw = [0u8; 6];
+        for (byte, mac_byte) in raw.iter_mut().zip(mac.iter().rev()) {
+            *byte = *mac_byte;
+        }
+        raw[5] |= 0xC0;
+
+        Address::random(raw)
+    }
+
+    /// Identifies this pet to other pets, carried in its visits.
+    ///
+    /// The last four bytes of the eFuse MAC: the first ones are the vendor's,
+    /// the rest is unique per chip and never changes, unlike our address when
+    /// it rotates.
+    pub fn pet_id() -> u32 {
+        let mac = Efuse::mac_address();
+        u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]])
+    }
+
+    /// Kind of the identity address a peer bonded with.
+    ///
+    /// The host doesn't report it: when the peer connected from its identity
+    /// address the connection tells, otherwise the identity was distributed
+    /// while pairing and is taken as static random only when its two most
+    /// significant bits are set, as phones mostly use their public address.
+    pub fn identity_kind(identity: BdAddr, peer: Address) -> AddrKind {
+        if identity == peer.addr {
+            peer.kind
+        } else if identity.raw()[5] & 0xC0 == 0xC0 {
+            AddrKind::RANDOM
+        } else {
+            AddrKind::PUBLIC
+        }
+    }
+}
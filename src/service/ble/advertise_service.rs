@@ -1,75 +1,231 @@
 use core::usize;
 
+use embassy_futures::select::{Either, select};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
 use log::{error, info};
-use trouble_host::{Controller, Stack, advertise, gatt::GattConnection, prelude::{AdStructure, AdvertisementParameters, AttributeServer, DefaultPacketPool, Peripheral, TxPower}};
+use tamagotchi_common::beacon::{self, ADV_DATA_MAX_LEN, PetBeacon};
+use tamagotchi_common::proximity::ProximityTracker;
+use trouble_host::{Address, Controller, Stack, advertise, gatt::GattConnection, prelude::{AdvertisementParameters, AttributeServer, DefaultPacketPool, Peripheral, TxPower}};
 
+use crate::service::ble::proximity_service::ProximityService;
+
+/// Latest pet summary, picked up by whichever slot is advertising
+static BEACON_UPDATES: Signal<CriticalSectionRawMutex, PetBeacon> = Signal::new();
+
+/// How long phones get to reconnect quickly before we fall back to the slow profile
+const FAST_RECONNECT_DURATION: Duration = Duration::from_secs(30);
+
+/// High duty cycle directed advertising is limited to 1.28 s by the spec
+const DIRECTED_DURATION: Duration = Duration::from_millis(1280);
+
+/// First retry delay after a failed advertising round, doubled on every failure
+const BACKOFF_BASE: Duration = Duration::from_millis(200);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// Consecutive failures after which the device saves the pet and reboots
+pub const REBOOT_AFTER_FAILURES: u32 = 6;
+
+#[derive(Debug)]
+pub enum AdvertiseError {
+    /// The controller refused to start advertising
+    Advertise(trouble_host::Error),
+    /// Advertising stopped without a connection
+    Accept(trouble_host::Error),
+    /// The connection couldn't be bound to the attribute server
+    AttachServer(trouble_host::Error),
+}
+
+/// Exponential backoff between advertising attempts
+#[derive(Debug, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Backoff { failures: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Registers a failure and returns how long to wait before retrying,
+    /// or `None` once the device should reboot instead
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        if self.failures >= REBOOT_AFTER_FAILURES {
+            return None;
+        }
+        let delay = BACKOFF_BASE * (1 << (self.failures - 1));
+        Some(if delay > BACKOFF_MAX { BACKOFF_MAX } else { delay })
+    }
+}
+
+/// Advertising profiles, tried in order until a phone connects
+#[derive(Debug, Clone, Copy)]
+pub enum AdvertisingProfile {
+    /// High duty cycle directed advertising to the last bonded peer
+    Directed(Address),
+    /// Short intervals right after boot or a disconnection, so phones find us fast
+    FastReconnect,
+    /// Long intervals at reduced TX power, kept until someone connects
+    SlowLowPower,
+}
+
+impl AdvertisingProfile {
+    /// How long the profile runs before moving to [`AdvertisingProfile::next`], `None` for forever
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            AdvertisingProfile::Directed(_) => Some(DIRECTED_DURATION),
+            AdvertisingProfile::FastReconnect => Some(FAST_RECONNECT_DURATION),
+            AdvertisingProfile::SlowLowPower => None,
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            AdvertisingProfile::Directed(_) => AdvertisingProfile::FastReconnect,
+            AdvertisingProfile::FastReconnect | AdvertisingProfile::SlowLowPower => AdvertisingProfile::SlowLowPower,
+        }
+    }
+
+    pub fn params(&self) -> AdvertisementParameters {
+        match self {
+            // Interval is fixed by the controller for high duty directed advertising
+            AdvertisingProfile::Directed(_) => AdvertisementParameters {
+                tx_power: TxPower::ZerodBm,
+                ..Default::default()
+            },
+            AdvertisingProfile::FastReconnect => AdvertisementParameters {
+                interval_min: Duration::from_millis(30),
+                interval_max: Duration::from_millis(60),
+                tx_power: TxPower::ZerodBm,
+                ..Default::default()
+            },
+            AdvertisingProfile::SlowLowPower => AdvertisementParameters {
+                interval_min: Duration::from_millis(1000),
+                interval_max: Duration::from_millis(1285),
+                tx_power: TxPower::Minus8dBm,
+                ..Default::default()
+            },
+        }
+    }
+}
 
 pub struct AdvertiseService {
-    advertise_data: [u8; 31],
+    advertise_data: [u8; ADV_DATA_MAX_LEN],
     len: usize,
+    scan_data: [u8; ADV_DATA_MAX_LEN],
+    scan_len: usize,
+    directed_peer: Option<Address>,
 }
 
 impl AdvertiseService {
 
-    pub async fn new(name: &str) -> Self {
-        let mut advertise_data = [0u8; 31];
-        let len = AdStructure::encode_slice(
-            &[
-                AdStructure::Flags(trouble_host::prelude::LE_GENERAL_DISCOVERABLE | trouble_host::prelude::BR_EDR_NOT_SUPPORTED),
-                AdStructure::ServiceUuids16(&[
-                trouble_host::prelude::service::BATTERY.to_le_bytes(),
-                trouble_host::prelude::service::HUMAN_INTERFACE_DEVICE.to_le_bytes(),
-                ]),
-                AdStructure::CompleteLocalName(name.as_bytes()),
-            ],
-            &mut advertise_data,
-        )
-        .unwrap();
-
-        Self {
-            advertise_data,
-            len,
-        }
-    } 
+    /// Builds the advertising payload: flags, services and the pet beacon in
+    /// the advertising data, and the name in the scan response.
+    pub async fn new(name: &str, beacon: PetBeacon) -> Self {
+        let scan = beacon::scan_response_data(name);
+        let mut scan_data = [0u8; ADV_DATA_MAX_LEN];
+        let scan_len = scan.as_bytes().len();
+        scan_data[..scan_len].copy_from_slice(scan.as_bytes());
+
+        let mut service = Self {
+            advertise_data: [0u8; ADV_DATA_MAX_LEN],
+            len: 0,
+            scan_data,
+            scan_len,
+            directed_peer: None,
+        };
+        service.set_beacon(&beacon);
+        service
+    }
+
+    /// Starts every advertising round with directed advertising to `peer`, usually the last bonded phone
+    pub fn with_directed_peer(mut self, peer: Option<Address>) -> Self {
+        self.directed_peer = peer;
+        self
+    }
+
+    /// Hands a new pet summary to the advertiser; the advertisement restarts
+    /// with it unless a phone connects first.
+    pub fn publish_beacon(beacon: PetBeacon) {
+        BEACON_UPDATES.signal(beacon);
+    }
+
+    fn set_beacon(&mut self, beacon: &PetBeacon) {
+        // Only the optional services this build registers; phones filter scans on them
+        let service_uuids: &[u16] = &[
+            #[cfg(feature = "battery")]
+            u16::from_le_bytes(trouble_host::prelude::service::BATTERY.to_le_bytes()),
+            #[cfg(feature = "hid")]
+            u16::from_le_bytes(trouble_host::prelude::service::HUMAN_INTERFACE_DEVICE.to_le_bytes()),
+        ];
+        let data = beacon::advertising_data(service_uuids, beacon);
+        self.len = data.as_bytes().len();
+        self.advertise_data[..self.len].copy_from_slice(data.as_bytes());
+    }
 
     pub async fn advertise<'a, 'server, C: Controller, const ATT: usize, const CCCD: usize, const CONN: usize>(
         &mut self,
         peripheral: &mut Peripheral<'a, C, DefaultPacketPool>,
-        server: &'server mut AttributeServer<'a, CriticalSectionRawMutex, DefaultPacketPool, ATT, CCCD, CONN>,
-    ) -> GattConnection<'a, 'server, DefaultPacketPool> {
+        server: &'server AttributeServer<'a, CriticalSectionRawMutex, DefaultPacketPool, ATT, CCCD, CONN>,
+    ) -> Result<GattConnection<'a, 'server, DefaultPacketPool>, AdvertiseError> {
 
-        let adv_params = AdvertisementParameters {
-                interval_min: embassy_time::Duration::from_millis(100),
-                interval_max: embassy_time::Duration::from_millis(200),
-                tx_power: TxPower::ZerodBm, // 0 dBm
-                ..Default::default()
-            };
+        let mut profile = self
+            .directed_peer
+            .map_or(AdvertisingProfile::FastReconnect, AdvertisingProfile::Directed);
+        let mut deadline = profile.duration().map(|duration| Instant::now() + duration);
+        info!("[advertise] Profile: {:?}", profile);
 
-        let advertise = peripheral.advertise(
-            &adv_params, 
-            advertise::Advertisement::ConnectableScannableUndirected {
-                adv_data: &self.advertise_data[..self.len],
-                scan_data: &[],
+        let conn = loop {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                profile = profile.next();
+                deadline = profile.duration().map(|duration| now + duration);
+                info!("[advertise] Profile: {:?}", profile);
             }
-        ).await;
 
-        let advertise = match advertise {
-            Ok(advertise) => advertise,
-            Err(e) => panic!("Failed to start advertising: {:?}", e),
-        };
+            // The controller stops advertising when the profile runs out
+            let mut adv_params = profile.params();
+            adv_params.timeout = deadline.map(|deadline| deadline.saturating_duration_since(now));
+
+            let advertisement = match profile {
+                AdvertisingProfile::Directed(peer) => {
+                    advertise::Advertisement::ConnectableNonscannableDirectedHighDuty { peer }
+                }
+                AdvertisingProfile::FastReconnect | AdvertisingProfile::SlowLowPower => {
+                    advertise::Advertisement::ConnectableScannableUndirected {
+                        adv_data: &self.advertise_data[..self.len],
+                        scan_data: &self.scan_data[..self.scan_len],
+                    }
+                }
+            };
 
-        let conn = advertise.accept().await;
+            let advertise = peripheral
+                .advertise(&adv_params, advertisement)
+                .await
+                .map_err(AdvertiseError::Advertise)?;
 
-        match conn {
-            Ok(conn) => {
-                match conn.with_attribute_server(server) {
-                    Ok(att_server) => att_server,
-                    Err(e) => panic!("Failed to attach attribute server: {:?}", e),
+            // The pet changed while nobody was connected: restart with the new beacon
+            match select(advertise.accept(), BEACON_UPDATES.wait()).await {
+                Either::First(Ok(conn)) => break conn,
+                // Profile ran out, the deadline check above moves to the next one
+                Either::First(Err(trouble_host::Error::Timeout)) => {}
+                Either::First(Err(e)) => return Err(AdvertiseError::Accept(e)),
+                Either::Second(beacon) => {
+                    info!("[advertise] Beacon updated: {:?}", beacon);
+                    self.set_beacon(&beacon);
                 }
-            },
-            Err(e) => panic!("Failed to accept connection: {:?}", e),
-        }
+            }
+        };
+
+        // The MTU exchange, if any, is served by the GATT event loop of the connection
+        conn.with_attribute_server(server)
+            .map_err(AdvertiseError::AttachServer)
     }
 }
 
@@ -77,6 +233,7 @@ pub async fn keep_connection_alive<'a, 'server, C: Controller>(
     conn: &GattConnection<'a, 'server, DefaultPacketPool>,
     stack: &Stack<'a, C, DefaultPacketPool>,
 ) {
+    let mut tracker = ProximityTracker::new();
     loop {
 
         let server = conn.raw();
@@ -86,6 +243,9 @@ pub async fn keep_connection_alive<'a, 'server, C: Controller>(
             match server.rssi(stack).await {
                 Ok(rssi) => {
                     info!("[connection] RSSI: {} dBm", rssi);
+                    if ProximityService::is_owner(server) {
+                        ProximityService::reading(server.peer_address(), &mut tracker, rssi);
+                    }
                 },
                 Err(e) => {
                     error!("[connection] Failed to read RSSI: {:?}", e);
@@ -1,16 +1,68 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use esp_hal::Blocking;
+use esp_hal::analog::adc::{Adc, AdcPin};
+use esp_hal::peripherals::{ADC1, GPIO35};
 use trouble_host::prelude::gatt_service;
 use trouble_host::prelude::service::BATTERY;
 use trouble_host::prelude::descriptors::{VALID_RANGE, MEASUREMENT_DESCRIPTION};
 use trouble_host::prelude::characteristic::BATTERY_LEVEL;
 
+/// Battery voltage read as empty and as full, in millivolts (single cell LiPo)
+const EMPTY_MV: u32 = 3300;
+const FULL_MV: u32 = 4200;
+
+/// Full scale of the ADC at 11 dB attenuation
+const ADC_MAX: u32 = 4095;
+const ADC_FULL_SCALE_MV: u32 = 3300;
+
+/// Last level measured, `None` until the first sample
+static LEVEL: Mutex<Cell<Option<u8>>> = Mutex::new(Cell::new(None));
 
 #[gatt_service(uuid = BATTERY)]
-struct BatteryService {
-    /// Battery Level
+pub struct BatteryService {
+    /// Battery Level, refreshed from the last sample on every read
     #[descriptor(uuid = VALID_RANGE, read, value = [0, 100])]
     #[descriptor(uuid = MEASUREMENT_DESCRIPTION, name = "hello", read, value = "Battery Level")]
-    #[characteristic(uuid = BATTERY_LEVEL, read, notify, value = 10)]
-    level: u8,
+    #[characteristic(uuid = BATTERY_LEVEL, read, notify)]
+    pub level: u8,
     #[characteristic(uuid = "408813df-5dd4-1f87-ec11-cdb001100000", write, read, notify)]
-    status: bool,
-}
\ No newline at end of file
+    pub status: bool,
+}
+
+impl BatteryService {
+    /// Stores a level measured by a [`BatterySensor`], served on the next read
+    pub fn publish(level: u8) {
+        critical_section::with(|cs| LEVEL.borrow(cs).set(Some(level)));
+    }
+
+    pub fn level() -> Option<u8> {
+        critical_section::with(|cs| LEVEL.borrow(cs).get())
+    }
+}
+
+/// Battery voltage through the ADC, turned into a charge percentage
+pub struct BatterySensor {
+    adc: Adc<'static, ADC1<'static>, Blocking>,
+    pin: AdcPin<GPIO35<'static>, ADC1<'static>>,
+}
+
+impl BatterySensor {
+    pub fn new(adc: Adc<'static, ADC1<'static>, Blocking>, pin: AdcPin<GPIO35<'static>, ADC1<'static>>) -> Self {
+        BatterySensor { adc, pin }
+    }
+
+    /// Charge in percent, linear between empty and full
+    pub fn sample(&mut self) -> u8 {
+        let raw = loop {
+            if let Ok(raw) = self.adc.read_oneshot(&mut self.pin) {
+                break raw as u32;
+            }
+        };
+        // The divider halves the battery voltage
+        let millivolts = raw * ADC_FULL_SCALE_MV / ADC_MAX * 2;
+        let percent = (millivolts.clamp(EMPTY_MV, FULL_MV) - EMPTY_MV) * 100 / (FULL_MV - EMPTY_MV);
+        percent as u8
+    }
+}
@@ -0,0 +1,78 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use tamagotchi_common::protocol::{Frame, MessageType};
+
+pub use tamagotchi_common::command::{COMMAND_MAX_LEN, Command, CommandStatus, PET_NAME_MAX};
+
+/// Size of a result payload: opcode, status and a snapshot of the pet stats
+pub const COMMAND_RESULT_LEN: usize = 2 + PET_STATS_LEN;
+
+/// Size of the pet stats snapshot carried by every command result
+pub const PET_STATS_LEN: usize = 6;
+
+/// Commands queued between the GATT event loop and the application
+pub static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, CommandRequest, 4> = Channel::new();
+
+/// A write received on the command characteristic, already parsed
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+    /// Connection the command came from, so the result goes back only to it
+    pub conn_handle: u16,
+    /// Sequence number of the request frame, echoed back in the result
+    pub sequence: u16,
+    /// Raw opcode byte, echoed back in the result even when it is unknown
+    pub opcode: u8,
+    pub command: Result<Command, CommandStatus>,
+}
+
+impl CommandRequest {
+    /// Decodes a [`MessageType::Command`] frame written to the command characteristic
+    pub fn from_frame(conn_handle: u16, data: &[u8]) -> Self {
+        match Frame::decode(data) {
+            Ok((frame, _)) if frame.message_type == MessageType::Command => CommandRequest {
+                conn_handle,
+                sequence: frame.sequence,
+                opcode: frame.payload.first().copied().unwrap_or(0),
+                command: Command::parse(frame.payload),
+            },
+            _ => CommandRequest {
+                conn_handle,
+                sequence: 0,
+                opcode: 0,
+                command: Err(CommandStatus::InvalidFrame),
+            },
+        }
+    }
+}
+
+/// Answer to a [`CommandRequest`], notified on the command result characteristic
+#[derive(Debug, Clone, Copy)]
+pub struct CommandResult {
+    pub conn_handle: u16,
+    pub sequence: u16,
+    pub opcode: u8,
+    pub status: CommandStatus,
+    pub stats: [u8; PET_STATS_LEN],
+}
+
+impl CommandResult {
+    /// Builds the answer to `request`, echoing its sequence number and opcode
+    pub fn new(request: &CommandRequest, status: CommandStatus, stats: [u8; PET_STATS_LEN]) -> Self {
+        CommandResult {
+            conn_handle: request.conn_handle,
+            sequence: request.sequence,
+            opcode: request.opcode,
+            status,
+            stats,
+        }
+    }
+
+    /// Payload of the [`MessageType::CommandResult`] frame: `[opcode, status, stats...]`
+    pub fn to_bytes(&self) -> [u8; COMMAND_RESULT_LEN] {
+        let mut buffer = [0u8; COMMAND_RESULT_LEN];
+        buffer[0] = self.opcode;
+        buffer[1] = self.status as u8;
+        buffer[2..].copy_from_slice(&self.stats);
+        buffer
+    }
+}
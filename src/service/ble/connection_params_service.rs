@@ -0,0 +1,93 @@
+use core::cell::Cell;
+
+use embassy_time::{Duration, Timer};
+use log::{info, warn};
+use tamagotchi_common::traffic::{TrafficLevel, TrafficMonitor, TrafficSample};
+use trouble_host::prelude::{ConnectParams, Controller, DefaultPacketPool, GattConnection, Stack};
+
+/// Length of one traffic sample
+const TRAFFIC_WINDOW: Duration = Duration::from_secs(2);
+
+/// Activity on one connection, counted by the tasks serving it
+#[derive(Debug, Default)]
+pub struct Traffic {
+    events: Cell<u32>,
+    transfers: Cell<u8>,
+    /// Whether a transfer ran since the last sample, even if it ended already
+    transferred: Cell<bool>,
+}
+
+impl Traffic {
+    pub const fn new() -> Self {
+        Traffic {
+            events: Cell::new(0),
+            transfers: Cell::new(0),
+            transferred: Cell::new(false),
+        }
+    }
+
+    /// A GATT read, write or notification
+    pub fn record(&self) {
+        self.events.set(self.events.get().saturating_add(1));
+    }
+
+    pub fn transfer_started(&self) {
+        self.transfers.set(self.transfers.get().saturating_add(1));
+        self.transferred.set(true);
+    }
+
+    pub fn transfer_finished(&self) {
+        self.transfers.set(self.transfers.get().saturating_sub(1));
+    }
+
+    /// Activity since the last call
+    fn take_sample(&self) -> TrafficSample {
+        let transfer = self.transferred.replace(self.transfers.get() > 0);
+        TrafficSample { events: self.events.replace(0), transfer }
+    }
+}
+
+/// Asks the phone for connection parameters matching the traffic of the link.
+///
+/// The phone has the last word: it may refuse an update or pick other values
+/// within the requested range.
+pub struct ConnectionParamsService;
+
+impl ConnectionParamsService {
+    /// Parameters requested for each level, within the limits iOS accepts
+    /// (interval × (latency + 1) ≤ 2 s, supervision timeout ≤ 6 s)
+    pub fn params(level: TrafficLevel) -> ConnectParams {
+        let (min_interval, max_interval, latency, timeout) = match level {
+            TrafficLevel::Idle => (180, 240, 4, 6_000),
+            TrafficLevel::Normal => (30, 50, 0, 4_000),
+            TrafficLevel::Busy => (15, 30, 0, 4_000),
+        };
+        ConnectParams {
+            min_connection_interval: Duration::from_millis(min_interval),
+            max_connection_interval: Duration::from_millis(max_interval),
+            max_latency: latency,
+            supervision_timeout: Duration::from_millis(timeout),
+            ..Default::default()
+        }
+    }
+
+    /// Samples `traffic` and requests new parameters whenever the level changes, until the link drops
+    pub async fn adapt<C: Controller>(
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        stack: &Stack<'_, C, DefaultPacketPool>,
+        traffic: &Traffic,
+    ) {
+        let mut monitor = TrafficMonitor::new();
+        while conn.raw().is_connected() {
+            Timer::after(TRAFFIC_WINDOW).await;
+
+            let Some(level) = monitor.update(traffic.take_sample()) else {
+                continue;
+            };
+            info!("[params] Traffic is now {:?}, requesting new connection parameters", level);
+            if let Err(e) = conn.raw().update_connection_params(stack, &Self::params(level)).await {
+                warn!("[params] Connection parameter update failed: {:?}", e);
+            }
+        }
+    }
+}
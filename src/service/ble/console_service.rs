@@ -0,0 +1,190 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::MultiwriteNorFlash;
+use heapless::{String, Vec};
+use log::{LevelFilter, info, warn};
+use tamagotchi_common::shell::{HELP, LINE_MAX, LineError, LogLevel, ShellCommand};
+use trouble_host::prelude::{DefaultPacketPool, GattConnection};
+
+use crate::pet::pet_state::PetState;
+use crate::service::ble::command_service::{Command, CommandStatus};
+use crate::service::ble::mtu_service::MtuService;
+use crate::service::ble::notification_characteristics::FRAGMENT_MAX_LEN;
+use crate::service::ble::nus_service::NusService;
+use crate::service::ble::storage_service::{self, STORAGE_RANGE, SharedStorage};
+
+/// Longest reply of one command
+pub const REPLY_MAX: usize = 256;
+
+/// Lines typed in a terminal, queued by the GATT event loop for the console task of the connection
+pub type ConsoleLines = Channel<CriticalSectionRawMutex, Result<String<LINE_MAX>, LineError>, 2>;
+
+/// Time given to the reply to leave before rebooting
+const REBOOT_DELAY: Duration = Duration::from_millis(200);
+
+/// What a command printed, and whether the device should reboot once it is sent
+#[derive(Debug, Default)]
+pub struct ConsoleReply {
+    pub text: String<REPLY_MAX>,
+    pub reboot: bool,
+}
+
+/// Debug shell behind the Nordic UART Service and the serial console
+pub struct ConsoleService;
+
+impl ConsoleService {
+    /// Runs one line from a terminal
+    pub async fn handle<S: MultiwriteNorFlash>(
+        line: Result<&str, LineError>,
+        pet: &RefCell<PetState>,
+        storage: &SharedStorage<S>,
+    ) -> ConsoleReply {
+        let mut reply = ConsoleReply::default();
+        let written = match line.map(ShellCommand::parse) {
+            Ok(Ok(command)) => Self::execute(command, pet, storage, &mut reply).await,
+            Ok(Err(e)) => write!(reply.text, "error: {}", e.message()),
+            Err(LineError::TooLong) => write!(reply.text, "error: line longer than {} bytes", LINE_MAX),
+            Err(LineError::InvalidUtf8) => write!(reply.text, "error: invalid UTF-8"),
+        };
+        if written.is_err() {
+            warn!("[console] Reply truncated to {} bytes", REPLY_MAX);
+        }
+        reply
+    }
+
+    async fn execute<S: MultiwriteNorFlash>(
+        command: ShellCommand,
+        pet: &RefCell<PetState>,
+        storage: &SharedStorage<S>,
+        reply: &mut ConsoleReply,
+    ) -> core::fmt::Result {
+        info!("[console] {:?}", command);
+        let out = &mut reply.text;
+        match command {
+            ShellCommand::Help => write!(out, "{}", HELP),
+            ShellCommand::Status => {
+                let pet = pet.borrow();
+                write!(
+                    out,
+                    "{} {:?}: {:?}, age {}, food {}, joy {}, hygiene {}, health {}",
+                    pet.name,
+                    pet.stage(),
+                    pet.status(),
+                    pet.age,
+                    pet.fullness,
+                    pet.happiness,
+                    pet.hygiene,
+                    pet.health
+                )
+            }
+            ShellCommand::Feed => {
+                let status = pet.borrow_mut().apply(&Command::Feed);
+                match status {
+                    CommandStatus::Ok => write!(out, "fed, food {}", pet.borrow().fullness),
+                    status => write!(out, "not fed: {:?}", status),
+                }
+            }
+            ShellCommand::BondsList => {
+                let bonds = storage_service::list_bonds(&mut *storage.lock().await).await;
+                match bonds {
+                    Ok(bonds) => {
+                        write!(out, "{} bond(s)", bonds.len())?;
+                        for bond in bonds {
+                            out.push('\n').map_err(|_| core::fmt::Error)?;
+                            Self::write_address(out, bond.raw())?;
+                        }
+                        Ok(())
+                    }
+                    Err(e) => write!(out, "error: {:?}", e),
+                }
+            }
+            ShellCommand::StorageStats => {
+                let stats = storage_service::storage_stats(&mut *storage.lock().await).await;
+                match stats {
+                    Ok(stats) => write!(
+                        out,
+                        "{} bond(s), {} friendship(s), {} other item(s) in {} bytes",
+                        stats.bonds,
+                        stats.friendships,
+                        stats.others,
+                        STORAGE_RANGE.end - STORAGE_RANGE.start
+                    ),
+                    Err(e) => write!(out, "error: {:?}", e),
+                }
+            }
+            ShellCommand::LogLevel(None) => write!(out, "log level {}", Self::log_level().name()),
+            ShellCommand::LogLevel(Some(level)) => {
+                log::set_max_level(Self::level_filter(level));
+                write!(out, "log level set to {}", level.name())
+            }
+            ShellCommand::Reboot => {
+                reply.reboot = true;
+                write!(out, "rebooting...")
+            }
+        }
+    }
+
+    /// Sends `reply` as `tx` notifications, one line ending appended
+    pub async fn send(
+        nus: &NusService,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        reply: &ConsoleReply,
+    ) -> Result<(), trouble_host::Error> {
+        let chunk_len = MtuService::notification_payload_len(conn);
+        let text = reply.text.as_bytes().iter().chain(b"\r\n");
+
+        let mut chunk: Vec<u8, FRAGMENT_MAX_LEN> = Vec::new();
+        for &byte in text {
+            if chunk.len() == chunk_len {
+                nus.tx.notify(conn, &chunk).await?;
+                chunk.clear();
+            }
+            let _ = chunk.push(byte);
+        }
+        nus.tx.notify(conn, &chunk).await
+    }
+
+    /// Restarts the device once the reply had time to leave
+    pub async fn reboot() -> ! {
+        info!("[console] Rebooting");
+        Timer::after(REBOOT_DELAY).await;
+        esp_hal::system::software_reset()
+    }
+
+    fn log_level() -> LogLevel {
+        match log::max_level() {
+            LevelFilter::Off => LogLevel::Off,
+            LevelFilter::Error => LogLevel::Error,
+            LevelFilter::Warn => LogLevel::Warn,
+            LevelFilter::Info => LogLevel::Info,
+            LevelFilter::Debug => LogLevel::Debug,
+            LevelFilter::Trace => LogLevel::Trace,
+        }
+    }
+
+    fn level_filter(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+
+    /// Most significant byte first, as phones show it
+    fn write_address(out: &mut String<REPLY_MAX>, raw: &[u8]) -> core::fmt::Result {
+        for (i, byte) in raw.iter().rev().enumerate() {
+            if i > 0 {
+                out.push(':').map_err(|_| core::fmt::Error)?;
+            }
+            write!(out, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+use trouble_host::prelude::gatt_service;
+use trouble_host::prelude::service::DEVICE_INFORMATION;
+use trouble_host::prelude::characteristic::{
+    FIRMWARE_REVISION_STRING, MANUFACTURER_NAME_STRING, MODEL_NUMBER_STRING,
+};
+
+pub const MANUFACTURER: &str = "Tamagotchi DIY";
+pub const MODEL: &str = "ESP32 Tamagotchi";
+pub const FIRMWARE_REVISION: &str = env!("CARGO_PKG_VERSION");
+
+/// Device Information Service, read-only strings identifying the board and its firmware
+#[gatt_service(uuid = DEVICE_INFORMATION)]
+pub struct DeviceInfoService {
+    #[characteristic(uuid = MANUFACTURER_NAME_STRING, read, value = MANUFACTURER)]
+    pub manufacturer: &'static str,
+    #[characteristic(uuid = MODEL_NUMBER_STRING, read, value = MODEL)]
+    pub model: &'static str,
+    #[characteristic(uuid = FIRMWARE_REVISION_STRING, read, value = FIRMWARE_REVISION)]
+    pub firmware_revision: &'static str,
+}
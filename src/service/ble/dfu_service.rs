@@ -0,0 +1,17 @@
+use heapless::Vec;
+use tamagotchi_common::dfu::{DFU_CONTROL_MAX_LEN, DFU_DATA_MAX_LEN};
+use trouble_host::prelude::gatt_service;
+
+/// Firmware update service, see [`tamagotchi_common::dfu`] for the messages.
+///
+/// The image goes to the inactive OTA partition; the device reboots into it
+/// once it is verified.
+#[gatt_service(uuid = "12345678-1234-5678-1234-56789abcdf00")]
+pub struct DfuService {
+    /// Start, finish and abort from the phone, their results and refused chunks back
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdf01", write, notify)]
+    pub control: Vec<u8, DFU_CONTROL_MAX_LEN>,
+    /// Offset and bytes of the image
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdf02", write, write_without_response)]
+    pub data: Vec<u8, DFU_DATA_MAX_LEN>,
+}
@@ -0,0 +1,133 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use heapless::Vec;
+use trouble_host::prelude::BdAddr;
+
+// HCI error codes reported as disconnect reasons (Core spec Vol 1, Part F)
+const AUTHENTICATION_FAILURE: u8 = 0x05;
+const PIN_OR_KEY_MISSING: u8 = 0x06;
+const CONNECTION_TIMEOUT: u8 = 0x08;
+const REMOTE_USER_TERMINATED: u8 = 0x13;
+const REMOTE_LOW_RESOURCES: u8 = 0x14;
+const REMOTE_POWER_OFF: u8 = 0x15;
+const LOCAL_HOST_TERMINATED: u8 = 0x16;
+const LL_RESPONSE_TIMEOUT: u8 = 0x22;
+const MIC_FAILURE: u8 = 0x3D;
+
+/// Peers whose disconnect statistics are kept, the oldest is dropped when full
+const PEER_STATS_MAX: usize = 8;
+
+static PEER_STATS: Mutex<RefCell<Vec<(BdAddr, PeerStats), PEER_STATS_MAX>>> = Mutex::new(RefCell::new(Vec::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectKind {
+    /// The phone or this device closed the connection on purpose
+    UserInitiated,
+    /// The link dropped: out of range, interference or a phone that went silent
+    SupervisionTimeout,
+    /// A packet failed its integrity check, the keys on both sides don't match
+    MicFailure,
+    /// The phone no longer has (or never had) the keys we stored
+    KeyMissing,
+    /// The phone is powering off, rebooting or out of resources
+    RemoteReset,
+    Other(u8),
+}
+
+impl DisconnectKind {
+    pub fn from_reason(reason: u8) -> Self {
+        match reason {
+            REMOTE_USER_TERMINATED | LOCAL_HOST_TERMINATED => DisconnectKind::UserInitiated,
+            CONNECTION_TIMEOUT | LL_RESPONSE_TIMEOUT => DisconnectKind::SupervisionTimeout,
+            MIC_FAILURE => DisconnectKind::MicFailure,
+            PIN_OR_KEY_MISSING | AUTHENTICATION_FAILURE => DisconnectKind::KeyMissing,
+            REMOTE_POWER_OFF | REMOTE_LOW_RESOURCES => DisconnectKind::RemoteReset,
+            other => DisconnectKind::Other(other),
+        }
+    }
+
+    /// The stored bond can't be used with this peer anymore
+    pub fn invalidates_bond(&self) -> bool {
+        matches!(self, DisconnectKind::MicFailure | DisconnectKind::KeyMissing)
+    }
+
+    /// The peer is likely still nearby and trying to come back
+    pub fn expects_reconnect(&self) -> bool {
+        matches!(self, DisconnectKind::SupervisionTimeout)
+    }
+}
+
+/// Disconnect counters of one peer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    pub user_initiated: u16,
+    pub timeouts: u16,
+    pub mic_failures: u16,
+    pub key_missing: u16,
+    pub remote_resets: u16,
+    pub other: u16,
+    pub last: Option<DisconnectKind>,
+}
+
+impl PeerStats {
+    pub fn total(&self) -> u16 {
+        self.user_initiated
+            .saturating_add(self.timeouts)
+            .saturating_add(self.mic_failures)
+            .saturating_add(self.key_missing)
+            .saturating_add(self.remote_resets)
+            .saturating_add(self.other)
+    }
+
+    fn record(&mut self, kind: DisconnectKind) {
+        let counter = match kind {
+            DisconnectKind::UserInitiated => &mut self.user_initiated,
+            DisconnectKind::SupervisionTimeout => &mut self.timeouts,
+            DisconnectKind::MicFailure => &mut self.mic_failures,
+            DisconnectKind::KeyMissing => &mut self.key_missing,
+            DisconnectKind::RemoteReset => &mut self.remote_resets,
+            DisconnectKind::Other(_) => &mut self.other,
+        };
+        *counter = counter.saturating_add(1);
+        self.last = Some(kind);
+    }
+}
+
+/// Per-peer disconnect statistics, kept in RAM for diagnostics
+pub struct DisconnectStats;
+
+impl DisconnectStats {
+    /// Counts a disconnect of `peer` and returns its updated statistics
+    pub fn record(peer: BdAddr, kind: DisconnectKind) -> PeerStats {
+        critical_section::with(|cs| {
+            let mut peers = PEER_STATS.borrow_ref_mut(cs);
+
+            let index = match peers.iter().position(|(addr, _)| *addr == peer) {
+                Some(index) => index,
+                None => {
+                    if peers.is_full() {
+                        peers.remove(0);
+                    }
+                    // Can't fail, a slot was just freed
+                    let _ = peers.push((peer, PeerStats::default()));
+                    peers.len() - 1
+                }
+            };
+
+            let stats = &mut peers[index].1;
+            stats.record(kind);
+            *stats
+        })
+    }
+
+    pub fn get(peer: &BdAddr) -> Option<PeerStats> {
+        critical_section::with(|cs| {
+            PEER_STATS
+                .borrow_ref(cs)
+                .iter()
+                .find(|(addr, _)| addr == peer)
+                .map(|(_, stats)| *stats)
+        })
+    }
+}
@@ -0,0 +1,103 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use trouble_host::prelude::{AttributeServer, AttributeTable, DefaultPacketPool};
+
+#[cfg(feature = "battery")]
+use crate::service::ble::battery_service::BatteryService;
+#[cfg(feature = "device-info")]
+use crate::service::ble::device_info_service::DeviceInfoService;
+use crate::service::ble::dfu_service::DfuService;
+#[cfg(feature = "hid")]
+use crate::service::ble::hid_service::HidService;
+use crate::service::ble::notification_characteristics::NotificationCharacteristics;
+use crate::service::ble::notification_service::CONNECTIONS_MAX;
+use crate::service::ble::nus_service::NusService;
+
+// Attributes and CCCDs of each optional service, zero when its feature is off
+#[cfg(feature = "battery")]
+const BATTERY: (usize, usize) = (BatteryService::ATTRIBUTE_COUNT, BatteryService::CCCD_COUNT);
+#[cfg(not(feature = "battery"))]
+const BATTERY: (usize, usize) = (0, 0);
+
+#[cfg(feature = "hid")]
+const HID: (usize, usize) = (HidService::ATTRIBUTE_COUNT, HidService::CCCD_COUNT);
+#[cfg(not(feature = "hid"))]
+const HID: (usize, usize) = (0, 0);
+
+#[cfg(feature = "device-info")]
+const DEVICE_INFO: (usize, usize) = (DeviceInfoService::ATTRIBUTE_COUNT, DeviceInfoService::CCCD_COUNT);
+#[cfg(not(feature = "device-info"))]
+const DEVICE_INFO: (usize, usize) = (0, 0);
+
+/// Attributes registered by every enabled service
+pub const ATTRIBUTE_TABLE_SIZE: usize =
+    NotificationCharacteristics::ATTRIBUTE_COUNT
+    + NusService::ATTRIBUTE_COUNT
+    + DfuService::ATTRIBUTE_COUNT
+    + BATTERY.0
+    + HID.0
+    + DEVICE_INFO.0;
+
+/// Characteristics a phone can subscribe to, tracked per connection
+pub const CCCD_TABLE_SIZE: usize =
+    NotificationCharacteristics::CCCD_COUNT
+    + NusService::CCCD_COUNT
+    + DfuService::CCCD_COUNT
+    + BATTERY.1
+    + HID.1
+    + DEVICE_INFO.1;
+
+pub type Server<'v> =
+    AttributeServer<'v, CriticalSectionRawMutex, DefaultPacketPool, ATTRIBUTE_TABLE_SIZE, CCCD_TABLE_SIZE, CONNECTIONS_MAX>;
+
+/// The GATT server of the pet: one attribute table holding every service.
+///
+/// The pet service, the UART console and firmware updates are always present; battery, HID and
+/// device information follow the `battery`, `hid` and `device-info` cargo features.
+pub struct TamagotchiServer<'v> {
+    pub server: Server<'v>,
+    pub notifications: NotificationCharacteristics,
+    pub nus: NusService,
+    pub dfu: DfuService,
+    #[cfg(feature = "battery")]
+    pub battery: BatteryService,
+    #[cfg(feature = "hid")]
+    pub hid: HidService,
+    #[cfg(feature = "device-info")]
+    pub device_info: DeviceInfoService,
+}
+
+impl<'v> TamagotchiServer<'v> {
+    pub fn new() -> Self {
+        let mut table: AttributeTable<'v, CriticalSectionRawMutex, ATTRIBUTE_TABLE_SIZE> = AttributeTable::new();
+
+        // Registration order defines the handles, keep the pet service first
+        let notifications = NotificationCharacteristics::new(&mut table);
+        let nus = NusService::new(&mut table);
+        let dfu = DfuService::new(&mut table);
+        #[cfg(feature = "battery")]
+        let battery = BatteryService::new(&mut table);
+        #[cfg(feature = "hid")]
+        let hid = HidService::new(&mut table);
+        #[cfg(feature = "device-info")]
+        let device_info = DeviceInfoService::new(&mut table);
+
+        TamagotchiServer {
+            server: AttributeServer::new(table),
+            notifications,
+            nus,
+            dfu,
+            #[cfg(feature = "battery")]
+            battery,
+            #[cfg(feature = "hid")]
+            hid,
+            #[cfg(feature = "device-info")]
+            device_info,
+        }
+    }
+}
+
+impl Default for TamagotchiServer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
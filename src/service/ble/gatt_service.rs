@@ -1,32 +1,189 @@
+use core::cell::{Cell, RefCell};
+
 use embedded_storage_async::nor_flash::MultiwriteNorFlash;
-use log::{error, info};
-use sequential_storage::{cache::NoCache, map::MapStorage};
-use trouble_host::{BondInformation, gatt::{GattConnection, GattConnectionEvent, GattEvent, ReadEvent, WriteEvent}, prelude::{DefaultPacketPool, SecurityLevel}};
+use log::{error, info, warn};
+use trouble_host::{Address, BondInformation, gatt::{GattConnection, GattConnectionEvent, GattEvent, ReadEvent, Reply, WriteEvent}, prelude::{AttErrorCode, BdAddr, DefaultPacketPool, SecurityLevel}};
+
+use tamagotchi_common::dfu::DfuControl;
+use tamagotchi_common::protocol::{FragmentError, Reassembler};
+use tamagotchi_common::shell::{LINE_MAX, LineBuffer, LineError};
 
-use crate::service::ble::storage_service::{self, StorageAddr};
+use crate::service::ble::address_service::AddressService;
+use crate::service::ble::command_service::{COMMAND_CHANNEL, CommandRequest, CommandStatus};
+use crate::service::ble::connection_params_service::Traffic;
+use crate::service::ble::console_service::ConsoleLines;
+use crate::service::ble::disconnect_service::{DisconnectKind, DisconnectStats};
+use crate::service::ble::mtu_service::MtuService;
+use crate::service::ble::ota_service::{DfuRequest, DfuRequests};
+use crate::service::ble::notification_characteristics::{ATT_HEADER_LEN, COMMAND_FRAME_LEN, DEFAULT_ATT_MTU};
+use crate::service::ble::pairing_service::{PairingPrompt, PairingPrompts};
+use crate::service::ble::read_service::ReadRegistry;
+use crate::service::ble::subscription_service::Subscriptions;
+use crate::service::ble::security_policy::{Operation, SecurityPolicy};
+use crate::service::ble::storage_service::{self, SharedStorage};
+use crate::service::ble::visit_service::VisitService;
 
 
-pub struct GattService {
+pub struct GattService<'a> {
+    command_handle: Option<u16>,
+    visit_handle: Option<u16>,
+    command_reassembler: RefCell<Reassembler<COMMAND_FRAME_LEN>>,
+    /// Console RX handle and the queue its lines go to
+    console: Option<(u16, &'a ConsoleLines)>,
+    console_buffer: RefCell<LineBuffer<LINE_MAX>>,
+    /// DFU control and data handles and the queue their writes go to
+    dfu: Option<(u16, u16, &'a DfuRequests)>,
+    /// Pairing steps shown to the user by the pairing task of the connection
+    pairing_prompts: Option<&'a PairingPrompts>,
+    /// Effective ATT MTU of the connection served by this instance
+    mtu: Cell<u16>,
+    /// Security level reached by the connection served by this instance
+    security_level: Cell<SecurityLevel>,
+    /// Whether the peer of this connection is bonded with us
+    bonded: Cell<bool>,
+    /// Identity address of the peer once it bonded, its connection address may be private
+    identity: Cell<Option<Address>>,
+    security_policy: SecurityPolicy,
+    /// Computes values of dynamic characteristics when they are read
+    read_registry: Option<&'a ReadRegistry<'a>>,
+    /// CCCD state of the connection, updated on every CCCD write
+    subscriptions: Option<&'a Subscriptions>,
+    /// Activity of the connection, drives its connection parameters
+    traffic: Option<&'a Traffic>,
 }
 
-impl GattService {
+impl<'a> GattService<'a> {
     pub fn new() -> Self {
-        GattService {}
+        GattService {
+            command_handle: None,
+            visit_handle: None,
+            command_reassembler: RefCell::new(Reassembler::new()),
+            console: None,
+            console_buffer: RefCell::new(LineBuffer::new()),
+            dfu: None,
+            pairing_prompts: None,
+            mtu: Cell::new(DEFAULT_ATT_MTU as u16),
+            security_level: Cell::new(SecurityLevel::NoEncryption),
+            bonded: Cell::new(false),
+            identity: Cell::new(None),
+            security_policy: SecurityPolicy::new(),
+            read_registry: None,
+            subscriptions: None,
+            traffic: None,
+        }
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level.get()
+    }
+
+    pub fn mtu(&self) -> u16 {
+        self.mtu.get()
+    }
+
+    /// Address the peer bonded under, or `peer`, the one it connected from, when it didn't bond
+    pub fn peer_identity(&self, peer: Address) -> Address {
+        self.identity.get().unwrap_or(peer)
+    }
+
+    /// The phone may exchange the MTU at any point of the connection, checked after every event
+    fn track_mtu(&self, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+        let mtu = MtuService::effective_mtu(conn);
+        if self.mtu.replace(mtu) != mtu {
+            info!("[gatt] ATT MTU is now {}", mtu);
+        }
+    }
+
+    /// Routes writes on `handle` to the application as commands
+    pub fn with_command_handle(mut self, handle: u16) -> Self {
+        self.command_handle = Some(handle);
+        self
+    }
+
+    /// Hands visits written on `handle` by other pets to the application
+    pub fn with_visit_handle(mut self, handle: u16) -> Self {
+        self.visit_handle = Some(handle);
+        self
+    }
+
+    /// Refreshes dynamic characteristic values from `registry` before reads are answered
+    pub fn with_read_registry(mut self, registry: &'a ReadRegistry<'a>) -> Self {
+        self.read_registry = Some(registry);
+        self
+    }
+
+    /// Keeps `subscriptions` in sync with the CCCD writes of the connection
+    pub fn with_subscriptions(mut self, subscriptions: &'a Subscriptions) -> Self {
+        self.subscriptions = Some(subscriptions);
+        self
+    }
+
+    /// Counts every GATT request of the connection in `traffic`
+    pub fn with_traffic(mut self, traffic: &'a Traffic) -> Self {
+        self.traffic = Some(traffic);
+        self
+    }
+
+    /// Collects the lines written on the console RX `handle` into `lines`
+    pub fn with_console(mut self, handle: u16, lines: &'a ConsoleLines) -> Self {
+        self.console = Some((handle, lines));
+        self
     }
 
-    pub fn handle_disconect_event(&self) {
-        // Handle disconnection event
+    /// Queues the writes on the DFU `control` and `data` handles into `requests`
+    pub fn with_dfu(mut self, control: u16, data: u16, requests: &'a DfuRequests) -> Self {
+        self.dfu = Some((control, data, requests));
+        self
+    }
+
+    /// Hands passkeys and pairing results to `prompts`, so the user is asked outside the event loop
+    pub fn with_pairing_prompts(mut self, prompts: &'a PairingPrompts) -> Self {
+        self.pairing_prompts = Some(prompts);
+        self
+    }
+
+    /// Security requirements enforced on every read and write
+    pub fn with_security_policy(mut self, policy: SecurityPolicy) -> Self {
+        self.security_policy = policy;
+        self
+    }
+
+    /// Classifies the disconnect, records it and drops the bond when the keys stopped working
+    pub async fn handle_disconect_event<S: MultiwriteNorFlash>(
+        &self,
+        reason: u8,
+        peer: BdAddr,
+        storage: &SharedStorage<S>,
+        bond_stored: &Cell<bool>,
+    ) -> DisconnectKind {
+        let kind = DisconnectKind::from_reason(reason);
+        let stats = DisconnectStats::record(peer, kind);
+        info!("[gatt] {:?} disconnected: {:?} (reason {:#04x}), {} disconnects so far", peer, kind, reason, stats.total());
+
+        if kind.invalidates_bond() {
+            warn!("[gatt] Keys rejected by {:?}, removing bond", peer);
+            let mut storage = storage.lock().await;
+            match storage_service::remove_bonding_info(&mut storage, &peer).await {
+                Ok(()) => bond_stored.set(false),
+                Err(e) => error!("[gatt] Failed to remove bonding information: {:?}", e),
+            }
+        }
+        kind
     }
 
     pub async fn handle_paring_complete_event<S: MultiwriteNorFlash>(
         &self, 
         security_level: SecurityLevel, 
         bond: BondInformation, 
-        storage: &mut MapStorage<StorageAddr, S, NoCache>,
+        storage: &SharedStorage<S>,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
     ) -> bool {
         info!("[gatt] pairing complete: {:?}", security_level);
 
-        match storage_service::store_bonding_info(storage, &bond).await {
+        let kind = AddressService::identity_kind(bond.identity.bd_addr, conn.raw().peer_address());
+        self.identity.set(Some(Address { kind, addr: bond.identity.bd_addr }));
+        let mut storage = storage.lock().await;
+        match storage_service::store_bonding_info(&mut storage, &bond, kind).await {
             Ok(_) => {
                 info!("[gatt] Bonding information stored successfully");
                 true
@@ -42,17 +199,36 @@ impl GattService {
         error!("[gatt] pairing failed {:?}", err);
     }
 
+    /// Queues `prompt` for the pairing task; a comparison nobody can answer is rejected
+    fn prompt(&self, prompt: PairingPrompt, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+        let queued = self.pairing_prompts.is_some_and(|prompts| prompts.try_send(prompt).is_ok());
+        if queued {
+            return;
+        }
+        warn!("[gatt] Pairing prompt dropped: {:?}", prompt);
+        let result = match prompt {
+            PairingPrompt::Confirm(_) => conn.raw().pass_key_cancel(),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("[gatt] Failed to cancel numeric comparison: {:?}", e);
+        }
+    }
+
     pub fn handle_gatt_event<'stack, 'server>(
         &self, 
         gatt_event: GattEvent<'stack, 'server, DefaultPacketPool>, 
         conn: &GattConnection<'_, '_, DefaultPacketPool>
     ) {
+        if let Some(traffic) = self.traffic {
+            traffic.record();
+        }
         match gatt_event {
             GattEvent::Write(event) => {
                 self.gatt_write_handler(event, conn);
             },
             GattEvent::Read(event) =>{
-                self.gatt_read_handler(event);
+                self.gatt_read_handler(event, conn);
             }
             _ => {}
         }
@@ -63,37 +239,172 @@ impl GattService {
         event: WriteEvent<'stack, 'server, DefaultPacketPool>,
             conn: &GattConnection<'_, '_, DefaultPacketPool>
     ) {
-        match conn.raw().security_level() {
-            core::prelude::v1::Ok(SecurityLevel::NoEncryption) => {
-                error!("[gatt] Write operation rejected: Connection is not encrypted");
-            },
-            core::prelude::v1::Ok(SecurityLevel::Encrypted) | core::prelude::v1::Ok(SecurityLevel::EncryptedAuthenticated) => {
-                info!("[gatt] Write operation accepted");
-                let value = event.payload().handle();
-                
-                info!("[gatt] Written data: {:?}", value);
-
-                match event.accept() {
-                    core::prelude::v1::Ok(data) => {                        
-                        let _ = data.try_send();
-                    },
-                    core::prelude::v1::Err(e) => {
-                        error!("[gatt] Failed to accept write: {:?}", e);
-                    }
+        let handle = event.handle();
+        if let Err(code) = self.check_access(handle, Operation::Write, conn) {
+            warn!("[gatt] Write on handle {} rejected: {:?}", handle, code);
+            Self::send_reply(event.reject(code));
+            return;
+        }
+
+        // Image chunks are too many and too large to log
+        if let Some((_, _, requests)) = self.dfu.filter(|(_, data, _)| *data == handle) {
+            let reply = match Self::queue_dfu_chunk(event.data(), requests) {
+                Ok(()) => event.accept(),
+                Err(code) => event.reject(code),
+            };
+            Self::send_reply(reply);
+            return;
+        }
+
+        info!("[gatt] Write on handle {}: {:?}", handle, event.data());
+        if self.command_handle == Some(handle) {
+            // The pet task can't take another command: refuse the write instead of losing it
+            if COMMAND_CHANNEL.is_full() {
+                error!("[gatt] Command queue full, refusing command");
+                Self::send_reply(event.reject(AttErrorCode::INSUFFICIENT_RESOURCES));
+                return;
+            }
+            // Answer the write before the command is looked at, the phone doesn't wait for it
+            let mut reassembler = self.command_reassembler.borrow_mut();
+            let fragment = reassembler.push(event.data());
+            Self::send_reply(event.accept());
+            Self::dispatch_command(conn.raw().handle().raw(), fragment);
+            return;
+        }
+        if let Some(subscriptions) = self.subscriptions {
+            subscriptions.on_write(handle, event.data());
+        }
+        if let Some((_, lines)) = self.console.filter(|(console_handle, _)| *console_handle == handle) {
+            self.collect_console_lines(event.data(), lines);
+        }
+        if let Some((_, _, requests)) = self.dfu.filter(|(control, _, _)| *control == handle) {
+            let Some(control) = DfuControl::from_bytes(event.data()) else {
+                Self::send_reply(event.reject(AttErrorCode::VALUE_NOT_ALLOWED));
+                return;
+            };
+            if requests.try_send(DfuRequest::Control(control)).is_err() {
+                error!("[gatt] DFU queue full, dropping {:?}", control.opcode());
+                Self::send_reply(event.reject(AttErrorCode::INSUFFICIENT_RESOURCES));
+                return;
+            }
+        }
+        if self.visit_handle == Some(handle) {
+            match VisitService::parse(event.data()) {
+                Some(visit) if VisitService::received(visit) => {}
+                Some(_) => {
+                    Self::send_reply(event.reject(AttErrorCode::INSUFFICIENT_RESOURCES));
+                    return;
+                }
+                None => {
+                    Self::send_reply(event.reject(AttErrorCode::VALUE_NOT_ALLOWED));
+                    return;
                 }
+            }
+        }
+
+        Self::send_reply(event.accept());
+    }
+
+    /// Checks the policy of `handle` against the current state of the link
+    fn check_access(
+        &self,
+        handle: u16,
+        operation: Operation,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    ) -> Result<(), trouble_host::prelude::AttErrorCode> {
+        // The link can be encrypted with a stored key without a new pairing event
+        let level = conn.raw().security_level().unwrap_or(SecurityLevel::NoEncryption);
+        self.security_policy.check(handle, operation, level, self.bonded.get())
+    }
 
+    fn send_reply(reply: Result<Reply<'_, DefaultPacketPool>, trouble_host::Error>) {
+        match reply {
+            Ok(reply) => {
+                let _ = reply.try_send();
             },
-            core::prelude::v1::Err(e) => {
-                error!("[gatt] Write operation rejected: Failed to get security level: {:?}", e);
+            Err(e) => {
+                error!("[gatt] Failed to build reply: {:?}", e);
             }
-        }      
+        }
     }
 
+    /// Queues the command once `fragment` completed its frame
+    fn dispatch_command(conn_handle: u16, fragment: Result<Option<&[u8]>, FragmentError>) {
+        let request = match fragment {
+            Ok(Some(frame)) => CommandRequest::from_frame(conn_handle, frame),
+            // Waiting for the remaining fragments
+            Ok(None) => return,
+            Err(e) => {
+                error!("[gatt] Invalid command fragment: {:?}", e);
+                CommandRequest {
+                    conn_handle,
+                    sequence: 0,
+                    opcode: 0,
+                    command: Err(CommandStatus::InvalidFrame),
+                }
+            }
+        };
+        info!("[gatt] Command received: seq {} opcode {:#04x}", request.sequence, request.opcode);
+
+        if COMMAND_CHANNEL.try_send(request).is_err() {
+            error!("[gatt] Command queue full, dropping command");
+        }
+    }
 
-    fn gatt_read_handler<'stack, 'server>(&self, event: ReadEvent<'stack, 'server, DefaultPacketPool>) {
-        info!("[gatt] Read request received on handle: {:?}", event.payload().handle());
-        // Você pode inspecionar qual característica está sendo lida
-        // e responder de acordo
+    /// A chunk the update task can't take yet is refused; sent without response, it is lost
+    /// and the next chunk gets an out of order notification with the offset to resume from
+    fn queue_dfu_chunk(data: &[u8], requests: &DfuRequests) -> Result<(), AttErrorCode> {
+        let chunk = data.try_into().map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        requests.try_send(DfuRequest::Chunk(chunk)).map_err(|_| {
+            warn!("[gatt] DFU queue full, refusing chunk");
+            AttErrorCode::INSUFFICIENT_RESOURCES
+        })
+    }
+
+    /// Splits a console write in lines; a short write without a line ending is a whole line,
+    /// since many terminal apps send none
+    fn collect_console_lines(&self, data: &[u8], lines: &ConsoleLines) {
+        let mut buffer = self.console_buffer.borrow_mut();
+        let queue = |line: Result<&str, LineError>| {
+            let line = line.map(|line| line.try_into().unwrap_or_default());
+            if lines.try_send(line).is_err() {
+                error!("[gatt] Console queue full, dropping line");
+            }
+        };
+
+        for &byte in data {
+            if let Some(line) = buffer.push(byte) {
+                queue(line);
+            }
+        }
+        let max_len = self.mtu() as usize - ATT_HEADER_LEN;
+        if data.len() < max_len {
+            if let Some(line) = buffer.finish() {
+                queue(line);
+            }
+        }
+    }
+
+    fn gatt_read_handler<'stack, 'server>(
+        &self,
+        event: ReadEvent<'stack, 'server, DefaultPacketPool>,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    ) {
+        let handle = event.handle();
+        info!("[gatt] Read request received on handle: {:?}", handle);
+
+        match self.check_access(handle, Operation::Read, conn) {
+            Ok(()) => {
+                if let Some(registry) = self.read_registry {
+                    registry.refresh(handle);
+                }
+                Self::send_reply(event.accept());
+            }
+            Err(code) => {
+                warn!("[gatt] Read on handle {} rejected: {:?}", handle, code);
+                Self::send_reply(event.reject(code));
+            }
+        }
     }
 
     /// Envia uma notificação de exemplo através do Battery Service
@@ -107,31 +418,56 @@ impl GattService {
 
     pub async fn handle_gatt_events<S: MultiwriteNorFlash>(
         &self,
-        storage: &mut MapStorage<StorageAddr, S, NoCache>,
+        storage: &SharedStorage<S>,
         //server: &Connection<'_, DefaultPacketPool>,
         conn: &GattConnection<'_, '_, DefaultPacketPool>,
-        bond_stored: &mut bool,
-    ) {
+        bond_stored: &Cell<bool>,
+    ) -> DisconnectKind {
+        // The connection is gone by the time the disconnect arrives
+        let peer = conn.raw().peer_address();
+
         let reason = loop {
-            match conn.next().await{
+            let event = conn.next().await;
+            self.track_mtu(conn);
+
+            match event {
                 GattConnectionEvent::Disconnected {reason} => { break reason }
                 GattConnectionEvent::PairingComplete {
                     security_level, 
                     bond
                 } => {
                     info!("[gatt] pairing complete: {:?}", security_level);
+                    self.security_level.set(security_level);
+                    self.prompt(PairingPrompt::Finished(true), conn);
 
                     if let Some(bond) = bond {
-                        
-                        *bond_stored = self.handle_paring_complete_event(
+                        self.bonded.set(true);
+                        bond_stored.set(self.handle_paring_complete_event(
                             security_level, 
                             bond,
-                            storage
-                        ).await;
+                            storage,
+                            conn
+                        ).await);
+                    } else {
+                        // Encrypted without a new bond: either a stored key was reused or pairing wasn't bondable
+                        let peer = self.peer_identity(peer);
+                        let mut storage = storage.lock().await;
+                        let known = storage_service::load_bonding_info(&mut storage, &peer.addr).await;
+                        self.bonded.set(matches!(known, Ok(Some(_))));
+                        if self.bonded.get() {
+                            self.identity.set(Some(peer));
+                        }
                     }
                 },
                 GattConnectionEvent::PairingFailed(err) => {
                     self.handle_paring_failed_event(err);
+                    self.prompt(PairingPrompt::Finished(false), conn);
+                },
+                GattConnectionEvent::PassKeyDisplay(passkey) => {
+                    self.prompt(PairingPrompt::Display(passkey.value()), conn);
+                },
+                GattConnectionEvent::PassKeyConfirm(passkey) => {
+                    self.prompt(PairingPrompt::Confirm(passkey.value()), conn);
                 },
                 GattConnectionEvent::Gatt { event } => {
                     self.handle_gatt_event(event, conn);
@@ -141,6 +477,8 @@ impl GattService {
                 }
             }
         };
-        info!("[gatt] disconnected: {:?}", reason);
+        // Bonds are stored under the identity, not a private address the phone rotates
+        let peer = self.peer_identity(peer).addr;
+        self.handle_disconect_event(reason.into_inner(), peer, storage, bond_stored).await
     }
 }
\ No newline at end of file
@@ -9,19 +9,19 @@ static DESC: [u8; 67] = [
 ];
 
 #[gatt_service(uuid = HUMAN_INTERFACE_DEVICE)]
-pub(crate) struct HidService {
+pub struct HidService {
     #[characteristic(uuid = "2a4a", read, value = [0x01, 0x01, 0x00, 0x03])]
-    pub(crate) hid_info: [u8; 4],
+    pub hid_info: [u8; 4],
     #[characteristic(uuid = "2a4b", read, value = DESC)]
-    pub(crate) report_map: [u8; 67],
+    pub report_map: [u8; 67],
     #[characteristic(uuid = "2a4c", write_without_response)]
-    pub(crate) hid_control_point: u8,
+    pub hid_control_point: u8,
     #[characteristic(uuid = "2a4e", read, write_without_response, value = 1)]
-    pub(crate) protocol_mode: u8,
+    pub protocol_mode: u8,
     #[descriptor(uuid = "2908", read, value = [0u8, 1u8])]
     #[characteristic(uuid = "2a4d", read, notify)]
-    pub(crate) input_keyboard: [u8; 8],
+    pub input_keyboard: [u8; 8],
     #[descriptor(uuid = "2908", read, value = [0u8, 2u8])]
     #[characteristic(uuid = "2a4d", read, write, write_without_response)]
-    pub(crate) output_keyboard: [u8; 1],
+    pub output_keyboard: [u8; 1],
 }
\ No newline at end of file
@@ -0,0 +1,212 @@
+use log::{error, info};
+use tamagotchi_common::playdate::PlayMessage;
+use tamagotchi_common::protocol::{
+    FRAME_OVERHEAD, Frame, MessageType, SequenceCounter, TransferKind, TransferStart,
+};
+use trouble_host::prelude::{
+    AttErrorCode, Connection, Controller, CreditFlowPolicy, DefaultPacketPool, L2capChannel, L2capChannelConfig,
+    SecurityLevel, Stack,
+};
+
+use crate::service::ble::security_policy::Access;
+
+/// Fixed LE PSM of the bulk data channel (dynamic range starts at 0x0080)
+pub const BULK_PSM: u16 = 0x0081;
+
+/// Largest SDU exchanged on the bulk channel
+pub const BULK_MTU: usize = 512;
+
+/// Data bytes carried by one `TransferData` frame
+pub const BULK_CHUNK_LEN: usize = BULK_MTU - FRAME_OVERHEAD;
+
+/// Credits granted to the phone when the channel opens
+const INITIAL_CREDITS: u16 = 8;
+
+/// Credits are returned to the phone after this many SDUs are consumed
+const CREDIT_THRESHOLD: u16 = 4;
+
+#[derive(Debug)]
+pub enum L2capError {
+    Channel(trouble_host::Error),
+    /// The peer sent something that isn't a valid frame, or not the frame we expected
+    Protocol,
+    /// The incoming transfer doesn't fit in the receive buffer
+    TooLarge,
+    /// The request isn't served on this device or on this link; the channel is closed
+    Refused,
+}
+
+impl From<trouble_host::Error> for L2capError {
+    fn from(e: trouble_host::Error) -> Self {
+        L2capError::Channel(e)
+    }
+}
+
+/// First frame of an exchange on the bulk channel
+#[derive(Debug, Clone, Copy)]
+pub enum BulkRequest {
+    /// The phone starts a transfer
+    Transfer(TransferStart),
+    /// A visiting pet greets us to start a play date
+    PlayDate(PlayMessage),
+}
+
+pub struct L2capService;
+
+impl L2capService {
+    /// Waits for the phone to open the bulk channel on [`BULK_PSM`]
+    pub async fn accept<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        conn: &Connection<'a, DefaultPacketPool>,
+    ) -> Result<L2capStream<'a, C>, L2capError> {
+        let config = L2capChannelConfig {
+            mtu: Some(BULK_MTU as u16),
+            flow_policy: CreditFlowPolicy::MinThreshold(CREDIT_THRESHOLD),
+            initial_credits: Some(INITIAL_CREDITS),
+            ..Default::default()
+        };
+
+        let channel = L2capChannel::accept(stack, conn, &[BULK_PSM], &config).await?;
+        info!("[l2cap] Bulk channel open on PSM {:#06x}", BULK_PSM);
+
+        Ok(L2capStream {
+            channel,
+            stack,
+            sequence: SequenceCounter::new(),
+        })
+    }
+
+    /// Transfers carry the owner's data, so like the matching characteristics they need an
+    /// encrypted link; play dates stay open, visiting pets never pair
+    pub fn check_transfer(conn: &Connection<'_, DefaultPacketPool>) -> Result<(), AttErrorCode> {
+        let level = conn.security_level().unwrap_or(SecurityLevel::NoEncryption);
+        Access::ENCRYPTED.check_link(level, false)
+    }
+
+    /// Opens the bulk channel of another pet we are connected to as a central
+    pub async fn connect<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        conn: &Connection<'a, DefaultPacketPool>,
+    ) -> Result<L2capStream<'a, C>, L2capError> {
+        let config = L2capChannelConfig {
+            mtu: Some(BULK_MTU as u16),
+            flow_policy: CreditFlowPolicy::MinThreshold(CREDIT_THRESHOLD),
+            initial_credits: Some(INITIAL_CREDITS),
+            ..Default::default()
+        };
+
+        let channel = L2capChannel::create(stack, conn, BULK_PSM, &config).await?;
+        info!("[l2cap] Connected to bulk channel on PSM {:#06x}", BULK_PSM);
+
+        Ok(L2capStream {
+            channel,
+            stack,
+            sequence: SequenceCounter::new(),
+        })
+    }
+}
+
+/// Frame oriented stream over an L2CAP connection-oriented channel.
+///
+/// Every SDU carries exactly one protocol frame; flow control is credit based
+/// and handled by the host, so `send` waits whenever the phone runs out of credits.
+pub struct L2capStream<'a, C: Controller> {
+    channel: L2capChannel<'a, DefaultPacketPool>,
+    stack: &'a Stack<'a, C, DefaultPacketPool>,
+    sequence: SequenceCounter,
+}
+
+impl<'a, C: Controller> L2capStream<'a, C> {
+    pub async fn send_frame(&mut self, message_type: MessageType, payload: &[u8]) -> Result<(), L2capError> {
+        let mut sdu = [0u8; BULK_MTU];
+        let len = Frame::new(message_type, self.sequence.next_sequence(), payload)
+            .encode(&mut sdu)
+            .map_err(|_| L2capError::TooLarge)?;
+
+        self.channel.send(self.stack, &sdu[..len]).await?;
+        Ok(())
+    }
+
+    /// Receives the next SDU into `sdu` and decodes it as a frame
+    pub async fn receive_frame<'b>(&mut self, sdu: &'b mut [u8; BULK_MTU]) -> Result<Frame<'b>, L2capError> {
+        let len = self.channel.receive(self.stack, sdu).await?;
+        let (frame, _) = Frame::decode(&sdu[..len]).map_err(|e| {
+            error!("[l2cap] Invalid frame: {:?}", e);
+            L2capError::Protocol
+        })?;
+        Ok(frame)
+    }
+
+    /// Sends `data` as one transfer: a start frame, data chunks and an end frame
+    pub async fn send_transfer(&mut self, kind: TransferKind, data: &[u8]) -> Result<(), L2capError> {
+        let total_len = data.len() as u32;
+        let start = TransferStart { kind, total_len };
+        self.send_frame(MessageType::TransferStart, &start.to_bytes()).await?;
+
+        for chunk in data.chunks(BULK_CHUNK_LEN) {
+            self.send_frame(MessageType::TransferData, chunk).await?;
+        }
+
+        self.send_frame(MessageType::TransferEnd, &total_len.to_le_bytes()).await?;
+        info!("[l2cap] Sent {:?} transfer: {} bytes", kind, total_len);
+        Ok(())
+    }
+
+    /// Receives the data of a transfer whose start frame was already read.
+    ///
+    /// Returns the number of bytes written to `buffer`.
+    pub async fn receive_transfer(&mut self, start: TransferStart, buffer: &mut [u8]) -> Result<usize, L2capError> {
+        let total_len = start.total_len as usize;
+        if total_len > buffer.len() {
+            return Err(L2capError::TooLarge);
+        }
+
+        let mut sdu = [0u8; BULK_MTU];
+        let mut received = 0;
+        loop {
+            let frame = self.receive_frame(&mut sdu).await?;
+            match frame.message_type {
+                MessageType::TransferData => {
+                    let end = received + frame.payload.len();
+                    if end > total_len {
+                        return Err(L2capError::TooLarge);
+                    }
+                    buffer[received..end].copy_from_slice(frame.payload);
+                    received = end;
+                }
+                MessageType::TransferEnd if received == total_len => {
+                    info!("[l2cap] Received {:?} transfer: {} bytes", start.kind, received);
+                    return Ok(received);
+                }
+                _ => return Err(L2capError::Protocol),
+            }
+        }
+    }
+
+    /// Waits for the peer to start a transfer or a play date
+    pub async fn receive_request(&mut self) -> Result<BulkRequest, L2capError> {
+        let mut sdu = [0u8; BULK_MTU];
+        let frame = self.receive_frame(&mut sdu).await?;
+        let request = match frame.message_type {
+            MessageType::TransferStart => TransferStart::from_bytes(frame.payload).map(BulkRequest::Transfer),
+            MessageType::PlayDate => PlayMessage::from_bytes(frame.payload).map(BulkRequest::PlayDate),
+            _ => None,
+        };
+        request.ok_or(L2capError::Protocol)
+    }
+
+    /// Waits for the next play date message
+    pub async fn receive_play_message(&mut self) -> Result<PlayMessage, L2capError> {
+        let mut sdu = [0u8; BULK_MTU];
+        let frame = self.receive_frame(&mut sdu).await?;
+        if frame.message_type != MessageType::PlayDate {
+            return Err(L2capError::Protocol);
+        }
+        PlayMessage::from_bytes(frame.payload).ok_or(L2capError::Protocol)
+    }
+
+    pub async fn send_play_message(&mut self, message: &PlayMessage) -> Result<(), L2capError> {
+        let (payload, len) = message.to_bytes();
+        self.send_frame(MessageType::PlayDate, &payload[..len]).await
+    }
+}
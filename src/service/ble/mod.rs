@@ -4,4 +4,24 @@ pub mod storage_service;
 pub mod advertise_service;
 pub mod gatt_service;
 pub mod notification_service;
-pub mod notification_characteristics;
\ No newline at end of file
+pub mod notification_characteristics;
+pub mod command_service;
+pub mod mtu_service;
+pub mod l2cap_service;
+pub mod address_service;
+pub mod pairing_service;
+pub mod security_policy;
+pub mod disconnect_service;
+pub mod read_service;
+pub mod subscription_service;
+pub mod outbound_service;
+pub mod device_info_service;
+pub mod gatt_server;
+pub mod visit_service;
+pub mod play_date_service;
+pub mod connection_params_service;
+pub mod proximity_service;
+pub mod console_service;
+pub mod nus_service;
+pub mod dfu_service;
+pub mod ota_service;
\ No newline at end of file
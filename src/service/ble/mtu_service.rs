@@ -0,0 +1,28 @@
+use trouble_host::prelude::{DefaultPacketPool, GattConnection};
+
+use crate::service::ble::notification_characteristics::{ATT_HEADER_LEN, DEFAULT_ATT_MTU, FRAGMENT_MAX_LEN};
+
+/// Largest ATT MTU we make use of; values are capped at [`FRAGMENT_MAX_LEN`]
+pub const PREFERRED_ATT_MTU: u16 = (FRAGMENT_MAX_LEN + ATT_HEADER_LEN) as u16;
+
+/// ATT MTU of a connection.
+///
+/// The phone is the ATT client, so it starts the exchange whenever it likes
+/// and the host answers with our preferred MTU. Until then (or if it never
+/// asks) the connection has the default MTU of 23; everything is sized from
+/// the current value when it is sent, and the GATT event loop logs changes.
+pub struct MtuService;
+
+impl MtuService {
+    /// Current ATT MTU of `conn`, clamped to what our characteristics can carry
+    pub fn effective_mtu(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> u16 {
+        conn.raw()
+            .att_mtu()
+            .clamp(DEFAULT_ATT_MTU as u16, PREFERRED_ATT_MTU)
+    }
+
+    /// Largest characteristic value that fits in one notification on `conn`
+    pub fn notification_payload_len(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> usize {
+        Self::effective_mtu(conn) as usize - ATT_HEADER_LEN
+    }
+}
@@ -1,42 +1,57 @@
+use heapless::Vec;
 use trouble_host::prelude::gatt_service;
 
+use tamagotchi_common::protocol::FRAME_OVERHEAD;
+use tamagotchi_common::visit::VISIT_MAX_LEN;
+
+use crate::service::ble::command_service::{COMMAND_MAX_LEN, COMMAND_RESULT_LEN};
+
+pub use tamagotchi_common::pet::TamagotchiStatus;
+
+/// ATT MTU padrão, usado enquanto o telefone não negocia um maior
+pub const DEFAULT_ATT_MTU: usize = 23;
+/// Cabeçalho de uma notificação ATT (opcode + handle)
+pub const ATT_HEADER_LEN: usize = 3;
+/// Maior valor de característica suportado (MTU de 247 menos o cabeçalho ATT)
+pub const FRAGMENT_MAX_LEN: usize = 247 - ATT_HEADER_LEN;
+/// Tamanho máximo do texto de uma mensagem, dividida em fragmentos para envio
+pub use tamagotchi_common::protocol::MESSAGE_MAX_LEN;
+pub const MESSAGE_FRAME_MAX_LEN: usize = FRAME_OVERHEAD + MESSAGE_MAX_LEN;
+pub const COUNTER_FRAME_LEN: usize = FRAME_OVERHEAD + 4;
+pub const STATUS_FRAME_LEN: usize = FRAME_OVERHEAD + 1;
+pub const COMMAND_FRAME_LEN: usize = FRAME_OVERHEAD + COMMAND_MAX_LEN;
+pub const COMMAND_RESULT_FRAME_LEN: usize = FRAME_OVERHEAD + COMMAND_RESULT_LEN;
+pub const VISIT_FRAME_LEN: usize = FRAME_OVERHEAD + VISIT_MAX_LEN;
+
 /// Serviço customizado para enviar notificações/mensagens para o telefone
+///
+/// Todas as características transportam um frame do protocolo definido em
+/// `tamagotchi_common::protocol` (versão, tipo, sequência, tamanho e CRC-16).
+/// As características `message` e `command` levam o frame em fragmentos e
+/// só enviam o tamanho real de cada fragmento.
 #[gatt_service(uuid = "12345678-1234-5678-1234-56789abcdef0")]
 pub struct NotificationCharacteristics {
-    /// Característica para enviar mensagens de texto
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef1", read, notify, value = [0u8; 128])]
-    pub message: [u8; 128],
+    /// Característica para enviar mensagens de texto (fragmentadas), por notificação ou indicação
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef1", read, notify, indicate)]
+    pub message: Vec<u8, FRAGMENT_MAX_LEN>,
     
     /// Característica para contador de notificações
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef2", read, notify, value = 0)]
-    pub counter: u32,
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef2", read, notify, value = [0u8; COUNTER_FRAME_LEN])]
+    pub counter: [u8; COUNTER_FRAME_LEN],
     
     /// Característica para status do Tamagotchi
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef3", read, notify, value = 0)]
-    pub tamagotchi_status: u8,
-}
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef3", read, notify, value = [0u8; STATUS_FRAME_LEN])]
+    pub tamagotchi_status: [u8; STATUS_FRAME_LEN],
 
-/// Estados possíveis do Tamagotchi para notificações
-#[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-pub enum TamagotchiStatus {
-    Happy = 0,
-    Hungry = 1,
-    Tired = 2,
-    Sick = 3,
-    Playing = 4,
-    Sleeping = 5,
-}
+    /// Característica para receber comandos do telefone (opcode + argumentos, fragmentados)
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef4", write)]
+    pub command: Vec<u8, FRAGMENT_MAX_LEN>,
+
+    /// Característica para responder aos comandos com o código de status
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef5", read, notify, value = [0u8; COMMAND_RESULT_FRAME_LEN])]
+    pub command_result: [u8; COMMAND_RESULT_FRAME_LEN],
 
-impl TamagotchiStatus {
-    pub fn as_message(&self) -> &'static [u8] {
-        match self {
-            TamagotchiStatus::Happy => b"Estou feliz!",
-            TamagotchiStatus::Hungry => b"Com fome...",
-            TamagotchiStatus::Tired => b"Cansado...",
-            TamagotchiStatus::Sick => b"Doente :(",
-            TamagotchiStatus::Playing => b"Brincando!",
-            TamagotchiStatus::Sleeping => b"Dormindo zzz",
-        }
-    }
+    /// Característica de visitas: outro pet escreve a visita dele e lê a nossa
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdef6", read, write, value = [0u8; VISIT_FRAME_LEN])]
+    pub visit: [u8; VISIT_FRAME_LEN],
 }
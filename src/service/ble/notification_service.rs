@@ -1,27 +1,173 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use heapless::Vec;
 use log::{info, error};
-use trouble_host::prelude::{GattConnection, DefaultPacketPool};
-use crate::service::ble::notification_characteristics::{NotificationCharacteristics, TamagotchiStatus};
+use tamagotchi_common::i18n::Language;
+use tamagotchi_common::protocol::{Fragments, Frame, MessageType, SequenceCounter};
+use trouble_host::prelude::{Characteristic, GattConnection, DefaultPacketPool};
+use crate::service::ble::command_service::CommandResult;
+use crate::service::ble::mtu_service::MtuService;
+use crate::service::ble::outbound_service::Delivery;
+use crate::service::ble::subscription_service::Subscriptions;
+use crate::service::ble::notification_characteristics::{
+    COMMAND_RESULT_FRAME_LEN, COUNTER_FRAME_LEN, FRAGMENT_MAX_LEN, MESSAGE_FRAME_MAX_LEN,
+    MESSAGE_MAX_LEN, NotificationCharacteristics, STATUS_FRAME_LEN, TamagotchiStatus,
+};
+
+/// Número máximo de conexões simultâneas; todas recebem as notificações do pet
+pub const CONNECTIONS_MAX: usize = 3;
+
+/// Notificações produzidas pela aplicação e distribuídas para as conexões
+#[derive(Debug, Clone)]
+pub enum Notification {
+    /// Enviada para todas as conexões
+    Status(TamagotchiStatus),
+    /// Enviada só para a conexão que mandou o comando
+    CommandResult(CommandResult),
+}
+
+static NOTIFICATIONS: PubSubChannel<CriticalSectionRawMutex, Notification, 4, CONNECTIONS_MAX, 1> =
+    PubSubChannel::new();
+
+/// Número de sequência dos frames enviados pelo dispositivo
+static SEQUENCE: Mutex<RefCell<SequenceCounter>> = Mutex::new(RefCell::new(SequenceCounter::new()));
 
 /// Helper para enviar notificações facilmente através do NotificationService
 pub struct NotificationService;
 
 impl NotificationService {
-    /// Envia uma mensagem de texto para o telefone
+    /// Publica uma notificação para as tasks de todas as conexões
+    pub fn publish(notification: Notification) {
+        NOTIFICATIONS.immediate_publisher().publish_immediate(notification);
+    }
+
+    /// Encaminha as notificações publicadas para `conn` até a conexão cair,
+    /// pulando as características em que o telefone não se inscreveu
+    pub async fn forward(
+        service: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+    ) {
+        let mut subscriber = match NOTIFICATIONS.subscriber() {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                error!("[notify] No notification subscriber left: {:?}", e);
+                return core::future::pending().await;
+            }
+        };
+        let conn_handle = conn.raw().handle().raw();
+
+        loop {
+            match subscriber.next_message_pure().await {
+                Notification::Status(status) => {
+                    // O texto do status vai pela fila de saída (OutboundQueue)
+                    if subscriptions.is_subscribed(service.tamagotchi_status.handle) {
+                        let _ = Self::send_status(service, conn, status as u8).await;
+                    }
+                }
+                Notification::CommandResult(result) if result.conn_handle == conn_handle => {
+                    if subscriptions.is_subscribed(service.command_result.handle) {
+                        let _ = Self::send_command_result(service, conn, &result).await;
+                    } else {
+                        info!("[notify] Command result dropped, phone not subscribed");
+                    }
+                }
+                Notification::CommandResult(_) => {}
+            }
+        }
+    }
+
+    fn next_sequence() -> u16 {
+        critical_section::with(|cs| SEQUENCE.borrow_ref_mut(cs).next_sequence())
+    }
+
+    /// Monta um frame do protocolo dentro do buffer da característica
+    fn encode_frame<const N: usize>(
+        message_type: MessageType,
+        sequence: u16,
+        payload: &[u8],
+    ) -> Result<[u8; N], trouble_host::Error> {
+        let mut buffer = [0u8; N];
+        Frame::new(message_type, sequence, payload)
+            .encode(&mut buffer)
+            .map_err(|e| {
+                error!("[notify] Failed to encode {:?} frame: {:?}", message_type, e);
+                trouble_host::Error::InvalidValue
+            })?;
+        Ok(buffer)
+    }
+
+    /// Envia um frame já codificado em fragmentos de até `fragment_len` bytes,
+    /// enviando só o tamanho real de cada fragmento. Com indicações cada
+    /// fragmento espera a confirmação do telefone antes do próximo.
+    async fn send_fragmented(
+        characteristic: &Characteristic<Vec<u8, FRAGMENT_MAX_LEN>>,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        frame: &[u8],
+        fragment_len: usize,
+        delivery: Delivery,
+    ) -> Result<(), trouble_host::Error> {
+        let fragment_len = fragment_len.min(FRAGMENT_MAX_LEN);
+        let fragments = Fragments::new(frame, fragment_len).map_err(|e| {
+            error!("[notify] Failed to fragment frame: {:?}", e);
+            trouble_host::Error::InvalidValue
+        })?;
+
+        let mut value = [0u8; FRAGMENT_MAX_LEN];
+        for fragment in fragments {
+            let len = fragment.write_to(&mut value).ok_or(trouble_host::Error::InvalidValue)?;
+            let value = Vec::from_slice(&value[..len]).map_err(|_| trouble_host::Error::InvalidValue)?;
+            match delivery {
+                Delivery::Notify => characteristic.notify(conn, &value).await?,
+                Delivery::Indicate => characteristic.indicate(conn, &value).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Frame do status do pet, como notificado e como lido da característica
+    pub fn status_frame(status: u8) -> Result<[u8; STATUS_FRAME_LEN], trouble_host::Error> {
+        Self::encode_frame(MessageType::Status, Self::next_sequence(), &[status])
+    }
+
+    /// Frame do contador, como notificado e como lido da característica
+    pub fn counter_frame(value: u32) -> Result<[u8; COUNTER_FRAME_LEN], trouble_host::Error> {
+        Self::encode_frame(MessageType::Counter, Self::next_sequence(), &value.to_le_bytes())
+    }
+
+    /// Envia uma mensagem de texto para o telefone, fragmentada de acordo com o MTU da conexão
     /// Retorna Ok(()) se enviado com sucesso
     pub async fn send_message(
         service: &NotificationCharacteristics,
         conn: &GattConnection<'_, '_, DefaultPacketPool>,
         message: &[u8],
     ) -> Result<(), trouble_host::Error> {
-        if message.len() > 128 {
-            error!("[notify] Message too long: {} bytes (max 128)", message.len());
+        Self::send_message_with(service, conn, message, Delivery::Notify).await
+    }
+
+    /// Como [`Self::send_message`], escolhendo entre notificação e indicação
+    pub async fn send_message_with(
+        service: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        message: &[u8],
+        delivery: Delivery,
+    ) -> Result<(), trouble_host::Error> {
+        if message.len() > MESSAGE_MAX_LEN {
+            error!("[notify] Message too long: {} bytes (max {})", message.len(), MESSAGE_MAX_LEN);
             return Err(trouble_host::Error::InvalidValue);
         }
 
-        let mut buffer = [0u8; 128];
-        buffer[..message.len()].copy_from_slice(message);
+        let mut buffer = [0u8; MESSAGE_FRAME_MAX_LEN];
+        let len = Frame::new(MessageType::Message, Self::next_sequence(), message)
+            .encode(&mut buffer)
+            .map_err(|_| trouble_host::Error::InvalidValue)?;
 
-        match service.message.notify(conn, &buffer).await {
+        let fragment_len = MtuService::notification_payload_len(conn);
+
+        match Self::send_fragmented(&service.message, conn, &buffer[..len], fragment_len, delivery).await {
             Ok(_) => {
                 if let Ok(msg_str) = core::str::from_utf8(message) {
                     info!("[notify] Message sent: {}", msg_str);
@@ -43,7 +189,9 @@ impl NotificationService {
         conn: &GattConnection<'_, '_, DefaultPacketPool>,
         value: u32,
     ) -> Result<(), trouble_host::Error> {
-        match service.counter.notify(conn, &value).await {
+        let buffer = Self::counter_frame(value)?;
+
+        match service.counter.notify(conn, &buffer).await {
             Ok(_) => {
                 info!("[notify] Counter sent: {}", value);
                 Ok(())
@@ -61,7 +209,9 @@ impl NotificationService {
         conn: &GattConnection<'_, '_, DefaultPacketPool>,
         status: u8,
     ) -> Result<(), trouble_host::Error> {
-        match service.tamagotchi_status.notify(conn, &status).await {
+        let buffer = Self::status_frame(status)?;
+
+        match service.tamagotchi_status.notify(conn, &buffer).await {
             Ok(_) => {
                 info!("[notify] Status sent: {}", status);
                 Ok(())
@@ -78,12 +228,35 @@ impl NotificationService {
         service: &NotificationCharacteristics,
         conn: &GattConnection<'_, '_, DefaultPacketPool>,
         status: TamagotchiStatus,
+        language: Language,
     ) -> Result<(), trouble_host::Error> {
         // Envia o código de status
         Self::send_status(service, conn, status as u8).await?;
         
-        // Envia a mensagem correspondente
-        let message = status.as_message();
+        // Envia a mensagem correspondente no idioma escolhido
+        let message = status.as_message(language);
         Self::send_message(service, conn, message).await
     }
+
+    /// Responde a um comando com o código de status e o estado atual do pet
+    pub async fn send_command_result(
+        service: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        result: &CommandResult,
+    ) -> Result<(), trouble_host::Error> {
+        // A resposta reutiliza o número de sequência do comando
+        let buffer: [u8; COMMAND_RESULT_FRAME_LEN] =
+            Self::encode_frame(MessageType::CommandResult, result.sequence, &result.to_bytes())?;
+
+        match service.command_result.notify(conn, &buffer).await {
+            Ok(_) => {
+                info!("[notify] Command result sent: opcode {:#04x} status {:?}", result.opcode, result.status);
+                Ok(())
+            }
+            Err(e) => {
+                error!("[notify] Failed to send command result: {:?}", e);
+                Err(e)
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,18 @@
+use heapless::Vec;
+use trouble_host::prelude::gatt_service;
+
+use crate::service::ble::notification_characteristics::FRAGMENT_MAX_LEN;
+
+/// Nordic UART Service, understood by standard BLE terminal apps.
+///
+/// Lines written to `rx` go to the debug shell, its replies come back as
+/// `tx` notifications split to the MTU of the connection.
+#[gatt_service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca9e")]
+pub struct NusService {
+    /// Terminal to device
+    #[characteristic(uuid = "6e400002-b5a3-f393-e0a9-e50e24dcca9e", write, write_without_response)]
+    pub rx: Vec<u8, FRAGMENT_MAX_LEN>,
+    /// Device to terminal
+    #[characteristic(uuid = "6e400003-b5a3-f393-e0a9-e50e24dcca9e", notify)]
+    pub tx: Vec<u8, FRAGMENT_MAX_LEN>,
+}
@@ -0,0 +1,294 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::ota::OtaImageState;
+use esp_bootloader_esp_idf::ota_updater::OtaUpdater;
+use esp_bootloader_esp_idf::partitions::{self, AppPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType};
+use esp_hal::peripherals::FLASH;
+use esp_storage::{FlashStorage, FlashStorageError};
+use heapless::Vec;
+use log::{error, info, warn};
+use tamagotchi_common::dfu::{
+    DFU_DATA_MAX_LEN, DfuChunk, DfuControl, DfuFlash, DfuOpcode, DfuReceiver, DfuResponse, DfuStatus, WRITE_ALIGN,
+};
+
+/// A write on the DFU service, queued by the GATT event loop for the update task of the connection
+#[derive(Debug)]
+pub enum DfuRequest {
+    Control(DfuControl),
+    /// Decoded by the update task, so a malformed chunk gets a notification
+    Chunk(Vec<u8, DFU_DATA_MAX_LEN>),
+}
+
+pub type DfuRequests = Channel<CriticalSectionRawMutex, DfuRequest, 4>;
+
+/// First byte of every ESP32 application image
+const IMAGE_MAGIC: u8 = 0xE9;
+
+/// Time given to the last notification to leave before rebooting
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// Whether a connection holds the inactive partition; one update at a time
+static UPDATING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+#[derive(Debug)]
+pub enum OtaError {
+    Bootloader(partitions::Error),
+    /// The partition table has no second OTA slot
+    NoPartition,
+}
+
+impl From<partitions::Error> for OtaError {
+    fn from(e: partitions::Error) -> Self {
+        OtaError::Bootloader(e)
+    }
+}
+
+/// What to notify after a request, and whether the device reboots into the new image once it is sent
+#[derive(Debug, Default)]
+pub struct DfuReply {
+    pub response: Option<DfuResponse>,
+    pub reboot: bool,
+}
+
+/// The OTA partition we are not running from, addressed from its start
+pub struct OtaPartition {
+    flash: FlashStorage<'static>,
+    offset: u32,
+    len: u32,
+}
+
+impl OtaPartition {
+    fn open() -> Result<Self, OtaError> {
+        let mut flash = OtaService::flash();
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let target = match OtaUpdater::new(&mut flash, &mut buffer)?.selected_partition()? {
+            AppPartitionSubType::Ota0 => AppPartitionSubType::Ota1,
+            _ => AppPartitionSubType::Ota0,
+        };
+        let table = partitions::read_partition_table(&mut flash, &mut buffer)?;
+        let entry = table.find_partition(PartitionType::App(target))?.ok_or(OtaError::NoPartition)?;
+        let (offset, len) = (entry.offset(), entry.len());
+        info!("[ota] Writing to {:?} at {:#x}, {} bytes", target, offset, len);
+        Ok(OtaPartition { flash, offset, len })
+    }
+}
+
+impl DfuFlash for OtaPartition {
+    type Error = FlashStorageError;
+
+    const ERASE_SIZE: u32 = <FlashStorage as NorFlash>::ERASE_SIZE as u32;
+
+    fn capacity(&self) -> u32 {
+        self.len
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        NorFlash::erase(&mut self.flash, self.offset + from, self.offset + to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(&mut self.flash, self.offset + offset, bytes)
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(&mut self.flash, self.offset + offset, bytes)
+    }
+}
+
+/// Firmware updates over BLE into the inactive OTA partition, with rollback.
+///
+/// The bootloader espflash installs is built without rollback support, so
+/// the application does it: a new image boots as `New` and is marked
+/// `PendingVerify`; if it reboots before [`confirm`](Self::confirm) ran,
+/// [`check_boot`](Self::check_boot) switches back to the previous image.
+pub struct OtaService {
+    receiver: DfuReceiver,
+    /// Claimed on start, released on abort, success or when the connection drops
+    partition: Option<OtaPartition>,
+}
+
+impl Default for OtaService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for OtaService {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl OtaService {
+    pub const fn new() -> Self {
+        OtaService { receiver: DfuReceiver::new(), partition: None }
+    }
+
+    /// Runs one write of the phone; written chunks are acknowledged by ATT alone
+    pub fn handle(&mut self, request: DfuRequest) -> DfuReply {
+        let (opcode, result) = match request {
+            DfuRequest::Control(control) => (control.opcode(), self.control(control)),
+            DfuRequest::Chunk(bytes) => (DfuOpcode::Chunk, self.chunk(&bytes)),
+        };
+        let (status, next_offset) = match result {
+            Ok(next_offset) => (DfuStatus::Ok, next_offset),
+            Err(status) => (status, self.receiver.received()),
+        };
+        if opcode == DfuOpcode::Chunk && status == DfuStatus::Ok {
+            return DfuReply::default();
+        }
+        DfuReply {
+            response: Some(DfuResponse { opcode, status, next_offset }),
+            reboot: opcode == DfuOpcode::Finish && status == DfuStatus::Ok,
+        }
+    }
+
+    fn control(&mut self, control: DfuControl) -> Result<u32, DfuStatus> {
+        match control {
+            DfuControl::Start(image) => {
+                self.claim()?;
+                let partition = self.partition.as_ref().ok_or(DfuStatus::FlashError)?;
+                self.receiver.start(partition, image).map_err(|e| e.status())?;
+                info!("[ota] Receiving a {} byte image", image.size);
+                Ok(0)
+            }
+            DfuControl::Finish => {
+                let partition = self.partition.as_mut().ok_or(DfuStatus::NotStarted)?;
+                let image = self.receiver.finish().map_err(|e| {
+                    warn!("[ota] Image rejected: {:?}", e);
+                    e.status()
+                })?;
+
+                let mut header = [0u8; WRITE_ALIGN];
+                partition.read(0, &mut header).map_err(|_| DfuStatus::FlashError)?;
+                if header[0] != IMAGE_MAGIC {
+                    warn!("[ota] Not an application image, header {:02x?}", header);
+                    return Err(DfuStatus::InvalidImage);
+                }
+                if let Err(e) = Self::activate() {
+                    error!("[ota] Failed to switch the boot partition: {:?}", e);
+                    return Err(DfuStatus::FlashError);
+                }
+                self.release();
+                info!("[ota] Image verified, booting it next");
+                Ok(image.size)
+            }
+            DfuControl::Abort => {
+                info!("[ota] Update aborted at {} bytes", self.receiver.received());
+                self.receiver.abort();
+                self.release();
+                Ok(0)
+            }
+        }
+    }
+
+    fn chunk(&mut self, bytes: &[u8]) -> Result<u32, DfuStatus> {
+        let chunk = DfuChunk::from_bytes(bytes).ok_or(DfuStatus::Malformed)?;
+        let partition = self.partition.as_mut().ok_or(DfuStatus::NotStarted)?;
+        self.receiver.write(partition, chunk.offset, chunk.data).map_err(|e| {
+            info!("[ota] Chunk at {} refused: {:?}", chunk.offset, e);
+            e.status()
+        })
+    }
+
+    /// The partition of this connection, opened on its first start
+    fn claim(&mut self) -> Result<(), DfuStatus> {
+        if self.partition.is_none() {
+            let busy = critical_section::with(|cs| UPDATING.borrow(cs).replace(true));
+            if busy {
+                return Err(DfuStatus::Busy);
+            }
+            match OtaPartition::open() {
+                Ok(partition) => self.partition = Some(partition),
+                Err(e) => {
+                    error!("[ota] Failed to open the OTA partition: {:?}", e);
+                    critical_section::with(|cs| UPDATING.borrow(cs).set(false));
+                    return Err(DfuStatus::FlashError);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        if self.partition.take().is_some() {
+            critical_section::with(|cs| UPDATING.borrow(cs).set(false));
+        }
+    }
+
+    /// Boots the partition just written next time, as a new image on probation
+    fn activate() -> Result<(), OtaError> {
+        Self::with_updater(|ota| {
+            ota.activate_next_partition()?;
+            ota.set_current_ota_state(OtaImageState::New)?;
+            Ok(())
+        })
+    }
+
+    /// Called first thing at boot: rolls back to the previous image when this
+    /// one already booted once without confirming itself
+    pub fn check_boot() {
+        let result = Self::with_updater(|ota| match ota.current_ota_state()? {
+            OtaImageState::New => {
+                info!("[ota] First boot of a new image, waiting for it to confirm itself");
+                ota.set_current_ota_state(OtaImageState::PendingVerify)?;
+                Ok(false)
+            }
+            OtaImageState::PendingVerify => {
+                error!("[ota] New image never confirmed itself, rolling back");
+                ota.set_current_ota_state(OtaImageState::Aborted)?;
+                ota.activate_next_partition()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        });
+        match result {
+            Ok(true) => esp_hal::system::software_reset(),
+            Ok(false) => {}
+            // Flashed over USB: no OTA data yet
+            Err(e) => info!("[ota] No OTA state: {:?}", e),
+        }
+    }
+
+    /// Marks the running image as good, so later boots keep it
+    pub fn confirm() {
+        let result = Self::with_updater(|ota| {
+            if ota.current_ota_state()? == OtaImageState::PendingVerify {
+                ota.set_current_ota_state(OtaImageState::Valid)?;
+                info!("[ota] New image confirmed");
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            info!("[ota] Nothing to confirm: {:?}", e);
+        }
+    }
+
+    /// Restarts into the new image once the last notification had time to leave
+    pub async fn reboot() -> ! {
+        info!("[ota] Rebooting into the new image");
+        Timer::after(REBOOT_DELAY).await;
+        esp_hal::system::software_reset()
+    }
+
+    fn with_updater<T>(
+        f: impl FnOnce(&mut OtaUpdater<'_, FlashStorage<'static>>) -> Result<T, OtaError>,
+    ) -> Result<T, OtaError> {
+        let mut flash = Self::flash();
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut ota = OtaUpdater::new(&mut flash, &mut buffer)?;
+        f(&mut ota)
+    }
+
+    fn flash() -> FlashStorage<'static> {
+        // SAFETY: the storage map owns FLASH too. Every esp-storage operation
+        // completes before returning and both users run on the one executor,
+        // so their accesses never overlap; each stays in its own partitions.
+        FlashStorage::new(unsafe { FLASH::steal() })
+    }
+}
@@ -0,0 +1,270 @@
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::{Duration, Timer};
+use heapless::{Deque, Vec};
+use log::{error, info, warn};
+use trouble_host::prelude::{DefaultPacketPool, GattConnection};
+
+use crate::service::ble::notification_characteristics::{MESSAGE_MAX_LEN, NotificationCharacteristics};
+use crate::service::ble::notification_service::{CONNECTIONS_MAX, NotificationService};
+use crate::service::ble::subscription_service::Subscriptions;
+
+/// Messages waiting for a phone
+pub const OUTBOUND_MAX: usize = 8;
+
+/// Sends of one message on a connection before it gives up on it
+const MAX_ATTEMPTS: u8 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The queue is also checked periodically, so a backlog goes out once the phone subscribes
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+static QUEUE: Mutex<RefCell<Deque<QueuedMessage, OUTBOUND_MAX>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// Slots with a phone connected, one bit per slot
+static CONNECTED: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Identifies a queued message while it's being sent
+static NEXT_ID: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Wakes the flush task of every connection when a message is queued
+static QUEUED: PubSubChannel<CriticalSectionRawMutex, (), 1, CONNECTIONS_MAX, 1> = PubSubChannel::new();
+
+const _: () = assert!(CONNECTIONS_MAX <= u8::BITS as usize, "one bit per slot");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Dropped when the last phone disconnects or the queue overflows
+    Normal,
+    /// Kept while no phone is connected, until one acknowledges it
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Fire and forget
+    Notify,
+    /// Acknowledged by the phone's ATT layer
+    Indicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundError {
+    TooLong,
+    /// Every queued message is high priority
+    Full,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboundMessage {
+    pub text: Vec<u8, MESSAGE_MAX_LEN>,
+    pub priority: Priority,
+    pub delivery: Delivery,
+}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    id: u32,
+    message: OutboundMessage,
+    /// Slots whose connection already got it (or gave up on it)
+    delivered: u8,
+}
+
+/// Bounded queue of text messages for the message characteristic.
+///
+/// Every connected phone gets each message once; a message leaves the queue
+/// when all of them have it.
+pub struct OutboundQueue;
+
+impl OutboundQueue {
+    /// Queues `text`; when full, the oldest normal priority message makes room
+    pub fn push(text: &[u8], priority: Priority, delivery: Delivery) -> Result<(), OutboundError> {
+        let text = Vec::from_slice(text).map_err(|_| OutboundError::TooLong)?;
+        let message = OutboundMessage { text, priority, delivery };
+
+        critical_section::with(|cs| {
+            let mut queue = QUEUE.borrow_ref_mut(cs);
+            if queue.is_full() {
+                let Some(index) = queue.iter().position(|m| m.message.priority == Priority::Normal) else {
+                    return Err(OutboundError::Full);
+                };
+                Self::remove(&mut queue, index);
+                warn!("[outbound] Queue full, dropped oldest normal message");
+            }
+            let id = NEXT_ID.borrow(cs).get();
+            NEXT_ID.borrow(cs).set(id.wrapping_add(1));
+            // Can't fail, there's room now
+            let _ = queue.push_back(QueuedMessage { id, message, delivered: 0 });
+            Ok(())
+        })?;
+
+        QUEUED.immediate_publisher().publish_immediate(());
+        Ok(())
+    }
+
+    pub fn len() -> usize {
+        critical_section::with(|cs| QUEUE.borrow_ref(cs).len())
+    }
+
+    /// Counts `slot` among the phones each message has to reach
+    pub fn connected(slot: usize) {
+        critical_section::with(|cs| {
+            let connected = CONNECTED.borrow(cs);
+            connected.set(connected.get() | 1 << slot);
+        });
+    }
+
+    /// Forgets what `slot` received, its next phone gets the backlog again.
+    /// Normal priority messages are dropped once no phone is left.
+    pub fn disconnected(slot: usize) {
+        critical_section::with(|cs| {
+            let connected = CONNECTED.borrow(cs);
+            connected.set(connected.get() & !(1 << slot));
+
+            let mut queue = QUEUE.borrow_ref_mut(cs);
+            for queued in queue.iter_mut() {
+                queued.delivered &= !(1 << slot);
+            }
+            if connected.get() == 0 {
+                let before = queue.len();
+                Self::retain(&mut queue, |queued| queued.message.priority == Priority::High);
+                if queue.len() != before {
+                    info!("[outbound] Dropped {} normal messages", before - queue.len());
+                }
+            } else {
+                Self::prune(&mut queue, connected.get());
+            }
+        });
+    }
+
+    fn remove(queue: &mut Deque<QueuedMessage, OUTBOUND_MAX>, index: usize) {
+        for _ in 0..index {
+            if let Some(queued) = queue.pop_front() {
+                let _ = queue.push_back(queued);
+            }
+        }
+        queue.pop_front();
+        for _ in index..queue.len() {
+            if let Some(queued) = queue.pop_front() {
+                let _ = queue.push_back(queued);
+            }
+        }
+    }
+
+    /// Keeps the messages matching `keep`, in order
+    fn retain(queue: &mut Deque<QueuedMessage, OUTBOUND_MAX>, keep: impl Fn(&QueuedMessage) -> bool) {
+        for _ in 0..queue.len() {
+            if let Some(queued) = queue.pop_front()
+                && keep(&queued)
+            {
+                let _ = queue.push_back(queued);
+            }
+        }
+    }
+
+    /// Drops the messages every connected phone has; with none connected they all wait
+    fn prune(queue: &mut Deque<QueuedMessage, OUTBOUND_MAX>, connected: u8) {
+        if connected == 0 {
+            return;
+        }
+        Self::retain(queue, |queued| queued.delivered & connected != connected);
+    }
+
+    /// Oldest message `slot` hasn't received yet
+    fn next(slot: usize) -> Option<(u32, OutboundMessage)> {
+        critical_section::with(|cs| {
+            QUEUE
+                .borrow_ref(cs)
+                .iter()
+                .find(|queued| queued.delivered & 1 << slot == 0)
+                .map(|queued| (queued.id, queued.message.clone()))
+        })
+    }
+
+    /// Records that `slot` is done with message `id`
+    fn delivered(slot: usize, id: u32) {
+        critical_section::with(|cs| {
+            let mut queue = QUEUE.borrow_ref_mut(cs);
+            if let Some(queued) = queue.iter_mut().find(|queued| queued.id == id) {
+                queued.delivered |= 1 << slot;
+            }
+            Self::prune(&mut queue, CONNECTED.borrow(cs).get());
+        });
+    }
+
+    /// Delivers queued messages over the connection of `slot` until it drops
+    pub async fn run(
+        slot: usize,
+        service: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+    ) {
+        let mut queued = match QUEUED.subscriber() {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                error!("[outbound] No queue subscriber left: {:?}", e);
+                return core::future::pending().await;
+            }
+        };
+
+        loop {
+            if !Self::flush(slot, service, conn, subscriptions).await {
+                // The connection is gone, the slot forgets what it received
+                return core::future::pending().await;
+            }
+            select(queued.next_message_pure(), Timer::after(FLUSH_INTERVAL)).await;
+        }
+    }
+
+    /// Sends the backlog of `slot`, returns `false` when the connection can't be used anymore
+    async fn flush(
+        slot: usize,
+        service: &NotificationCharacteristics,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        subscriptions: &Subscriptions,
+    ) -> bool {
+        loop {
+            let Some(state) = subscriptions.state(service.message.handle).filter(|s| s.is_enabled()) else {
+                // Nobody listening yet, keep the backlog
+                return true;
+            };
+            // Stays queued while it's sent, a dropped task leaves it for later
+            let Some((id, message)) = Self::next(slot) else {
+                return true;
+            };
+
+            // Use what the phone subscribed to, preferring what the message asks for
+            let delivery = match message.delivery {
+                Delivery::Indicate if state.indicate => Delivery::Indicate,
+                Delivery::Notify if state.notify => Delivery::Notify,
+                _ if state.indicate => Delivery::Indicate,
+                _ => Delivery::Notify,
+            };
+
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                match NotificationService::send_message_with(service, conn, &message.text, delivery).await {
+                    Ok(()) => break,
+                    Err(e) if attempts < MAX_ATTEMPTS && conn.raw().is_connected() => {
+                        warn!("[outbound] Send failed ({:?}), retry {}/{}", e, attempts, MAX_ATTEMPTS - 1);
+                        Timer::after(RETRY_DELAY * attempts as u32).await;
+                    }
+                    Err(_) => {
+                        warn!("[outbound] Giving up after {} attempts", attempts);
+                        // High priority messages are tried again on the next flush
+                        if message.priority == Priority::Normal {
+                            Self::delivered(slot, id);
+                        }
+                        return conn.raw().is_connected();
+                    }
+                }
+            }
+            Self::delivered(slot, id);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, with_timeout};
+use esp_hal::gpio::Input;
+use log::{error, info, warn};
+use trouble_host::prelude::{DefaultPacketPool, GattConnection, IoCapabilities};
+
+/// Time the user has to confirm a numeric comparison; SMP gives up after 30 s
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Pairing UI shared by every connection, only one pairing is shown at a time
+pub type SharedPairingUi<U> = Mutex<CriticalSectionRawMutex, U>;
+
+/// A pairing step of one connection, queued by its GATT event loop so it never waits for the user
+#[derive(Debug, Clone, Copy)]
+pub enum PairingPrompt {
+    Display(u32),
+    Confirm(u32),
+    Finished(bool),
+}
+
+pub type PairingPrompts = Channel<CriticalSectionRawMutex, PairingPrompt, 4>;
+
+/// What the device can show and accept while pairing.
+///
+/// The IO capabilities decide the pairing method: a display with a yes/no
+/// input gets numeric comparison (or passkey entry on the phone), which is
+/// what reaches `SecurityLevel::EncryptedAuthenticated`. Without any IO the
+/// phone falls back to Just Works.
+#[allow(async_fn_in_trait)]
+pub trait PairingUi {
+    fn io_capabilities(&self) -> IoCapabilities;
+
+    /// Shows the passkey the user has to type on the phone
+    async fn display_passkey(&mut self, passkey: u32);
+
+    /// Shows the value both sides computed and waits for the user to accept it
+    async fn confirm_passkey(&mut self, passkey: u32) -> bool;
+
+    /// Clears the prompt once pairing ends
+    async fn pairing_finished(&mut self, success: bool);
+}
+
+/// No display or input: Just Works pairing, encrypted but not authenticated
+pub struct JustWorks;
+
+impl PairingUi for JustWorks {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::NoInputNoOutput
+    }
+
+    async fn display_passkey(&mut self, _passkey: u32) {}
+
+    async fn confirm_passkey(&mut self, _passkey: u32) -> bool {
+        false
+    }
+
+    async fn pairing_finished(&mut self, _success: bool) {}
+}
+
+/// Confirms pairing with a button press.
+///
+/// There's no screen driver yet, so the passkey goes to the console; a display
+/// only needs its own `PairingUi` to replace this one.
+pub struct ButtonPairingUi<'d> {
+    button: Input<'d>,
+}
+
+impl<'d> ButtonPairingUi<'d> {
+    pub fn new(button: Input<'d>) -> Self {
+        ButtonPairingUi { button }
+    }
+}
+
+impl PairingUi for ButtonPairingUi<'_> {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::DisplayYesNo
+    }
+
+    async fn display_passkey(&mut self, passkey: u32) {
+        info!("[pairing] Type this passkey on the phone: {:06}", passkey);
+    }
+
+    async fn confirm_passkey(&mut self, passkey: u32) -> bool {
+        info!("[pairing] Does the phone show {:06}? Press the button to confirm", passkey);
+        match with_timeout(CONFIRM_TIMEOUT, self.button.wait_for_falling_edge()).await {
+            Ok(()) => true,
+            Err(_) => {
+                warn!("[pairing] No confirmation, rejecting");
+                false
+            }
+        }
+    }
+
+    async fn pairing_finished(&mut self, success: bool) {
+        info!("[pairing] Pairing {}", if success { "succeeded" } else { "failed" });
+    }
+}
+
+pub struct PairingService;
+
+impl PairingService {
+    /// Shows the prompts of the connection until it drops.
+    ///
+    /// A comparison still waiting for the user is dropped as soon as the next
+    /// prompt arrives, e.g. when the phone cancels pairing.
+    pub async fn serve<U: PairingUi>(
+        ui: &SharedPairingUi<U>,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        prompts: &PairingPrompts,
+    ) {
+        let mut prompt = prompts.receive().await;
+        loop {
+            match prompt {
+                PairingPrompt::Display(passkey) => ui.lock().await.display_passkey(passkey).await,
+                PairingPrompt::Confirm(passkey) => {
+                    if let Either::Second(next) = select(Self::confirm(ui, conn, passkey), prompts.receive()).await {
+                        prompt = next;
+                        continue;
+                    }
+                }
+                PairingPrompt::Finished(success) => ui.lock().await.pairing_finished(success).await,
+            }
+            prompt = prompts.receive().await;
+        }
+    }
+
+    /// Asks the user to compare `passkey` and answers the phone
+    async fn confirm<U: PairingUi>(
+        ui: &SharedPairingUi<U>,
+        conn: &GattConnection<'_, '_, DefaultPacketPool>,
+        passkey: u32,
+    ) {
+        let accepted = ui.lock().await.confirm_passkey(passkey).await;
+
+        let result = if accepted {
+            conn.raw().pass_key_confirm()
+        } else {
+            conn.raw().pass_key_cancel()
+        };
+        if let Err(e) = result {
+            error!("[pairing] Failed to answer numeric comparison: {:?}", e);
+        }
+    }
+}
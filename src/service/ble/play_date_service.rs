@@ -0,0 +1,139 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, with_timeout};
+use esp_hal::rng::Rng;
+use log::{error, info, warn};
+use tamagotchi_common::playdate::{self, Gift, Hand, Outcome, PlayDate, PlayMessage, ROUNDS, Role};
+use tamagotchi_common::visit::Visit;
+use trouble_host::prelude::*;
+
+use crate::service::ble::l2cap_service::{L2capError, L2capService, L2capStream};
+
+/// Connection, channel setup and every message of one play date
+const PLAY_DATE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Play dates another pet started with us, consumed by the pet task
+pub static PLAY_DATE_CHANNEL: Channel<CriticalSectionRawMutex, Outcome, 2> = Channel::new();
+
+#[derive(Debug)]
+pub enum PlayDateError {
+    /// The other pet didn't take the connection
+    Connect,
+    /// The bulk channel failed or carried something that isn't a play date message
+    Channel(L2capError),
+    /// The other pet broke the rules of the play date
+    Rules(playdate::PlayDateError),
+    Timeout,
+}
+
+impl From<L2capError> for PlayDateError {
+    fn from(e: L2capError) -> Self {
+        PlayDateError::Channel(e)
+    }
+}
+
+impl From<playdate::PlayDateError> for PlayDateError {
+    fn from(e: playdate::PlayDateError) -> Self {
+        PlayDateError::Rules(e)
+    }
+}
+
+/// Play dates between pets, run over the L2CAP bulk channel.
+///
+/// The visiting pet opens the channel as a central and greets; the pet being
+/// visited answers from its connection slot.
+pub struct PlayDateService;
+
+impl PlayDateService {
+    /// Our side of a play date, with random hands and gift
+    pub fn prepare(role: Role, ours: Visit, friendship: u8) -> PlayDate {
+        let rng = Rng::new();
+        let hands: [Hand; ROUNDS] = core::array::from_fn(|_| Hand::from_random(rng.random()));
+        PlayDate::new(role, ours, friendship, hands, Gift::from_random(rng.random()))
+    }
+
+    /// Hands a play date another pet started with us to the application
+    pub fn finished(outcome: Outcome) {
+        info!(
+            "[play] Played with {}: {} won, {} lost, friendship {}",
+            outcome.friend.name(),
+            outcome.wins,
+            outcome.losses,
+            outcome.friendship
+        );
+        if PLAY_DATE_CHANNEL.try_send(outcome).is_err() {
+            error!("[play] Play date queue full, dropping outcome");
+        }
+    }
+
+    /// Connects to `peer` as a central and plays with it as the initiator
+    pub async fn play<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        central: &mut Central<'a, C, DefaultPacketPool>,
+        peer: Address,
+        play_date: &mut PlayDate,
+    ) -> Result<Outcome, PlayDateError> {
+        match with_timeout(PLAY_DATE_TIMEOUT, Self::connect_and_play(stack, central, peer, play_date)).await {
+            Ok(result) => result,
+            Err(_) => Err(PlayDateError::Timeout),
+        }
+    }
+
+    /// Answers a play date whose greeting was already read from `stream`
+    pub async fn respond<C: Controller>(
+        stream: &mut L2capStream<'_, C>,
+        play_date: &mut PlayDate,
+        greeting: PlayMessage,
+    ) -> Result<Outcome, PlayDateError> {
+        let exchange = async {
+            let reply = play_date.on_message(greeting)?;
+            Self::run(stream, play_date, reply).await
+        };
+        match with_timeout(PLAY_DATE_TIMEOUT, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(PlayDateError::Timeout),
+        }
+    }
+
+    async fn connect_and_play<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        central: &mut Central<'a, C, DefaultPacketPool>,
+        peer: Address,
+        play_date: &mut PlayDate,
+    ) -> Result<Outcome, PlayDateError> {
+        let config = ConnectConfig {
+            connect_params: Default::default(),
+            scan_config: ScanConfig {
+                filter_accept_list: &[(peer.kind, &peer.addr)],
+                ..Default::default()
+            },
+        };
+        let conn = central.connect(&config).await.map_err(|e| {
+            warn!("[play] Failed to connect to {:?}: {:?}", peer, e);
+            PlayDateError::Connect
+        })?;
+        info!("[play] Connected to {:?}", peer);
+
+        let mut stream = L2capService::connect(stack, &conn).await?;
+        let greeting = play_date.start();
+        Self::run(&mut stream, play_date, greeting).await
+    }
+
+    /// Sends `message`, then answers every message received until the play date ends
+    async fn run<C: Controller>(
+        stream: &mut L2capStream<'_, C>,
+        play_date: &mut PlayDate,
+        mut message: Option<PlayMessage>,
+    ) -> Result<Outcome, PlayDateError> {
+        loop {
+            if let Some(message) = &message {
+                stream.send_play_message(message).await?;
+            }
+            if let Some(outcome) = play_date.outcome() {
+                return Ok(outcome);
+            }
+            let received = stream.receive_play_message().await?;
+            message = play_date.on_message(received)?;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use log::{error, info};
+use tamagotchi_common::proximity::{ProximityTracker, Zone};
+use trouble_host::prelude::{Connection, DefaultPacketPool, SecurityLevel};
+use trouble_host::Address;
+
+/// Zone changes of the owner's phone, consumed by the pet task
+pub static PROXIMITY_CHANNEL: Channel<CriticalSectionRawMutex, Zone, 4> = Channel::new();
+
+/// Phone whose zone was published last, and that zone
+static OWNER: Mutex<Cell<Option<(Address, Zone)>>> = Mutex::new(Cell::new(None));
+
+/// Turns the RSSI of the owner's connection into near, medium and far zones
+pub struct ProximityService;
+
+impl ProximityService {
+    /// Only the owner's phone pairs with us; visiting pets and phones that never paired don't count
+    pub fn is_owner(conn: &Connection<'_, DefaultPacketPool>) -> bool {
+        matches!(conn.security_level(), Ok(level) if level != SecurityLevel::NoEncryption)
+    }
+
+    /// Feeds an RSSI reading of `peer`'s connection, publishing the zone when it changes
+    pub fn reading(peer: Address, tracker: &mut ProximityTracker, rssi: i8) {
+        if let Some(zone) = tracker.update(rssi) {
+            Self::publish(peer, zone);
+        }
+    }
+
+    /// A dropped link means the owner walked out of range
+    pub fn disconnected(peer: Address) {
+        let last = critical_section::with(|cs| OWNER.borrow(cs).get());
+        if matches!(last, Some((owner, zone)) if owner == peer && zone != Zone::Far) {
+            Self::publish(peer, Zone::Far);
+        }
+    }
+
+    fn publish(peer: Address, zone: Zone) {
+        info!("[proximity] {:?} is now {:?}", peer, zone);
+        critical_section::with(|cs| OWNER.borrow(cs).set(Some((peer, zone))));
+        if PROXIMITY_CHANNEL.try_send(zone).is_err() {
+            error!("[proximity] Proximity queue full, dropping {:?}", zone);
+        }
+    }
+}
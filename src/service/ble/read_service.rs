@@ -0,0 +1,41 @@
+use heapless::Vec;
+use log::error;
+
+/// Characteristics that can have a read callback
+pub const READ_CALLBACKS_MAX: usize = 8;
+
+/// Writes the current value of one characteristic into the attribute table
+pub type ReadCallback<'a> = &'a dyn Fn() -> Result<(), trouble_host::Error>;
+
+/// Read callbacks keyed by characteristic handle.
+///
+/// A read is answered from the attribute table, so the callback of the handle
+/// runs right before the read is accepted and stores a value computed from
+/// the application state at that moment.
+#[derive(Default)]
+pub struct ReadRegistry<'a> {
+    callbacks: Vec<(u16, ReadCallback<'a>), READ_CALLBACKS_MAX>,
+}
+
+impl<'a> ReadRegistry<'a> {
+    pub fn new() -> Self {
+        ReadRegistry { callbacks: Vec::new() }
+    }
+
+    pub fn with_callback(mut self, handle: u16, callback: ReadCallback<'a>) -> Self {
+        if self.callbacks.push((handle, callback)).is_err() {
+            error!("[read] Registry full, reads of handle {} return the stored value", handle);
+        }
+        self
+    }
+
+    /// Runs the callback of `handle`, if any
+    pub fn refresh(&self, handle: u16) {
+        let Some((_, callback)) = self.callbacks.iter().find(|(h, _)| *h == handle) else {
+            return;
+        };
+        if let Err(e) = callback() {
+            error!("[read] Failed to refresh handle {}: {:?}", handle, e);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use heapless::Vec;
+use log::error;
+use trouble_host::prelude::{AsGatt, AttErrorCode, Characteristic, IoCapabilities, SecurityLevel};
+
+/// Characteristics (CCCDs included) a policy can list
+pub const POLICY_MAX: usize = 24;
+
+/// What a peer needs before it may read or write an attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    allowed: bool,
+    encryption: bool,
+    authentication: bool,
+    bonded: bool,
+}
+
+impl Access {
+    pub const OPEN: Access = Access { allowed: true, encryption: false, authentication: false, bonded: false };
+    pub const ENCRYPTED: Access = Access { encryption: true, ..Access::OPEN };
+    /// Encrypted with MITM protection (passkey or numeric comparison)
+    pub const AUTHENTICATED: Access = Access { authentication: true, ..Access::ENCRYPTED };
+    pub const DENIED: Access = Access { allowed: false, ..Access::OPEN };
+
+    /// Additionally requires the peer to be bonded with us
+    pub const fn bonded(self) -> Access {
+        Access { bonded: true, encryption: true, ..self }
+    }
+
+    /// Returns the ATT error to answer with when a link at `level` doesn't meet this access;
+    /// whether the operation is allowed at all is up to the caller
+    pub fn check_link(&self, level: SecurityLevel, bonded: bool) -> Result<(), AttErrorCode> {
+        let encrypted = level != SecurityLevel::NoEncryption;
+        let authenticated = level == SecurityLevel::EncryptedAuthenticated;
+
+        // An unencrypted link that needs MITM protection must pair, not just encrypt
+        if self.authentication && !authenticated {
+            return Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+        }
+        if self.encryption && !encrypted {
+            return Err(AttErrorCode::INSUFFICIENT_ENCRYPTION);
+        }
+        if self.bonded && !bonded {
+            return Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION);
+        }
+        Ok(())
+    }
+
+    /// What the owner's phone needs to write commands, console lines or firmware.
+    ///
+    /// MITM protection when `io` lets pairing reach it; without any IO Just
+    /// Works only gives an encrypted link, so the phone has to be bonded instead.
+    pub fn owner(io: IoCapabilities) -> Access {
+        match io {
+            IoCapabilities::NoInputNoOutput => Access::ENCRYPTED.bonded(),
+            _ => Access::AUTHENTICATED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CharacteristicPolicy {
+    pub read: Access,
+    pub write: Access,
+}
+
+impl CharacteristicPolicy {
+    /// Applied to handles missing from the table: anyone reads, writes need encryption
+    pub const DEFAULT: CharacteristicPolicy = CharacteristicPolicy { read: Access::OPEN, write: Access::ENCRYPTED };
+
+    fn access(&self, operation: Operation) -> Access {
+        match operation {
+            Operation::Read => self.read,
+            Operation::Write => self.write,
+        }
+    }
+}
+
+/// Security requirements per attribute handle, checked for every read and write
+#[derive(Debug, Clone, Default)]
+pub struct SecurityPolicy {
+    entries: Vec<(u16, CharacteristicPolicy), POLICY_MAX>,
+}
+
+impl SecurityPolicy {
+    pub fn new() -> Self {
+        SecurityPolicy { entries: Vec::new() }
+    }
+
+    pub fn with_handle(mut self, handle: u16, read: Access, write: Access) -> Self {
+        if self.entries.push((handle, CharacteristicPolicy { read, write })).is_err() {
+            error!("[security] Policy table full, handle {} uses the default policy", handle);
+        }
+        self
+    }
+
+    /// Adds `characteristic` and lets anyone (un)subscribe through its CCCD
+    pub fn with_characteristic<T: AsGatt>(self, characteristic: &Characteristic<T>, read: Access, write: Access) -> Self {
+        let policy = self.with_handle(characteristic.handle, read, write);
+        match characteristic.cccd_handle {
+            Some(cccd) => policy.with_handle(cccd, Access::OPEN, Access::OPEN),
+            None => policy,
+        }
+    }
+
+    pub fn policy(&self, handle: u16) -> CharacteristicPolicy {
+        self.entries
+            .iter()
+            .find(|(h, _)| *h == handle)
+            .map_or(CharacteristicPolicy::DEFAULT, |(_, policy)| *policy)
+    }
+
+    /// Returns the ATT error to answer with when the link doesn't meet the policy of `handle`
+    pub fn check(&self, handle: u16, operation: Operation, level: SecurityLevel, bonded: bool) -> Result<(), AttErrorCode> {
+        let access = self.policy(handle).access(operation);
+
+        if !access.allowed {
+            return Err(match operation {
+                Operation::Read => AttErrorCode::READ_NOT_PERMITTED,
+                Operation::Write => AttErrorCode::WRITE_NOT_PERMITTED,
+            });
+        }
+
+        access.check_link(level, bonded)
+    }
+}
@@ -1,27 +1,95 @@
-use embedded_storage_async::nor_flash::MultiwriteNorFlash;
-use log::info;
-use trouble_host::prelude::{BdAddr, SecurityLevel};
+use core::ops::Range;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, NorFlash};
+use heapless::Vec;
+use log::{info, warn};
+use trouble_host::prelude::{AddrKind, BdAddr, SecurityLevel};
 use trouble_host::{BondInformation, Identity, LongTermKey};
 use sequential_storage::cache::NoCache;
 use sequential_storage::map::{Key, MapConfig, MapStorage, SerializationError, Value};
+use tamagotchi_common::i18n::Language;
+
+use crate::pet::pet_state::PetState;
+
+/// Storage shared by every connection task
+pub type SharedStorage<S> = Mutex<CriticalSectionRawMutex, MapStorage<StorageKey, S, NoCache>>;
+
+/// Flash reserved for the map: the `pet` partition of `partitions.csv`, past both OTA slots
+pub const STORAGE_RANGE: Range<u32> = 0x3F0000..0x3F8000;
+
+/// Distinct keys looked at by [`list_bonds`] and [`storage_stats`]
+pub const KEYS_LIST_MAX: usize = 32;
 
+/// Layout of the map; a device holding another one is erased on boot
+pub const FORMAT_VERSION: u8 = 3;
+
+const BOND_TAG: u8 = 0;
+const LANGUAGE_TAG: u8 = 2;
+const FRIENDSHIP_TAG: u8 = 3;
+const FORMAT_TAG: u8 = 4;
+const PET_TAG: u8 = 5;
+
+/// Key of every item kept in flash, prefixed by a tag byte
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct StorageAddr(BdAddr);
+pub enum StorageKey {
+    /// Bond with the peer of this identity address
+    Bond(BdAddr),
+    /// Language of the texts sent to the phone
+    Language,
+    /// Friendship level with the pet of this id
+    Friendship(u32),
+    /// [`FORMAT_VERSION`] the map was written with
+    Format,
+    /// The pet, saved before the device reboots itself
+    Pet,
+}
 
-impl Key for StorageAddr {
+impl Key for StorageKey {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        if buffer.len() < 6 {
-            return Err(SerializationError::BufferTooSmall);
+        match self {
+            StorageKey::Bond(addr) => {
+                if buffer.len() < 7 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = BOND_TAG;
+                buffer[1..7].copy_from_slice(addr.raw());
+                Ok(7)
+            }
+            StorageKey::Friendship(id) => {
+                if buffer.len() < 5 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = FRIENDSHIP_TAG;
+                buffer[1..5].copy_from_slice(&id.to_le_bytes());
+                Ok(5)
+            }
+            StorageKey::Language | StorageKey::Format | StorageKey::Pet => {
+                if buffer.is_empty() {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = match self {
+                    StorageKey::Language => LANGUAGE_TAG,
+                    StorageKey::Pet => PET_TAG,
+                    _ => FORMAT_TAG,
+                };
+                Ok(1)
+            }
         }
-        buffer[0..6].copy_from_slice(self.0.raw());
-        Ok(6)
     }
 
     fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
-        if buffer.len() < 6 {
-            Err(SerializationError::BufferTooSmall)
-        } else {
-            Ok((StorageAddr(BdAddr::new(buffer[0..6].try_into().unwrap())), 6))
+        match buffer.first() {
+            None => Err(SerializationError::BufferTooSmall),
+            Some(&BOND_TAG) if buffer.len() < 7 => Err(SerializationError::BufferTooSmall),
+            Some(&FRIENDSHIP_TAG) if buffer.len() < 5 => Err(SerializationError::BufferTooSmall),
+            Some(&BOND_TAG) => Ok((StorageKey::Bond(BdAddr::new(buffer[1..7].try_into().unwrap())), 7)),
+            Some(&FRIENDSHIP_TAG) => Ok((StorageKey::Friendship(u32::from_le_bytes(buffer[1..5].try_into().unwrap())), 5)),
+            Some(&LANGUAGE_TAG) => Ok((StorageKey::Language, 1)),
+            Some(&FORMAT_TAG) => Ok((StorageKey::Format, 1)),
+            Some(&PET_TAG) => Ok((StorageKey::Pet, 1)),
+            Some(_) => Err(SerializationError::InvalidData),
         }
     }
 }
@@ -29,11 +97,13 @@ impl Key for StorageAddr {
 pub struct StoredBondInformation {
     ltk: LongTermKey,
     security_level: SecurityLevel,
+    /// Kind of the identity address, to direct advertising at it
+    kind: AddrKind,
 }
 
 impl<'a> Value<'a> for StoredBondInformation {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        if buffer.len() < 17 {
+        if buffer.len() < 18 {
             return Err(SerializationError::BufferTooSmall);
         }
         buffer[0..16].copy_from_slice(self.ltk.to_le_bytes().as_slice());
@@ -42,14 +112,15 @@ impl<'a> Value<'a> for StoredBondInformation {
             SecurityLevel::Encrypted => 1,
             SecurityLevel::EncryptedAuthenticated => 2,
         };
-        Ok(17)
+        buffer[17] = if self.kind == AddrKind::RANDOM { 1 } else { 0 };
+        Ok(18)
     }
 
     fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
     where
         Self: Sized,
     {
-        if buffer.len() < 17 {
+        if buffer.len() < 18 {
             Err(SerializationError::BufferTooSmall)
         } else {
             let ltk = LongTermKey::from_le_bytes(buffer[0..16].try_into().unwrap());
@@ -59,22 +130,29 @@ impl<'a> Value<'a> for StoredBondInformation {
                 2 => SecurityLevel::EncryptedAuthenticated,
                 _ => return Err(SerializationError::InvalidData),
             };
-            Ok((StoredBondInformation { ltk, security_level }, 17))
+            let kind = match buffer[17] {
+                0 => AddrKind::PUBLIC,
+                1 => AddrKind::RANDOM,
+                _ => return Err(SerializationError::InvalidData),
+            };
+            Ok((StoredBondInformation { ltk, security_level, kind }, 18))
         }
     }
 }
 
 pub async fn store_bonding_info<S: MultiwriteNorFlash>(
-    storage: &mut MapStorage<StorageAddr, S, NoCache>,
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
     info: &BondInformation,
+    kind: AddrKind,
 ) -> Result<(), sequential_storage::Error<S::Error>> {
     let mut buffer = [0; 32];
-    let key = StorageAddr(info.identity.bd_addr);
+    let key = StorageKey::Bond(info.identity.bd_addr);
     
     // "Manually cloning" to avoid lifetime issues
     let value = StoredBondInformation {
         ltk: info.ltk,
         security_level: info.security_level,
+        kind,
     };
 
     // Try to remove existing entry, but ignore Corrupted errors (storage might be uninitialized)
@@ -90,11 +168,11 @@ pub async fn store_bonding_info<S: MultiwriteNorFlash>(
 }
 
 pub async fn load_bonding_info<S: MultiwriteNorFlash>(
-    storage: &mut MapStorage<StorageAddr, S, NoCache>,
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
     addr: &BdAddr,
 ) -> Result<Option<BondInformation>, sequential_storage::Error<S::Error>> {
     let mut buffer = [0; 32];
-    let key = StorageAddr(*addr);
+    let key = StorageKey::Bond(*addr);
 
     match storage.fetch_item::<StoredBondInformation>(&mut buffer, &key).await {
         Ok(Some(stored)) => {
@@ -115,9 +193,18 @@ pub async fn load_bonding_info<S: MultiwriteNorFlash>(
     }
 }
 
+pub async fn remove_bonding_info<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    addr: &BdAddr,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    storage.remove_item(&mut buffer, &StorageKey::Bond(*addr)).await
+}
+
+/// First stored bond, with the kind of its identity address
 pub async fn get_first_bonded<S: MultiwriteNorFlash>(
-    storage: &mut MapStorage<StorageAddr, S, NoCache>,
-) -> Result<Option<BondInformation>, sequential_storage::Error<S::Error>> {
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+) -> Result<Option<(BondInformation, AddrKind)>, sequential_storage::Error<S::Error>> {
     let mut buffer = [0; 32];
     
     // Fetch the first item from storage
@@ -135,27 +222,188 @@ pub async fn get_first_bonded<S: MultiwriteNorFlash>(
         };
     info!("Fetching first bonded device from storage...");
 
-    match iter.next::<StoredBondInformation>(&mut buffer).await {
-        Ok(Some((key, stored))) => {
-            
-            info!("Loaded bonded device with address: {:?}", key.0);
-            let bond_info = BondInformation {
-                identity: Identity {
-                    bd_addr: key.0,
-                    irk: None,
-                },
-                ltk: stored.ltk,
-                security_level: stored.security_level,
-                is_bonded: true,
-            };
-            Ok(Some(bond_info))
+    // Other items (e.g. the language) share the map, skip them until a bond shows up
+    loop {
+        match iter.next::<&[u8]>(&mut buffer).await {
+            Ok(Some((StorageKey::Bond(addr), value))) => {
+                let Ok((stored, _)) = StoredBondInformation::deserialize_from(value) else {
+                    info!("Skipping unreadable bond for {:?}", addr);
+                    continue;
+                };
+                info!("Loaded bonded device with address: {:?}", addr);
+                let bond_info = BondInformation {
+                    identity: Identity {
+                        bd_addr: addr,
+                        irk: None,
+                    },
+                    ltk: stored.ltk,
+                    security_level: stored.security_level,
+                    is_bonded: true,
+                };
+                return Ok(Some((bond_info, stored.kind)));
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
         }
-        Ok(None) => Ok(None),
+    }
+}
+
+/// Stored language, `None` when never set or unknown to this firmware
+pub async fn load_language<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+) -> Result<Option<Language>, sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    match storage.fetch_item::<u8>(&mut buffer, &StorageKey::Language).await {
+        Ok(code) => Ok(code.and_then(Language::from_u8)),
+        Err(sequential_storage::Error::Corrupted {}) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn store_language<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    language: Language,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    storage.store_item(&mut buffer, &StorageKey::Language, &(language as u8)).await
+}
+
+/// Friendship level with the pet `id`, 0 when they never played together
+pub async fn load_friendship<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    id: u32,
+) -> Result<u8, sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    match storage.fetch_item::<u8>(&mut buffer, &StorageKey::Friendship(id)).await {
+        Ok(level) => Ok(level.unwrap_or(0)),
+        Err(sequential_storage::Error::Corrupted {}) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn store_friendship<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    id: u32,
+    level: u8,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    storage.store_item(&mut buffer, &StorageKey::Friendship(id), &level).await
+}
+
+/// Restores the saved pet into `pet`; returns whether a readable one was found
+pub async fn load_pet<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    pet: &mut PetState,
+) -> Result<bool, sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    match storage.fetch_item::<&[u8]>(&mut buffer, &StorageKey::Pet).await {
+        Ok(Some(record)) => Ok(pet.restore(record)),
+        Ok(None) | Err(sequential_storage::Error::Corrupted {}) => Ok(false),
         Err(e) => Err(e),
     }
 }
-pub fn init_storage<S: MultiwriteNorFlash>(flash: S) -> MapStorage<StorageAddr, S, NoCache> {
-let map_config = MapConfig::new(0x3F0000..0x3F8000); // Last 32KB of 4MB flash
 
-    MapStorage::new(flash, map_config, NoCache {})
+pub async fn store_pet<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+    pet: &PetState,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 32];
+    let (record, len) = pet.record();
+    storage.store_item(&mut buffer, &StorageKey::Pet, &&record[..len]).await
+}
+
+/// Items kept in flash, per kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub bonds: usize,
+    pub friendships: usize,
+    /// The settings, the saved pet and the format version
+    pub others: usize,
+}
+
+/// Addresses of every bonded peer, up to [`KEYS_LIST_MAX`] items are looked at
+pub async fn list_bonds<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+) -> Result<Vec<BdAddr, KEYS_LIST_MAX>, sequential_storage::Error<S::Error>> {
+    let keys = list_keys(storage).await?;
+    Ok(keys
+        .iter()
+        .filter_map(|key| match key {
+            StorageKey::Bond(addr) => Some(*addr),
+            _ => None,
+        })
+        .collect())
+}
+
+pub async fn storage_stats<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+) -> Result<StorageStats, sequential_storage::Error<S::Error>> {
+    let mut stats = StorageStats::default();
+    for key in list_keys(storage).await? {
+        match key {
+            StorageKey::Bond(_) => stats.bonds += 1,
+            StorageKey::Friendship(_) => stats.friendships += 1,
+            StorageKey::Language | StorageKey::Format | StorageKey::Pet => stats.others += 1,
+        }
+    }
+    Ok(stats)
+}
+
+/// Every key in the map, once; the iterator also returns overwritten items
+async fn list_keys<S: MultiwriteNorFlash>(
+    storage: &mut MapStorage<StorageKey, S, NoCache>,
+) -> Result<Vec<StorageKey, KEYS_LIST_MAX>, sequential_storage::Error<S::Error>> {
+    let mut keys: Vec<StorageKey, KEYS_LIST_MAX> = Vec::new();
+    let mut buffer = [0; 32];
+    let mut iter = match storage.fetch_all_items(&mut buffer).await {
+        Ok(iter) => iter,
+        Err(sequential_storage::Error::Corrupted {}) => return Ok(keys),
+        Err(e) => return Err(e),
+    };
+    while let Some((key, _)) = iter.next::<&[u8]>(&mut buffer).await? {
+        if !keys.contains(&key) && keys.push(key).is_err() {
+            break;
+        }
+    }
+    Ok(keys)
+}
+
+/// Opens the map, erasing it first when it was written in another format.
+///
+/// Older firmware keyed bonds by the bare address, which this one can't read
+/// back; those devices lose their bonds once and pair again.
+pub async fn init_storage<S: MultiwriteNorFlash>(mut flash: S) -> MapStorage<StorageKey, S, NoCache> {
+    let version = {
+        let mut storage = MapStorage::new(&mut flash, MapConfig::new(STORAGE_RANGE), NoCache {});
+        let mut buffer = [0; 32];
+        storage.fetch_item::<u8>(&mut buffer, &StorageKey::Format).await
+    };
+
+    let erase = match version {
+        Ok(Some(FORMAT_VERSION)) => false,
+        // The flash failed, not the layout: keep what's there
+        Err(sequential_storage::Error::Storage { .. }) => {
+            warn!("[storage] Failed to read the format version: {:?}", version);
+            false
+        }
+        _ => {
+            info!("[storage] Map format {:?}, expected {}. Erasing it.", version, FORMAT_VERSION);
+            true
+        }
+    };
+    if erase {
+        if let Err(e) = flash.erase(STORAGE_RANGE.start, STORAGE_RANGE.end).await {
+            warn!("[storage] Failed to erase the map: {:?}", e);
+        }
+    }
+
+    let mut storage = MapStorage::new(flash, MapConfig::new(STORAGE_RANGE), NoCache {});
+    if erase {
+        let mut buffer = [0; 32];
+        if let Err(e) = storage.store_item(&mut buffer, &StorageKey::Format, &FORMAT_VERSION).await {
+            warn!("[storage] Failed to store the format version: {:?}", e);
+        }
+    }
+    storage
 }
\ No newline at end of file
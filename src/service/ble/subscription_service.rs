@@ -0,0 +1,121 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::Vec;
+use log::{error, info};
+use trouble_host::prelude::{AsGatt, Characteristic};
+
+/// Characteristics with a CCCD tracked per connection
+pub const SUBSCRIPTIONS_MAX: usize = 8;
+
+const NOTIFY_BIT: u8 = 0x01;
+const INDICATE_BIT: u8 = 0x02;
+
+/// Value of a Client Characteristic Configuration Descriptor
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CccdState {
+    pub notify: bool,
+    pub indicate: bool,
+}
+
+impl CccdState {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let bits = data.first().copied().unwrap_or(0);
+        CccdState {
+            notify: bits & NOTIFY_BIT != 0,
+            indicate: bits & INDICATE_BIT != 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.notify || self.indicate
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    value_handle: u16,
+    cccd_handle: u16,
+    state: CccdState,
+}
+
+/// CCCD state of one connection.
+///
+/// Fed by the GATT write handler; the notification layer asks it before
+/// sending and the application waits on [`Subscriptions::subscribed`] to push
+/// a snapshot as soon as the phone starts listening.
+pub struct Subscriptions {
+    entries: RefCell<Vec<Subscription, SUBSCRIPTIONS_MAX>>,
+    /// Value handles whose CCCD just went from disabled to enabled
+    subscribed: Channel<CriticalSectionRawMutex, u16, SUBSCRIPTIONS_MAX>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Subscriptions {
+            entries: RefCell::new(Vec::new()),
+            subscribed: Channel::new(),
+        }
+    }
+
+    /// Tracks the CCCD of `characteristic`, which starts unsubscribed
+    pub fn with_characteristic<T: AsGatt>(self, characteristic: &Characteristic<T>) -> Self {
+        let Some(cccd_handle) = characteristic.cccd_handle else {
+            error!("[cccd] Handle {} has no CCCD to track", characteristic.handle);
+            return self;
+        };
+
+        let subscription = Subscription {
+            value_handle: characteristic.handle,
+            cccd_handle,
+            state: CccdState::default(),
+        };
+        if self.entries.borrow_mut().push(subscription).is_err() {
+            error!("[cccd] Too many subscriptions, handle {} is not tracked", characteristic.handle);
+        }
+        self
+    }
+
+    /// Updates the state when `handle` is a tracked CCCD, returns whether it was one
+    pub fn on_write(&self, handle: u16, data: &[u8]) -> bool {
+        let mut entries = self.entries.borrow_mut();
+        let Some(subscription) = entries.iter_mut().find(|s| s.cccd_handle == handle) else {
+            return false;
+        };
+
+        let state = CccdState::from_bytes(data);
+        let was_enabled = subscription.state.is_enabled();
+        subscription.state = state;
+        info!("[cccd] Handle {}: {:?}", subscription.value_handle, state);
+
+        if state.is_enabled() && !was_enabled && self.subscribed.try_send(subscription.value_handle).is_err() {
+            error!("[cccd] Subscription queue full, no snapshot for handle {}", subscription.value_handle);
+        }
+        true
+    }
+
+    pub fn state(&self, value_handle: u16) -> Option<CccdState> {
+        self.entries
+            .borrow()
+            .iter()
+            .find(|s| s.value_handle == value_handle)
+            .map(|s| s.state)
+    }
+
+    /// Whether sending on `value_handle` reaches the phone; untracked handles always do
+    pub fn is_subscribed(&self, value_handle: u16) -> bool {
+        self.state(value_handle).is_none_or(|state| state.is_enabled())
+    }
+
+    /// Waits for the phone to subscribe to a characteristic and returns its value handle
+    pub async fn subscribed(&self) -> u16 {
+        self.subscribed.receive().await
+    }
+}
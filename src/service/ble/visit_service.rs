@@ -0,0 +1,276 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, with_timeout};
+use heapless::Vec;
+use log::{error, info, warn};
+use tamagotchi_common::beacon::{self, PetBeacon};
+use tamagotchi_common::protocol::{Frame, MessageType};
+use tamagotchi_common::visit::{PET_SERVICE_UUID, VISIT_CHARACTERISTIC_UUID, Visit};
+use trouble_host::prelude::*;
+
+use crate::service::ble::notification_characteristics::VISIT_FRAME_LEN;
+
+/// Pets remembered from the advertising reports
+pub const NEARBY_MAX: usize = 8;
+
+/// Pets not heard from for this long are dropped from the list
+const NEARBY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connection, discovery and exchange of one visit
+const VISIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Services the GATT client keeps while discovering the pet service
+const CLIENT_SERVICES_MAX: usize = 4;
+
+/// A pet is welcome once per this long, the interval pets visit each other at
+const VISIT_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// Recent visitors remembered; once all of them are recent, newcomers wait too
+const VISITORS_MAX: usize = 8;
+
+/// Visits written to our visit characteristic, consumed by the pet task
+pub static VISIT_CHANNEL: Channel<CriticalSectionRawMutex, Visit, 2> = Channel::new();
+
+/// Pet ids that visited during the last cooldown, and when
+static VISITORS: Mutex<RefCell<Vec<(u32, Instant), VISITORS_MAX>>> = Mutex::new(RefCell::new(Vec::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitError {
+    /// The other pet didn't take the connection
+    Connect,
+    /// The pet service or its visit characteristic is missing, or the write or read failed
+    Exchange,
+    /// The other pet answered with something that isn't a visit
+    InvalidReply,
+    Timeout,
+}
+
+/// Another pet heard while scanning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NearbyPet {
+    pub address: Address,
+    pub beacon: PetBeacon,
+    pub rssi: i8,
+    pub last_seen: Instant,
+}
+
+/// Pets running this firmware heard while scanning.
+///
+/// Fed by the host runner with every advertising report; reports from other
+/// devices are ignored.
+pub struct NearbyPets {
+    pets: Mutex<RefCell<Vec<NearbyPet, NEARBY_MAX>>>,
+}
+
+impl Default for NearbyPets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NearbyPets {
+    pub const fn new() -> Self {
+        NearbyPets { pets: Mutex::new(RefCell::new(Vec::new())) }
+    }
+
+    /// Updates or adds `address`, returns whether it wasn't known yet
+    fn record(&self, address: Address, beacon: PetBeacon, rssi: i8) -> bool {
+        let now = Instant::now();
+        critical_section::with(|cs| {
+            let mut pets = self.pets.borrow_ref_mut(cs);
+            if let Some(pet) = pets.iter_mut().find(|pet| pet.address == address) {
+                pet.beacon = beacon;
+                pet.rssi = rssi;
+                pet.last_seen = now;
+                return false;
+            }
+
+            let pet = NearbyPet { address, beacon, rssi, last_seen: now };
+            if pets.is_full() {
+                // Make room by forgetting the pet heard from the longest time ago
+                if let Some(index) = pets.iter().enumerate().min_by_key(|(_, pet)| pet.last_seen).map(|(i, _)| i) {
+                    pets.swap_remove(index);
+                }
+            }
+            let _ = pets.push(pet);
+            true
+        })
+    }
+
+    /// Pets heard recently, stale entries are dropped
+    pub fn list(&self) -> Vec<NearbyPet, NEARBY_MAX> {
+        let now = Instant::now();
+        critical_section::with(|cs| {
+            let mut pets = self.pets.borrow_ref_mut(cs);
+            pets.retain(|pet| now.saturating_duration_since(pet.last_seen) < NEARBY_TIMEOUT);
+            pets.clone()
+        })
+    }
+
+    /// The strongest signal, usually the closest pet
+    pub fn closest(&self) -> Option<NearbyPet> {
+        self.list().into_iter().max_by_key(|pet| pet.rssi)
+    }
+}
+
+impl EventHandler for NearbyPets {
+    fn on_adv_reports(&self, mut reports: LeAdvReportsIter<'_>) {
+        while let Some(Ok(report)) = reports.next() {
+            let Some(beacon) = beacon::find_beacon(report.data) else {
+                continue;
+            };
+            let address = Address { kind: report.addr_kind, addr: report.addr };
+            if self.record(address, beacon, report.rssi) {
+                info!("[scan] Found pet {:?} at {:?}, {} dBm", beacon, address, report.rssi);
+            }
+        }
+    }
+}
+
+/// Visits between pets: we answer visits as a peripheral and pay them as a central
+pub struct VisitService;
+
+impl VisitService {
+    /// Value of the visit characteristic
+    pub fn frame(visit: &Visit) -> Result<[u8; VISIT_FRAME_LEN], trouble_host::Error> {
+        let (payload, len) = visit.to_bytes();
+        let mut buffer = [0u8; VISIT_FRAME_LEN];
+        Frame::new(MessageType::Visit, 0, &payload[..len])
+            .encode(&mut buffer)
+            .map_err(|e| {
+                error!("[visit] Failed to encode visit: {:?}", e);
+                trouble_host::Error::InvalidValue
+            })?;
+        Ok(buffer)
+    }
+
+    /// Parses a visit frame, `None` if it isn't one
+    pub fn parse(data: &[u8]) -> Option<Visit> {
+        match Frame::decode(data) {
+            Ok((frame, _)) if frame.message_type == MessageType::Visit => Visit::from_bytes(frame.payload),
+            Ok((frame, _)) => {
+                warn!("[visit] Unexpected {:?} frame", frame.message_type);
+                None
+            }
+            Err(e) => {
+                warn!("[visit] Invalid visit frame: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Hands a visit written by another pet to the application; returns `false`
+    /// when it's refused because too many visits came in recently
+    pub fn received(visit: Visit) -> bool {
+        if !Self::admit(visit.id) {
+            warn!("[visit] {} ({:08x}) visited too recently, refused", visit.name(), visit.id);
+            return false;
+        }
+        info!("[visit] {} came to visit", visit.name());
+        if VISIT_CHANNEL.try_send(visit).is_err() {
+            error!("[visit] Visit queue full, dropping visit");
+        }
+        true
+    }
+
+    /// Records a visit of the pet `id` unless it already came during the cooldown.
+    ///
+    /// The table is bounded, so writes with made up ids are limited as well.
+    fn admit(id: u32) -> bool {
+        let now = Instant::now();
+        critical_section::with(|cs| {
+            let mut visitors = VISITORS.borrow_ref_mut(cs);
+            visitors.retain(|(_, at)| now.saturating_duration_since(*at) < VISIT_COOLDOWN);
+            if visitors.iter().any(|(visitor, _)| *visitor == id) {
+                return false;
+            }
+            visitors.push((id, now)).is_ok()
+        })
+    }
+
+    /// Connects to `peer` as a central, writes our visit and reads theirs back
+    pub async fn visit<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        central: &mut Central<'a, C, DefaultPacketPool>,
+        peer: Address,
+        ours: &Visit,
+    ) -> Result<Visit, VisitError> {
+        match with_timeout(VISIT_TIMEOUT, Self::exchange(stack, central, peer, ours)).await {
+            Ok(result) => result,
+            Err(_) => Err(VisitError::Timeout),
+        }
+    }
+
+    async fn exchange<'a, C: Controller>(
+        stack: &'a Stack<'a, C, DefaultPacketPool>,
+        central: &mut Central<'a, C, DefaultPacketPool>,
+        peer: Address,
+        ours: &Visit,
+    ) -> Result<Visit, VisitError> {
+        let config = ConnectConfig {
+            connect_params: Default::default(),
+            scan_config: ScanConfig {
+                filter_accept_list: &[(peer.kind, &peer.addr)],
+                ..Default::default()
+            },
+        };
+        let conn = central.connect(&config).await.map_err(|e| {
+            warn!("[visit] Failed to connect to {:?}: {:?}", peer, e);
+            VisitError::Connect
+        })?;
+        info!("[visit] Connected to {:?}", peer);
+
+        let client = GattClient::<C, DefaultPacketPool, CLIENT_SERVICES_MAX>::new(stack, &conn)
+            .await
+            .map_err(|e| {
+                warn!("[visit] GATT client failed: {:?}", e);
+                VisitError::Exchange
+            })?;
+
+        let frame = Self::frame(ours).map_err(|_| VisitError::Exchange)?;
+        let exchange = async {
+            let services = client
+                .services_by_uuid(&Uuid::new_long(PET_SERVICE_UUID.to_le_bytes()))
+                .await
+                .map_err(|e| {
+                    warn!("[visit] Service discovery failed: {:?}", e);
+                    VisitError::Exchange
+                })?;
+            let service = services.first().ok_or(VisitError::Exchange)?;
+            let characteristic: Characteristic<[u8; VISIT_FRAME_LEN]> = client
+                .characteristic_by_uuid(service, &Uuid::new_long(VISIT_CHARACTERISTIC_UUID.to_le_bytes()))
+                .await
+                .map_err(|e| {
+                    warn!("[visit] No visit characteristic: {:?}", e);
+                    VisitError::Exchange
+                })?;
+
+            client.write_characteristic(&characteristic, &frame).await.map_err(|e| {
+                warn!("[visit] Write failed: {:?}", e);
+                VisitError::Exchange
+            })?;
+
+            let mut reply = [0u8; VISIT_FRAME_LEN];
+            let len = client.read_characteristic(&characteristic, &mut reply).await.map_err(|e| {
+                warn!("[visit] Read failed: {:?}", e);
+                VisitError::Exchange
+            })?;
+            Self::parse(&reply[..len]).ok_or(VisitError::InvalidReply)
+        };
+
+        // The client task processes the responses, it only ends with the connection
+        match select(client.task(), exchange).await {
+            Either::First(_) => Err(VisitError::Exchange),
+            Either::Second(result) => {
+                if let Ok(visit) = &result {
+                    info!("[visit] Visited {}", visit.name());
+                }
+                result
+            }
+        }
+    }
+}